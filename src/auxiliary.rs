@@ -0,0 +1,58 @@
+use pwm_pca9685::Channel;
+
+use crate::backend::MotorBackend;
+use crate::config::AuxChannel;
+use crate::error::RoverError;
+use crate::output::{self, PwmOutput};
+use crate::rover::{CHANNELS, MAX_SPEED};
+
+/// Highest level of an auxiliary output, i.e. full brightness.
+pub const MAX_LEVEL: u16 = MAX_SPEED;
+
+/// A named output on a spare PCA9685 channel, such as headlights or an LED
+/// strip, dimmed with the same duty cycle as the motors' speed.
+#[derive(Debug)]
+pub struct AuxOutput {
+    backend: Box<dyn MotorBackend>,
+    channel: Channel,
+    /// See `AuxChannel::neutral_level`.
+    neutral_level: u16,
+    level: u16,
+}
+
+impl AuxOutput {
+    pub fn new(backend: Box<dyn MotorBackend>, config: &AuxChannel) -> Self {
+        AuxOutput {
+            backend,
+            channel: CHANNELS[usize::from(config.channel)],
+            neutral_level: config.neutral_level,
+            level: 0,
+        }
+    }
+
+    /// Sets the output to `level`, from 0 (off) to `MAX_LEVEL`.
+    pub fn set_level(&mut self, level: u16) -> Result<(), RoverError> {
+        debug!("AuxOutput.set_level({:?}, {})", self, level);
+
+        self.backend.set_pwm_duty_cycle(self.channel, level.min(MAX_LEVEL))?;
+        self.level = level.min(MAX_LEVEL);
+
+        Ok(())
+    }
+}
+
+/// Levels as values, negative ones turning the output off, see `PwmOutput`.
+impl PwmOutput for AuxOutput {
+    fn set_value(&mut self, value: f32) -> Result<(), RoverError> {
+        self.set_level(output::scale(value.max(0.0), i32::from(MAX_LEVEL)) as u16)
+    }
+
+    fn value(&self) -> f32 {
+        f32::from(self.level) / f32::from(MAX_LEVEL)
+    }
+
+    /// Sets the output to its neutral level, see `AuxChannel::neutral_level`.
+    fn neutral(&mut self) -> Result<(), RoverError> {
+        self.set_level(self.neutral_level)
+    }
+}
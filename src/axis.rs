@@ -0,0 +1,119 @@
+use serde::Serialize;
+
+use crate::rover::{TurnDirection, MAX_SPEED};
+
+/// Turns the position of an analog stick, each axis from -1.0 to 1.0, into
+/// `Drive` speeds, see `RoverCommand::Axis`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AxisShaping {
+    /// Distance from the center (0.0 to 1.0) under which the stick counts
+    /// as centered, the rest of its travel being rescaled to start from 0.
+    deadzone: f32,
+    /// Blend between a linear (0.0) and a cubic (1.0) response, for finer
+    /// control at low speed.
+    expo: f32,
+}
+
+impl AxisShaping {
+    pub fn new(deadzone: f32, expo: f32) -> Self {
+        AxisShaping { deadzone, expo }
+    }
+
+    pub fn deadzone(&self) -> f32 {
+        self.deadzone
+    }
+
+    pub fn expo(&self) -> f32 {
+        self.expo
+    }
+
+    /// Shapes `throttle` (positive forward) and `steer` (positive right)
+    /// into the `linear` and `angular` speeds of `Drive`.
+    ///
+    /// The deadzone is radial, so that a noisy center doesn't creep along
+    /// either axis, and keeps the direction of the stick.
+    pub fn shape(&self, throttle: f32, steer: f32) -> (i16, i16) {
+        let magnitude = throttle.hypot(steer);
+
+        if magnitude <= self.deadzone {
+            return (0, 0);
+        }
+
+        // Corners of square gates go past 1.0.
+        let scale = ((magnitude.min(1.0) - self.deadzone) / (1.0 - self.deadzone)) / magnitude;
+        let speed = |axis: f32| {
+            let axis = axis * scale;
+            let curved = (1.0 - self.expo) * axis + self.expo * axis.powi(3);
+
+            (curved * f32::from(MAX_SPEED)).round() as i16
+        };
+
+        (speed(throttle), speed(steer))
+    }
+}
+
+/// Flips the operator's inputs before they are mixed into motor speeds, e.g.
+/// for a rover whose motors are wired backward or a controller driving it
+/// from the other side, see `RoverCommand::SetSteeringInvert`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Inversion {
+    /// Swap forward and backward.
+    pub forward: bool,
+    /// Swap left and right.
+    pub steering: bool,
+}
+
+impl Inversion {
+    /// Flips the `linear` and `angular` speeds of `Drive`.
+    pub fn drive(&self, linear: i16, angular: i16) -> (i16, i16) {
+        let flip = |speed: i16, inverted| if inverted { -speed } else { speed };
+
+        (flip(linear, self.forward), flip(angular, self.steering))
+    }
+
+    /// Flips the direction of a spin in place, which only turns.
+    pub fn spin(&self, direction: TurnDirection) -> TurnDirection {
+        match (self.steering, direction) {
+            (false, direction) => direction,
+            (true, TurnDirection::Clockwise) => TurnDirection::CounterClockwise,
+            (true, TurnDirection::CounterClockwise) => TurnDirection::Clockwise,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn centered_sticks_dont_creep() {
+        let shaping = AxisShaping::new(0.1, 0.0);
+
+        assert_eq!(shaping.shape(0.05, -0.07), (0, 0));
+        assert_eq!(shaping.shape(1.0, 0.0), (100, 0));
+        assert_eq!(shaping.shape(0.0, -1.0), (0, -100));
+        // Just past the deadzone starts from 0, not from 10.
+        assert_eq!(shaping.shape(0.11, 0.0), (1, 0));
+        assert_eq!(shaping.shape(0.55, 0.0), (50, 0));
+    }
+
+    #[test]
+    fn expo_softens_the_center() {
+        let shaping = AxisShaping::new(0.0, 1.0);
+
+        assert_eq!(shaping.shape(0.5, 0.0), (13, 0));
+        assert_eq!(shaping.shape(-1.0, 0.0), (-100, 0));
+    }
+
+    #[test]
+    fn inversions_flip_each_axis() {
+        let steering = Inversion { forward: false, steering: true };
+        let both = Inversion { forward: true, steering: true };
+
+        assert_eq!(Inversion::default().drive(50, -20), (50, -20));
+        assert_eq!(steering.drive(50, -20), (50, 20));
+        assert_eq!(both.drive(50, -20), (-50, 20));
+        assert_eq!(steering.spin(TurnDirection::Clockwise), TurnDirection::CounterClockwise);
+        assert_eq!(Inversion { forward: true, steering: false }.spin(TurnDirection::Clockwise), TurnDirection::Clockwise);
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use embedded_hal::blocking::i2c::WriteRead;
+use linux_embedded_hal::I2cdev;
+use linux_embedded_hal::i2cdev::linux::LinuxI2CError;
+use pwm_pca9685::{Channel, Pca9685};
+
+use crate::error::RoverError;
+
+/// Frequency of the PCA9685's internal oscillator.
+const OSCILLATOR_HZ: f32 = 25_000_000f32;
+const MODE1_REGISTER: u8 = 0x00;
+const MODE1_SLEEP: u8 = 0x10;
+const LED0_ON_L_REGISTER: u8 = 0x06;
+/// Highest value of the 12-bit on/off count registers.
+const MAX_COUNT: i32 = 4095;
+
+/// What drives the channels of a `DCMotor`.
+pub trait MotorBackend: fmt::Debug + Send {
+    /// Sets the duty cycle of `channel`, `pulse` being a percentage.
+    fn set_pwm_duty_cycle(&mut self, channel: Channel, pulse: u16) -> Result<(), RoverError>;
+
+    /// Sets the off count of `channel`, for outputs needing a finer
+    /// resolution than whole percentages such as servos.
+    fn set_off_count(&mut self, channel: Channel, off: u16) -> Result<(), RoverError>;
+
+    /// Drives `channel` fully high (1) or low (0).
+    fn set_level(&mut self, channel: Channel, value: u16) -> Result<(), RoverError>;
+
+    /// Stops the motor whose speed is set through `control`.
+    fn stop(&mut self, control: Channel) -> Result<(), RoverError> {
+        self.set_pwm_duty_cycle(control, 0)
+    }
+
+    /// Reads back the (on, off) counts actually programmed on `channel`.
+    fn read_channel(&self, channel: Channel) -> Result<(u16, u16), LinuxI2CError>;
+
+    /// Whether the hardware went through a reset since it was initialized,
+    /// in which case it needs `reinitialize()`.
+    fn was_reset(&self) -> Result<bool, LinuxI2CError> {
+        Ok(false)
+    }
+
+    fn reinitialize(&mut self) -> Result<(), RoverError> {
+        Ok(())
+    }
+}
+
+/// Off count of a channel for a `pulse` duty cycle percentage, whatever the
+/// PWM frequency. Percentages above 100 are clamped to full duty.
+fn off_count(pulse: u16) -> u16 {
+    // 100f32 because `pulse` is a percentage of the 4096 counts
+    let count = (f32::from(pulse) * (4096f32 / 100f32) - 1f32).round() as i32;
+
+    count.clamp(0, MAX_COUNT) as u16
+}
+
+/// Off count of a channel for a pulse of `width_us` microseconds at
+/// `frequency_hz`, clamped to full duty.
+pub fn pulse_width_count(width_us: u16, frequency_hz: u16) -> u16 {
+    let count = (f32::from(width_us) * f32::from(frequency_hz) * 4096f32 / 1_000_000f32).round() as i32;
+
+    count.clamp(0, MAX_COUNT) as u16
+}
+
+/// Prescale value giving a PWM frequency of `frequency_hz`, which must be
+/// within `FREQUENCY_RANGE_HZ`.
+fn prescale(frequency_hz: u16) -> u8 {
+    ((OSCILLATOR_HZ / (4096f32 * f32::from(frequency_hz))).round() - 1f32) as u8
+}
+
+/// Channels of a PCA9685 board on an I2C bus.
+///
+/// Clones share the same driver, so that motors on the same board don't
+/// re-initialize it under each other's feet.
+#[derive(Clone)]
+pub struct Pca9685Backend {
+    pwm: Arc<Mutex<Pca9685<I2cdev>>>,
+    i2c_path: String,
+    address: u8,
+    prescale: u8,
+}
+
+impl fmt::Debug for Pca9685Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pca9685Backend")
+            .field("i2c_path", &self.i2c_path)
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+impl Pca9685Backend {
+    /// Initializes the board at `address` on the `i2c_path` bus, its
+    /// outputs running at `frequency_hz`.
+    pub fn new(i2c_path: &str, address: u8, frequency_hz: u16) -> Result<Self, RoverError> {
+        trace!("creating i2c device");
+        let dev = I2cdev::new(i2c_path).map_err(|source| RoverError::Device {
+            path: i2c_path.to_string(),
+            source,
+        })?;
+        trace!("creating PCA9685 device");
+        let mut pwm = Pca9685::new(dev, address)?;
+        let prescale = prescale(frequency_hz);
+
+        debug!("PWM frequency {}Hz, prescale {}", frequency_hz, prescale);
+        initialize(&mut pwm, prescale)?;
+
+        Ok(Pca9685Backend {
+            pwm: Arc::new(Mutex::new(pwm)),
+            i2c_path: i2c_path.to_string(),
+            address,
+            prescale,
+        })
+    }
+
+    /// Reads registers directly, since the driver only exposes writes.
+    fn read_registers(&self, register: u8, data: &mut [u8]) -> Result<(), LinuxI2CError> {
+        let mut dev = I2cdev::new(&self.i2c_path)?;
+
+        dev.write_read(self.address, &[register], data)
+    }
+}
+
+fn initialize(pwm: &mut Pca9685<I2cdev>, prescale: u8) -> Result<(), RoverError> {
+    pwm.set_prescale(prescale)?;
+    // It is necessary to enable the device.
+    pwm.enable()?;
+
+    Ok(())
+}
+
+impl MotorBackend for Pca9685Backend {
+    fn set_pwm_duty_cycle(&mut self, channel: Channel, pulse: u16) -> Result<(), RoverError> {
+        let off = off_count(pulse);
+
+        trace!("set_channel_on_off({:?}, 0, {})", channel, off);
+        self.pwm.lock().unwrap().set_channel_on_off(channel, 0, off)?;
+
+        Ok(())
+    }
+
+    fn set_off_count(&mut self, channel: Channel, off: u16) -> Result<(), RoverError> {
+        trace!("set_channel_on_off({:?}, 0, {})", channel, off);
+        self.pwm.lock().unwrap().set_channel_on_off(channel, 0, off)?;
+
+        Ok(())
+    }
+
+    fn set_level(&mut self, channel: Channel, value: u16) -> Result<(), RoverError> {
+        if value == 1 {
+            trace!("set_channel_on_off({:?}, 0, 4095)", channel);
+            self.pwm.lock().unwrap().set_channel_on_off(channel, 0, 4095)?;
+        } else {
+            trace!("set_channel_on_off({:?}, 0, 0)", channel);
+            self.pwm.lock().unwrap().set_channel_on_off(channel, 0, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// This relies on register auto-increment, which the driver enables on
+    /// its first channel write.
+    fn read_channel(&self, channel: Channel) -> Result<(u16, u16), LinuxI2CError> {
+        let mut data = [0; 4];
+
+        self.read_registers(LED0_ON_L_REGISTER + 4 * channel as u8, &mut data)?;
+
+        Ok((
+            u16::from_le_bytes([data[0], data[1]]),
+            u16::from_le_bytes([data[2], data[3]]),
+        ))
+    }
+
+    /// A reset chip comes back asleep with its prescale lost.
+    fn was_reset(&self) -> Result<bool, LinuxI2CError> {
+        let mut mode1 = [0];
+
+        self.read_registers(MODE1_REGISTER, &mut mode1)?;
+
+        trace!("Pca9685Backend.was_reset(): MODE1 = {:#04x}", mode1[0]);
+
+        // We never leave the oscillator asleep: SLEEP set means the chip came
+        // back from a power-on reset. RESTART is not checked because it is
+        // only ever cleared by writing to it, and a reset clears it anyway.
+        Ok(mode1[0] & MODE1_SLEEP != 0)
+    }
+
+    /// Re-applies the prescale and enables the device.
+    fn reinitialize(&mut self) -> Result<(), RoverError> {
+        let mut pwm = self.pwm.lock().unwrap();
+
+        // The driver caches MODE1, which no longer matches the chip.
+        pwm.reset_internal_driver_state();
+        initialize(&mut pwm, self.prescale)
+    }
+}
+
+/// Outcome of the last transaction with a board, shared by the backends of
+/// all its outputs, so that the health of the bus can be reported without
+/// talking to it.
+#[derive(Debug, Default)]
+pub struct I2cHealth {
+    last_error: Mutex<Option<String>>,
+    errors: AtomicU64,
+}
+
+impl I2cHealth {
+    /// Error of the last transaction, `None` if it succeeded.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Number of failed transactions so far.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    fn record<T, E: fmt::Display>(&self, result: &Result<T, E>) {
+        let mut last_error = self.last_error.lock().unwrap();
+
+        match result {
+            Ok(_) => {
+                if let Some(error) = last_error.take() {
+                    info!("I2C transactions succeed again, after: {}", error);
+                }
+            },
+            Err(e) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                *last_error = Some(e.to_string());
+            },
+        }
+    }
+}
+
+/// Records the outcome of each transaction of `backend` in an `I2cHealth`.
+#[derive(Clone, Debug)]
+pub struct Monitored<B> {
+    backend: B,
+    health: Arc<I2cHealth>,
+}
+
+impl<B> Monitored<B> {
+    pub fn new(backend: B, health: Arc<I2cHealth>) -> Self {
+        Monitored { backend, health }
+    }
+
+    fn record<T, E: fmt::Display>(&self, result: Result<T, E>) -> Result<T, E> {
+        self.health.record(&result);
+
+        result
+    }
+}
+
+impl<B: MotorBackend> MotorBackend for Monitored<B> {
+    fn set_pwm_duty_cycle(&mut self, channel: Channel, pulse: u16) -> Result<(), RoverError> {
+        let result = self.backend.set_pwm_duty_cycle(channel, pulse);
+
+        self.record(result)
+    }
+
+    fn set_off_count(&mut self, channel: Channel, off: u16) -> Result<(), RoverError> {
+        let result = self.backend.set_off_count(channel, off);
+
+        self.record(result)
+    }
+
+    fn set_level(&mut self, channel: Channel, value: u16) -> Result<(), RoverError> {
+        let result = self.backend.set_level(channel, value);
+
+        self.record(result)
+    }
+
+    fn stop(&mut self, control: Channel) -> Result<(), RoverError> {
+        let result = self.backend.stop(control);
+
+        self.record(result)
+    }
+
+    fn read_channel(&self, channel: Channel) -> Result<(u16, u16), LinuxI2CError> {
+        self.record(self.backend.read_channel(channel))
+    }
+
+    fn was_reset(&self) -> Result<bool, LinuxI2CError> {
+        self.record(self.backend.was_reset())
+    }
+
+    fn reinitialize(&mut self) -> Result<(), RoverError> {
+        let result = self.backend.reinitialize();
+
+        self.record(result)
+    }
+}
+
+/// Last state commanded on a channel of a `MockBackend`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MockChannel {
+    DutyCycle(u16),
+    OffCount(u16),
+    Level(u16),
+}
+
+/// Records the commanded channels in memory instead of driving hardware,
+/// to run the rover without a PCA9685 (e.g. on a development machine).
+///
+/// Clones share the recorded state, like motors sharing the same board.
+#[derive(Clone, Default)]
+pub struct MockBackend {
+    channels: Arc<Mutex<HashMap<u8, MockChannel>>>,
+}
+
+impl fmt::Debug for MockBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockBackend").finish()
+    }
+}
+
+impl MockBackend {
+    fn record(&mut self, channel: Channel, state: MockChannel) {
+        trace!("MockBackend: {:?} = {:?}", channel, state);
+        self.channels.lock().unwrap().insert(channel as u8, state);
+    }
+}
+
+impl MotorBackend for MockBackend {
+    fn set_pwm_duty_cycle(&mut self, channel: Channel, pulse: u16) -> Result<(), RoverError> {
+        self.record(channel, MockChannel::DutyCycle(pulse));
+
+        Ok(())
+    }
+
+    fn set_off_count(&mut self, channel: Channel, off: u16) -> Result<(), RoverError> {
+        self.record(channel, MockChannel::OffCount(off));
+
+        Ok(())
+    }
+
+    fn set_level(&mut self, channel: Channel, value: u16) -> Result<(), RoverError> {
+        self.record(channel, MockChannel::Level(value));
+
+        Ok(())
+    }
+
+    /// Mimics what a PCA9685 would have been programmed with.
+    fn read_channel(&self, channel: Channel) -> Result<(u16, u16), LinuxI2CError> {
+        Ok(match self.channels.lock().unwrap().get(&(channel as u8)) {
+            None | Some(MockChannel::Level(0)) => (0, 0),
+            Some(MockChannel::Level(_)) => (0, 4095),
+            Some(MockChannel::DutyCycle(pulse)) => (0, off_count(*pulse)),
+            Some(MockChannel::OffCount(off)) => (0, *off),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn off_count_of(pulse: u16) -> u16 {
+        let mut backend = MockBackend::default();
+
+        backend.set_pwm_duty_cycle(Channel::C0, pulse).unwrap();
+
+        let (on, off) = backend.read_channel(Channel::C0).unwrap();
+
+        assert_eq!(on, 0);
+
+        off
+    }
+
+    #[test]
+    fn zero_pulse_is_off() {
+        assert_eq!(off_count_of(0), 0);
+    }
+
+    #[test]
+    fn low_pulse() {
+        // 1% of 4096 counts, rounded.
+        assert_eq!(off_count_of(1), 40);
+    }
+
+    #[test]
+    fn half_pulse() {
+        assert_eq!(off_count_of(50), 2047);
+    }
+
+    #[test]
+    fn full_pulse() {
+        assert_eq!(off_count_of(100), 4095);
+    }
+
+    #[test]
+    fn out_of_range_pulse_is_clamped() {
+        assert_eq!(off_count_of(200), 4095);
+        assert_eq!(off_count_of(u16::MAX), 4095);
+    }
+
+    #[test]
+    fn servo_pulse_width() {
+        // 1.5ms of a 20ms period.
+        assert_eq!(pulse_width_count(1500, 50), 307);
+    }
+
+    #[test]
+    fn levels() {
+        let mut backend = MockBackend::default();
+
+        backend.set_level(Channel::C1, 1).unwrap();
+        backend.set_level(Channel::C2, 0).unwrap();
+
+        assert_eq!(backend.read_channel(Channel::C1).unwrap(), (0, 4095));
+        assert_eq!(backend.read_channel(Channel::C2).unwrap(), (0, 0));
+    }
+
+    /// A PCA9685 answering only while `plugged`.
+    #[derive(Clone, Debug)]
+    struct Flaky {
+        plugged: bool,
+    }
+
+    impl MotorBackend for Flaky {
+        fn set_pwm_duty_cycle(&mut self, _: Channel, _: u16) -> Result<(), RoverError> {
+            if self.plugged {
+                Ok(())
+            } else {
+                Err(RoverError::I2c(LinuxI2CError::Io(std::io::ErrorKind::TimedOut.into())))
+            }
+        }
+
+        fn set_off_count(&mut self, channel: Channel, _: u16) -> Result<(), RoverError> {
+            self.set_pwm_duty_cycle(channel, 0)
+        }
+
+        fn set_level(&mut self, channel: Channel, _: u16) -> Result<(), RoverError> {
+            self.set_pwm_duty_cycle(channel, 0)
+        }
+
+        fn read_channel(&self, _: Channel) -> Result<(u16, u16), LinuxI2CError> {
+            Ok((0, 0))
+        }
+    }
+
+    #[test]
+    fn health_follows_the_last_transaction() {
+        let health = Arc::new(I2cHealth::default());
+        let mut backend = Monitored::new(Flaky { plugged: false }, health.clone());
+
+        assert!(backend.set_level(Channel::C0, 1).is_err());
+        assert!(health.last_error().is_some());
+        assert_eq!(health.errors(), 1);
+
+        backend.backend.plugged = true;
+        backend.set_level(Channel::C0, 1).unwrap();
+
+        assert_eq!(health.last_error(), None);
+    }
+}
@@ -0,0 +1,1069 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::auxiliary::MAX_LEVEL;
+use crate::rover::{MinSpeedPolicy, TargetPolicy, MAX_SPEED, MOTOR_OUTPUTS, STEERING_OUTPUT};
+use crate::rgb::Color;
+use crate::servo::MAX_ANGLE;
+use crate::MAX_SEQUENCE_MS;
+
+/// PWM frequencies the PCA9685 supports, with its internal 25MHz oscillator.
+pub const FREQUENCY_RANGE_HZ: std::ops::RangeInclusive<u16> = 24..=1526;
+/// Trims, in percent of the commanded speed.
+pub const TRIM_RANGE: std::ops::RangeInclusive<i8> = -25..=25;
+/// Configuration file read when ROVER_CONFIG isn't set, if it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "rover.toml";
+/// Id of the board of `i2c_path` and `pca9685_address`, which the channels
+/// are on unless they say otherwise.
+pub const DEFAULT_BOARD: &str = "default";
+
+fn default_board() -> String {
+    DEFAULT_BOARD.to_string()
+}
+
+/// How many motors drive the rover.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u8")]
+pub enum Layout {
+    /// A left and a right motor.
+    TwoMotors,
+    /// Front and rear motors on each side, as on skid-steer rovers.
+    FourMotors,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2" => Ok(Layout::TwoMotors),
+            "4" => Ok(Layout::FourMotors),
+            _ => Err(format!("unknown layout {:?}, expected 2 or 4 (motors)", s)),
+        }
+    }
+}
+
+impl TryFrom<u8> for Layout {
+    type Error = String;
+
+    fn try_from(motors: u8) -> Result<Self, Self::Error> {
+        motors.to_string().parse()
+    }
+}
+
+/// A PCA9685 board besides the default one, e.g. for more channels.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoardConfig {
+    /// I2C bus the board is on, the default board's if not given.
+    pub i2c_path: Option<String>,
+    pub address: u8,
+    /// How long to wait before initializing the board, e.g. for it to power
+    /// up once the boards before it in `RoverConfig::init_order` drew their
+    /// inrush current.
+    #[serde(default)]
+    pub init_delay_ms: u64,
+}
+
+/// PCA9685 channels (0 to 15) a motor driver is wired to.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MotorChannels {
+    /// Sets the speed.
+    pub control: u8,
+    pub forward: u8,
+    pub backward: u8,
+    /// Whether the motor is mounted backwards, in which case its forward
+    /// and backward channels are swapped so that forward still moves the
+    /// rover forward.
+    #[serde(default)]
+    pub inverted: bool,
+    /// Id of the board the channels are on.
+    #[serde(default = "default_board")]
+    pub board: String,
+}
+
+impl MotorChannels {
+    fn new(control: u8, forward: u8, backward: u8) -> Self {
+        MotorChannels { control, forward, backward, inverted: false, board: default_board() }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&str, u8)> {
+        vec![self.control, self.forward, self.backward]
+            .into_iter()
+            .map(move |channel| (self.board.as_str(), channel))
+    }
+}
+
+impl FromStr for MotorChannels {
+    type Err = String;
+
+    /// Parses `<control>,<forward>,<backward>`, e.g. `5,3,4`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let channels = s
+            .split(',')
+            .map(|channel| channel.trim().parse::<u8>().ok().filter(|channel| *channel < 16))
+            .collect::<Option<Vec<_>>>();
+
+        match channels.as_deref() {
+            Some(&[control, forward, backward]) => Ok(MotorChannels::new(control, forward, backward)),
+            _ => Err(format!("expected <control>,<forward>,<backward> channels from 0 to 15, not {:?}", s)),
+        }
+    }
+}
+
+/// A servo on a PCA9685 channel, see `Servo`.
+///
+/// The pulse widths default to the usual 1.0ms to 2.0ms. To calibrate them,
+/// send `SetSteering` with -90 and 90 and widen (or narrow) the range until
+/// the servo reaches its end stops without straining against them.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServoConfig {
+    pub channel: u8,
+    /// Id of the board the channel is on.
+    #[serde(default = "default_board")]
+    pub board: String,
+    /// Pulse width turning the servo to -90 degrees.
+    #[serde(default = "ServoConfig::default_min_pulse_us")]
+    pub min_pulse_us: u16,
+    /// Pulse width turning the servo to 90 degrees.
+    #[serde(default = "ServoConfig::default_max_pulse_us")]
+    pub max_pulse_us: u16,
+    /// Angle of the servo in the neutral pose, e.g. to straighten wheels
+    /// that don't quite point forward at 0, see `Rover::neutral()`.
+    #[serde(default)]
+    pub neutral_angle: i8,
+}
+
+impl ServoConfig {
+    fn new(channel: u8) -> Self {
+        ServoConfig {
+            channel,
+            board: default_board(),
+            min_pulse_us: ServoConfig::default_min_pulse_us(),
+            max_pulse_us: ServoConfig::default_max_pulse_us(),
+            neutral_angle: 0,
+        }
+    }
+
+    fn default_min_pulse_us() -> u16 {
+        1000
+    }
+
+    fn default_max_pulse_us() -> u16 {
+        2000
+    }
+}
+
+/// Channel of an auxiliary output, either just the channel number (on the
+/// default board) or `{ channel = 3, board = "<id>", neutral_level = 20 }`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(from = "AuxSetting")]
+pub struct AuxChannel {
+    pub channel: u8,
+    pub board: String,
+    /// Level of the output in the neutral pose, off by default, see
+    /// `Rover::neutral()`.
+    pub neutral_level: u16,
+}
+
+impl AuxChannel {
+    fn new(channel: u8) -> Self {
+        AuxChannel { channel, board: default_board(), neutral_level: 0 }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AuxSetting {
+    Channel(u8),
+    OnBoard(AuxTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuxTable {
+    channel: u8,
+    #[serde(default = "default_board")]
+    board: String,
+    #[serde(default)]
+    neutral_level: u16,
+}
+
+impl From<AuxSetting> for AuxChannel {
+    fn from(setting: AuxSetting) -> Self {
+        match setting {
+            AuxSetting::Channel(channel) => AuxChannel::new(channel),
+            AuxSetting::OnBoard(AuxTable { channel, board, neutral_level }) => {
+                AuxChannel { channel, board, neutral_level }
+            },
+        }
+    }
+}
+
+/// Channels of an RGB LED, e.g. `{ red = 12, green = 13, blue = 14 }`,
+/// see `RgbLed`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RgbLedConfig {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    /// Id of the board the channels are on.
+    #[serde(default = "default_board")]
+    pub board: String,
+}
+
+/// Colors of the status LED in each state of the rover, see
+/// `Rover::state()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusColors {
+    pub ready: Color,
+    pub armed: Color,
+    pub fault: Color,
+}
+
+impl Default for StatusColors {
+    fn default() -> Self {
+        StatusColors {
+            ready: Color::new(0, 255, 0),
+            armed: Color::new(255, 255, 0),
+            fault: Color::new(255, 0, 0),
+        }
+    }
+}
+
+/// Configuration of the rover, read from a TOML file whose keys are the
+/// field names (`motors` for `layout`), see `load()`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoverConfig {
+    /// I2C bus the (default) PCA9685 board is on.
+    pub i2c_path: String,
+    pub pca9685_address: u8,
+    /// More PCA9685 boards by id, e.g. `[boards.front]`, which channels
+    /// refer to with `board = "front"`. They share `frequency_hz`.
+    pub boards: BTreeMap<String, BoardConfig>,
+    /// Same as `BoardConfig::init_delay_ms`, for the default board.
+    pub pca9685_init_delay_ms: u64,
+    /// Ids of the boards in the order they are initialized, the ones left
+    /// out following in the order of their ids.
+    pub init_order: Vec<String>,
+    /// How many times to try initializing the boards, which may not be
+    /// ready yet on cold boot, waiting `init_backoff_ms` after the first
+    /// attempt and twice as long after each of the next ones.
+    pub init_attempts: u32,
+    pub init_backoff_ms: u64,
+    /// Keep going when some boards still fail to initialize after the last
+    /// attempt, as long as one works: the commands to the motors on the
+    /// other ones are rejected, their steering servo and auxiliary outputs
+    /// missing. Off by default, failing to start instead.
+    pub allow_degraded: bool,
+    /// PWM frequency of the PCA9685 outputs, 25Hz by default: the closest
+    /// to the ~25.3Hz of the prescale of 240 the rover always ran at
+    /// (prescale 243).
+    pub frequency_hz: u16,
+    /// Percentage added to the speed of the left motor, negative to slow it
+    /// down, to correct a rover pulling to one side.
+    pub left_trim: i8,
+    pub right_trim: i8,
+    /// Speed below which the motors don't turn, only whine. Nonzero speeds
+    /// below it are handled according to `min_speed_policy`.
+    ///
+    /// To calibrate it, lift the rover and run each motor with `MotorRun`
+    /// at increasing speeds: the lowest one turning both wheels reliably is
+    /// the minimum speed. 0 (the default) disables it.
+    pub min_speed: u16,
+    pub min_speed_policy: MinSpeedPolicy,
+    /// Whether a new speed for a motor still ramping (or kicked, or
+    /// stopped before reversing) replaces the one it is on its way to
+    /// (`latest`, the default) or waits for it (`queue`).
+    pub target_policy: TargetPolicy,
+    /// How long the motors running faster than `reversal_guard_speed` are
+    /// stopped before reversing them, to spare the gearboxes and the
+    /// battery. 0 (the default) disables it.
+    pub reversal_dwell_ms: u64,
+    pub reversal_guard_speed: u16,
+    /// Duty cycle the motors start at for `kick_ms` when asked for a lower
+    /// speed, for them to overcome static friction. 0 (the default)
+    /// disables it.
+    pub kick_duty: u16,
+    pub kick_ms: u64,
+    /// Highest speed the motors run at whatever the clients ask, e.g. to
+    /// test indoors, see `Rover::max_speed`.
+    pub max_speed: u16,
+    /// Distance from the center (0.0 to 1.0) under which `Axis` commands
+    /// read gamepad sticks as centered, so that noisy sticks don't make the
+    /// rover creep. The rest of their travel is rescaled to start from 0.
+    pub axis_deadzone: f32,
+    /// Response curve of `Axis` commands, from 0.0 (linear, the default) to
+    /// 1.0 (cubic), for finer control at low speed.
+    pub axis_expo: f32,
+    /// Flip forward and backward, and left and right, of the operator's
+    /// inputs, so that the inversion survives restarts, see
+    /// `RoverCommand::SetSteeringInvert`.
+    pub invert_forward: bool,
+    pub invert_steering: bool,
+    /// Speed of the `TestPattern` commands, which also turn at it.
+    pub pattern_speed: u16,
+    /// How long a `Square` pattern drives along each side, and how long it
+    /// turns in place at each corner, to be tuned for a square turn.
+    pub pattern_side_ms: u32,
+    pub pattern_turn_ms: u32,
+    /// How long a `FigureEight` pattern drives each of its loops.
+    pub pattern_loop_ms: u32,
+    #[serde(rename = "motors")]
+    pub layout: Layout,
+    /// Channels of the left motor, or of the front left one with four
+    /// motors.
+    pub left_channels: MotorChannels,
+    pub right_channels: MotorChannels,
+    /// Only used with four motors.
+    pub rear_left_channels: MotorChannels,
+    pub rear_right_channels: MotorChannels,
+    /// Steering servo, if any. Servos want a 50Hz `frequency_hz`.
+    pub steering: Option<ServoConfig>,
+    /// Channels of the auxiliary outputs by name, see `AuxOutput`.
+    pub aux: BTreeMap<String, AuxChannel>,
+    /// RGB LEDs by id, which `SetColor` commands set.
+    pub rgb_leds: BTreeMap<String, RgbLedConfig>,
+    /// Id of the RGB LED showing the state of the rover in `status_colors`,
+    /// if any. `SetColor` commands still set it, until the state changes.
+    pub status_led: Option<String>,
+    pub status_colors: StatusColors,
+    /// Stop the rover when it receives no command for that long while
+    /// moving, 0 to disable the watchdog.
+    pub watchdog_ms: u64,
+    pub bind_addr: SocketAddr,
+    /// Directory of the static files, nothing outside of it being served.
+    pub web_root: String,
+    /// Path of the WebSocket endpoint, e.g. to namespace it behind a proxy.
+    pub ws_path: String,
+    /// PEM certificate chain and private key to serve HTTPS and WSS with,
+    /// both or neither. Requires the "tls" feature.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Origins of the browser clients allowed to call the HTTP endpoints
+    /// from another origin, `*` allowing any. None by default.
+    pub cors_origins: Vec<String>,
+    /// Most WebSocket connections open at once, the next ones being
+    /// rejected with 503 Service Unavailable. 0 for no limit.
+    pub max_connections: usize,
+    /// Demo mode, e.g. for exhibitions: how long each controller keeps
+    /// control before it goes to the next connection in line, the rover
+    /// stopping, with the time left in the telemetry. 0 (the default)
+    /// disables it. Requires exclusive control, see `Control`.
+    pub demo_session_ms: u64,
+    /// Whether the controller loses control once its demo session is over,
+    /// or the countdown is only shown. Enforced by default.
+    pub demo_enforce: bool,
+}
+
+impl Default for RoverConfig {
+    fn default() -> Self {
+        RoverConfig {
+            i2c_path: "/dev/i2c-1".to_string(),
+            // Address::default() of the pwm_pca9685 crate.
+            pca9685_address: 0x40,
+            boards: BTreeMap::new(),
+            pca9685_init_delay_ms: 0,
+            init_order: Vec::new(),
+            init_attempts: 5,
+            init_backoff_ms: 200,
+            allow_degraded: false,
+            frequency_hz: 25,
+            left_trim: 0,
+            right_trim: 0,
+            min_speed: 0,
+            min_speed_policy: MinSpeedPolicy::SnapUp,
+            target_policy: TargetPolicy::LatestWins,
+            reversal_dwell_ms: 0,
+            reversal_guard_speed: 50,
+            kick_duty: 0,
+            kick_ms: 50,
+            max_speed: MAX_SPEED,
+            axis_deadzone: 0.1,
+            axis_expo: 0.0,
+            invert_forward: false,
+            invert_steering: false,
+            pattern_speed: 40,
+            pattern_side_ms: 1000,
+            pattern_turn_ms: 700,
+            pattern_loop_ms: 5000,
+            layout: Layout::TwoMotors,
+            left_channels: MotorChannels::new(5, 3, 4),
+            right_channels: MotorChannels::new(0, 1, 2),
+            rear_left_channels: MotorChannels::new(11, 9, 10),
+            rear_right_channels: MotorChannels::new(6, 7, 8),
+            steering: None,
+            aux: BTreeMap::new(),
+            rgb_leds: BTreeMap::new(),
+            status_led: None,
+            status_colors: StatusColors::default(),
+            watchdog_ms: 500,
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            web_root: ".".to_string(),
+            ws_path: "/websocket".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            cors_origins: Vec::new(),
+            max_connections: 8,
+            demo_session_ms: 0,
+            demo_enforce: true,
+        }
+    }
+}
+
+/// Parses `0x`-prefixed hexadecimal or decimal numbers.
+fn parse_u8(value: &str) -> Result<u8, std::num::ParseIntError> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
+
+/// Reads and parses the `name` environment variable, if set.
+fn env_var<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value.trim().parse().map(Some).map_err(|e| format!("invalid {} {:?}: {}", name, value, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads a boolean environment variable, e.g. ROVER_LEFT_INVERTED=1.
+fn env_flag(name: &str) -> Option<bool> {
+    std::env::var(name).ok().map(|value| value == "1" || value == "true")
+}
+
+impl RoverConfig {
+    /// Reads the configuration file ROVER_CONFIG points to, or
+    /// `DEFAULT_CONFIG_PATH` if it exists, then applies the ROVER_*
+    /// environment variables over it, the defaults filling in the rest.
+    ///
+    /// Also returns the file the configuration was read from, if any.
+    pub fn load() -> Result<(Self, Option<PathBuf>), String> {
+        let path = match std::env::var("ROVER_CONFIG") {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|path| path.exists()),
+        };
+        let mut config = match &path {
+            Some(path) => RoverConfig::from_file(path)?,
+            None => RoverConfig::default(),
+        };
+
+        config.apply_env()?;
+        config.check()?;
+
+        Ok((config, path))
+    }
+
+    /// Reads a TOML configuration file, the defaults filling in what it
+    /// leaves out. The settings are not checked yet.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("unable to read {:?}: {}", path, e))?;
+
+        toml::from_str(&content).map_err(|e| format!("invalid {:?}: {}", path, e))
+    }
+
+    /// Overrides the settings the ROVER_* environment variables give.
+    fn apply_env(&mut self) -> Result<(), String> {
+        if let Ok(path) = std::env::var("ROVER_I2C_PATH") {
+            self.i2c_path = path;
+        }
+        if let Ok(address) = std::env::var("ROVER_PCA_ADDR") {
+            self.pca9685_address = parse_u8(&address).map_err(|e| {
+                format!("ROVER_PCA_ADDR must be an I2C address such as 0x40, not {:?}: {}", address, e)
+            })?;
+        }
+        if let Some(ms) = env_var("ROVER_PCA_INIT_DELAY_MS")? {
+            self.pca9685_init_delay_ms = ms;
+        }
+        // e.g. ROVER_INIT_ORDER=default,front
+        if let Ok(order) = std::env::var("ROVER_INIT_ORDER") {
+            self.init_order = order
+                .split(',')
+                .map(|id| id.trim().to_string())
+                .filter(|id| !id.is_empty())
+                .collect();
+        }
+        if let Some(frequency) = env_var("ROVER_PWM_FREQUENCY_HZ")? {
+            self.frequency_hz = frequency;
+        }
+        if let Some(attempts) = env_var("ROVER_INIT_ATTEMPTS")? {
+            self.init_attempts = attempts;
+        }
+        if let Some(ms) = env_var("ROVER_INIT_BACKOFF_MS")? {
+            self.init_backoff_ms = ms;
+        }
+        if let Some(allow) = env_flag("ROVER_ALLOW_DEGRADED") {
+            self.allow_degraded = allow;
+        }
+
+        if let Some(trim) = env_var("ROVER_LEFT_TRIM")? {
+            self.left_trim = trim;
+        }
+        if let Some(trim) = env_var("ROVER_RIGHT_TRIM")? {
+            self.right_trim = trim;
+        }
+
+        if let Some(speed) = env_var("ROVER_MIN_SPEED")? {
+            self.min_speed = speed;
+        }
+        if let Some(policy) = env_var("ROVER_MIN_SPEED_POLICY")? {
+            self.min_speed_policy = policy;
+        }
+        if let Some(policy) = env_var("ROVER_TARGET_POLICY")? {
+            self.target_policy = policy;
+        }
+        if let Some(ms) = env_var("ROVER_REVERSAL_DWELL_MS")? {
+            self.reversal_dwell_ms = ms;
+        }
+        if let Some(speed) = env_var("ROVER_REVERSAL_GUARD_SPEED")? {
+            self.reversal_guard_speed = speed;
+        }
+        if let Some(duty) = env_var("ROVER_KICK_DUTY")? {
+            self.kick_duty = duty;
+        }
+        if let Some(ms) = env_var("ROVER_KICK_MS")? {
+            self.kick_ms = ms;
+        }
+        if let Some(speed) = env_var("ROVER_MAX_SPEED")? {
+            self.max_speed = speed;
+        }
+        if let Some(deadzone) = env_var("ROVER_AXIS_DEADZONE")? {
+            self.axis_deadzone = deadzone;
+        }
+        if let Some(expo) = env_var("ROVER_AXIS_EXPO")? {
+            self.axis_expo = expo;
+        }
+        if let Some(inverted) = env_flag("ROVER_INVERT_FORWARD") {
+            self.invert_forward = inverted;
+        }
+        if let Some(inverted) = env_flag("ROVER_INVERT_STEERING") {
+            self.invert_steering = inverted;
+        }
+        if let Some(speed) = env_var("ROVER_PATTERN_SPEED")? {
+            self.pattern_speed = speed;
+        }
+        if let Some(ms) = env_var("ROVER_PATTERN_SIDE_MS")? {
+            self.pattern_side_ms = ms;
+        }
+        if let Some(ms) = env_var("ROVER_PATTERN_TURN_MS")? {
+            self.pattern_turn_ms = ms;
+        }
+        if let Some(ms) = env_var("ROVER_PATTERN_LOOP_MS")? {
+            self.pattern_loop_ms = ms;
+        }
+
+        if let Some(layout) = env_var("ROVER_MOTORS")? {
+            self.layout = layout;
+        }
+        for (name, channels) in [
+            ("LEFT", &mut self.left_channels),
+            ("RIGHT", &mut self.right_channels),
+            ("REAR_LEFT", &mut self.rear_left_channels),
+            ("REAR_RIGHT", &mut self.rear_right_channels),
+        ] {
+            if let Some(value) = env_var::<MotorChannels>(&format!("ROVER_{}_CHANNELS", name))? {
+                // The file may still say whether the motor is inverted, and
+                // which board it is on.
+                *channels = MotorChannels { inverted: channels.inverted, board: channels.board.clone(), ..value };
+            }
+            if let Some(inverted) = env_flag(&format!("ROVER_{}_INVERTED", name)) {
+                channels.inverted = inverted;
+            }
+        }
+        if let Some(channel) = env_var("ROVER_STEERING_CHANNEL")? {
+            self.steering.get_or_insert_with(|| ServoConfig::new(channel)).channel = channel;
+        }
+        if let Some(steering) = &mut self.steering {
+            for (name, width) in [
+                ("ROVER_STEERING_MIN_PULSE_US", &mut steering.min_pulse_us),
+                ("ROVER_STEERING_MAX_PULSE_US", &mut steering.max_pulse_us),
+            ] {
+                if let Some(value) = env_var(name)? {
+                    *width = value;
+                }
+            }
+            if let Some(angle) = env_var("ROVER_STEERING_NEUTRAL_ANGLE")? {
+                steering.neutral_angle = angle;
+            }
+        }
+        // e.g. ROVER_AUX=headlight=12,taillight=13, replacing the outputs of
+        // the file. They are all on the default board.
+        if let Ok(aux) = std::env::var("ROVER_AUX") {
+            self.aux.clear();
+
+            for output in aux.split(',').filter(|output| !output.trim().is_empty()) {
+                let invalid = || format!("ROVER_AUX outputs must be <name>=<channel from 0 to 15>, not {:?}", output);
+                let (name, channel) = output.split_once('=').ok_or_else(invalid)?;
+                let name = name.trim();
+                let channel = channel.trim().parse::<u8>().map_err(|_| invalid())?;
+
+                if name.is_empty() {
+                    return Err(invalid());
+                }
+
+                if self.aux.insert(name.to_string(), AuxChannel::new(channel)).is_some() {
+                    return Err(format!("ROVER_AUX declares {:?} twice", name));
+                }
+            }
+        }
+
+        if let Ok(id) = std::env::var("ROVER_STATUS_LED") {
+            self.status_led = Some(id).filter(|id| !id.is_empty());
+        }
+        // e.g. ROVER_STATUS_ARMED_COLOR=255,160,0
+        for (name, color) in [
+            ("READY", &mut self.status_colors.ready),
+            ("ARMED", &mut self.status_colors.armed),
+            ("FAULT", &mut self.status_colors.fault),
+        ] {
+            if let Some(value) = env_var(&format!("ROVER_STATUS_{}_COLOR", name))? {
+                *color = value;
+            }
+        }
+
+        if let Some(ms) = env_var("ROVER_WATCHDOG_MS")? {
+            self.watchdog_ms = ms;
+        }
+        if let Some(addr) = env_var("ROVER_BIND_ADDR")? {
+            self.bind_addr = addr;
+        }
+        if let Ok(root) = std::env::var("ROVER_WEB_ROOT") {
+            self.web_root = root;
+        }
+        if let Ok(path) = std::env::var("ROVER_WS_PATH") {
+            self.ws_path = path;
+        }
+        if let Ok(path) = std::env::var("ROVER_TLS_CERT") {
+            self.tls_cert = Some(path);
+        }
+        if let Ok(path) = std::env::var("ROVER_TLS_KEY") {
+            self.tls_key = Some(path);
+        }
+        // e.g. ROVER_CORS_ORIGINS=http://localhost:8080,https://rover.example
+        if let Ok(origins) = std::env::var("ROVER_CORS_ORIGINS") {
+            self.cors_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        if let Some(connections) = env_var("ROVER_MAX_CONNECTIONS")? {
+            self.max_connections = connections;
+        }
+        if let Some(ms) = env_var("ROVER_DEMO_SESSION_MS")? {
+            self.demo_session_ms = ms;
+        }
+        if let Some(enforce) = env_flag("ROVER_DEMO_ENFORCE") {
+            self.demo_enforce = enforce;
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure that the settings are within range and consistent, naming
+    /// them as in the file and in the environment.
+    fn check(&self) -> Result<(), String> {
+        if !FREQUENCY_RANGE_HZ.contains(&self.frequency_hz) {
+            return Err(format!(
+                "frequency_hz (ROVER_PWM_FREQUENCY_HZ) must be between {} and {}Hz, not {}",
+                FREQUENCY_RANGE_HZ.start(),
+                FREQUENCY_RANGE_HZ.end(),
+                self.frequency_hz,
+            ));
+        }
+
+        if self.init_attempts == 0 {
+            return Err("init_attempts (ROVER_INIT_ATTEMPTS) must be at least 1".to_string());
+        }
+
+        for &(name, trim) in &[
+            ("left_trim (ROVER_LEFT_TRIM)", self.left_trim),
+            ("right_trim (ROVER_RIGHT_TRIM)", self.right_trim),
+        ] {
+            if !TRIM_RANGE.contains(&trim) {
+                return Err(format!(
+                    "{} must be a percentage between {} and {}, not {}",
+                    name,
+                    TRIM_RANGE.start(),
+                    TRIM_RANGE.end(),
+                    trim,
+                ));
+            }
+        }
+
+        if self.min_speed > MAX_SPEED {
+            return Err(format!(
+                "min_speed (ROVER_MIN_SPEED) must be between 0 and {}, not {}",
+                MAX_SPEED,
+                self.min_speed,
+            ));
+        }
+        if self.reversal_guard_speed > MAX_SPEED {
+            return Err(format!(
+                "reversal_guard_speed (ROVER_REVERSAL_GUARD_SPEED) must be between 0 and {}, not {}",
+                MAX_SPEED,
+                self.reversal_guard_speed,
+            ));
+        }
+        if self.kick_duty > MAX_SPEED {
+            return Err(format!(
+                "kick_duty (ROVER_KICK_DUTY) must be between 0 and {}, not {}",
+                MAX_SPEED,
+                self.kick_duty,
+            ));
+        }
+        if !(1..=MAX_SPEED).contains(&self.max_speed) {
+            return Err(format!(
+                "max_speed (ROVER_MAX_SPEED) must be between 1 and {}, not {}",
+                MAX_SPEED,
+                self.max_speed,
+            ));
+        }
+        if self.min_speed > self.max_speed {
+            return Err(format!(
+                "min_speed ({}) can't be above max_speed ({})",
+                self.min_speed,
+                self.max_speed,
+            ));
+        }
+        if !(0.0..1.0).contains(&self.axis_deadzone) {
+            return Err(format!(
+                "axis_deadzone (ROVER_AXIS_DEADZONE) must be at least 0.0 and less than 1.0, not {}",
+                self.axis_deadzone,
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.axis_expo) {
+            return Err(format!(
+                "axis_expo (ROVER_AXIS_EXPO) must be between 0.0 and 1.0, not {}",
+                self.axis_expo,
+            ));
+        }
+        if !(1..=MAX_SPEED).contains(&self.pattern_speed) {
+            return Err(format!(
+                "pattern_speed (ROVER_PATTERN_SPEED) must be between 1 and {}, not {}",
+                MAX_SPEED,
+                self.pattern_speed,
+            ));
+        }
+        // Test patterns run as sequences.
+        let square_ms = 4 * (u64::from(self.pattern_side_ms) + u64::from(self.pattern_turn_ms));
+
+        if square_ms > u64::from(MAX_SEQUENCE_MS) {
+            return Err(format!(
+                "a square pattern lasts at most {}ms, not {}, lower pattern_side_ms (ROVER_PATTERN_SIDE_MS) or pattern_turn_ms (ROVER_PATTERN_TURN_MS)",
+                MAX_SEQUENCE_MS,
+                square_ms,
+            ));
+        }
+        if 2 * u64::from(self.pattern_loop_ms) > u64::from(MAX_SEQUENCE_MS) {
+            return Err(format!(
+                "pattern_loop_ms (ROVER_PATTERN_LOOP_MS) must be at most {}, not {}",
+                MAX_SEQUENCE_MS / 2,
+                self.pattern_loop_ms,
+            ));
+        }
+
+        if let Some(steering) = &self.steering {
+            if steering.min_pulse_us >= steering.max_pulse_us {
+                return Err(format!(
+                    "the steering min_pulse_us (ROVER_STEERING_MIN_PULSE_US, {}) must be less than its max_pulse_us (ROVER_STEERING_MAX_PULSE_US, {})",
+                    steering.min_pulse_us,
+                    steering.max_pulse_us,
+                ));
+            }
+            // The pulses must fit in the PWM period.
+            if u32::from(steering.max_pulse_us) * u32::from(self.frequency_hz) >= 1_000_000 {
+                return Err(format!(
+                    "{}us steering pulses don't fit in the PWM period at {}Hz, set frequency_hz to 50",
+                    steering.max_pulse_us,
+                    self.frequency_hz,
+                ));
+            }
+            if !(-MAX_ANGLE..=MAX_ANGLE).contains(&steering.neutral_angle) {
+                return Err(format!(
+                    "the steering neutral_angle (ROVER_STEERING_NEUTRAL_ANGLE) must be between -{} and {} degrees, not {}",
+                    MAX_ANGLE,
+                    MAX_ANGLE,
+                    steering.neutral_angle,
+                ));
+            }
+        }
+        for (name, aux) in &self.aux {
+            // Outputs are set by id, see `Rover::output_mut()`.
+            if MOTOR_OUTPUTS.contains(&name.as_str()) || name == STEERING_OUTPUT {
+                return Err(format!("the {:?} aux output (ROVER_AUX) must be renamed, a motor or the steering has that name", name));
+            }
+            if aux.neutral_level > MAX_LEVEL {
+                return Err(format!(
+                    "the neutral_level of the {:?} aux output must be between 0 and {}, not {}",
+                    name,
+                    MAX_LEVEL,
+                    aux.neutral_level,
+                ));
+            }
+        }
+        if let Some(id) = &self.status_led {
+            if !self.rgb_leds.contains_key(id) {
+                return Err(format!("status_led (ROVER_STATUS_LED) names the {:?} RGB LED, which doesn't exist, see rgb_leds", id));
+            }
+        }
+
+        if !self.ws_path.starts_with('/') {
+            return Err(format!(
+                "ws_path (ROVER_WS_PATH) must be an absolute path such as /websocket, not {:?}",
+                self.ws_path,
+            ));
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("tls_cert (ROVER_TLS_CERT) and tls_key (ROVER_TLS_KEY) must be given together".to_string());
+        }
+
+        self.check_channels()
+    }
+
+    /// Channels of the motors of the layout, left then right, front first.
+    pub fn motor_channels(&self) -> Vec<&MotorChannels> {
+        match self.layout {
+            Layout::TwoMotors => vec![&self.left_channels, &self.right_channels],
+            Layout::FourMotors => vec![
+                &self.left_channels,
+                &self.rear_left_channels,
+                &self.right_channels,
+                &self.rear_right_channels,
+            ],
+        }
+    }
+
+    /// I2C bus and address of each board by id, the default one included.
+    pub fn boards(&self) -> BTreeMap<&str, (&str, u8)> {
+        self.boards
+            .iter()
+            .map(|(id, board)| {
+                let path = board.i2c_path.as_deref().unwrap_or(&self.i2c_path);
+
+                (id.as_str(), (path, board.address))
+            })
+            .chain(std::iter::once((DEFAULT_BOARD, (self.i2c_path.as_str(), self.pca9685_address))))
+            .collect()
+    }
+
+    /// Ids of the boards in the order to initialize them, with how long to
+    /// wait before each, see `init_order`.
+    pub fn board_inits(&self) -> Vec<(&str, Duration)> {
+        let delay = |id: &str| {
+            let ms = match self.boards.get(id) {
+                Some(board) => board.init_delay_ms,
+                None => self.pca9685_init_delay_ms,
+            };
+
+            Duration::from_millis(ms)
+        };
+        let boards = self.boards();
+        let listed = self.init_order.iter().map(String::as_str).filter(|id| boards.contains_key(id));
+        let others = boards.keys().copied().filter(|id| !self.init_order.iter().any(|listed| listed == id));
+
+        listed.chain(others).map(|id| (id, delay(id))).collect()
+    }
+
+    /// Makes sure that no channel is wired to two things at once, and that
+    /// the boards exist.
+    fn check_channels(&self) -> Result<(), String> {
+        if self.boards.contains_key(DEFAULT_BOARD) {
+            return Err(format!(
+                "the {:?} board is the one of i2c_path and pca9685_address, declare the others with another id",
+                DEFAULT_BOARD,
+            ));
+        }
+
+        let boards = self.boards();
+        let mut buses = Vec::new();
+
+        for (id, bus) in &boards {
+            if let Some((other, _)) = buses.iter().find(|(_, other_bus)| other_bus == bus) {
+                return Err(format!(
+                    "the {:?} and {:?} boards are both at address {:#04x} on {}",
+                    other,
+                    id,
+                    bus.1,
+                    bus.0,
+                ));
+            }
+            buses.push((*id, *bus));
+        }
+
+        for (i, id) in self.init_order.iter().enumerate() {
+            if !boards.contains_key(id.as_str()) {
+                return Err(format!("init_order (ROVER_INIT_ORDER) names the {:?} board, which doesn't exist", id));
+            }
+            if self.init_order[..i].contains(id) {
+                return Err(format!("init_order (ROVER_INIT_ORDER) names the {:?} board more than once", id));
+            }
+        }
+
+        let mut used = Vec::new();
+
+        let motor_channels = self.motor_channels();
+        let motor_channels = motor_channels.into_iter().flat_map(MotorChannels::iter);
+        let steering_channel = self.steering.iter().map(|steering| (steering.board.as_str(), steering.channel));
+        let aux_channels = self.aux.values().map(|aux| (aux.board.as_str(), aux.channel));
+        let led_channels = self.rgb_leds.values().flat_map(|led| {
+            let board = led.board.as_str();
+
+            [(board, led.red), (board, led.green), (board, led.blue)]
+        });
+
+        for (board, channel) in motor_channels.chain(steering_channel).chain(aux_channels).chain(led_channels) {
+            if !boards.contains_key(board) {
+                return Err(format!("there is no {:?} board, declare it in [boards.{}]", board, board));
+            }
+            if channel > 15 {
+                return Err(format!("there is no channel {}, the PCA9685 has channels 0 to 15", channel));
+            }
+            if used.contains(&(board, channel)) {
+                return Err(format!("channel {} of the {:?} board is assigned to more than one output", channel, board));
+            }
+            used.push((board, channel));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_file() {
+        let config: RoverConfig = toml::from_str(r#"
+            pca9685_address = 0x41
+            frequency_hz = 50
+            min_speed_policy = "stop"
+            target_policy = "queue"
+            motors = 4
+            bind_addr = "127.0.0.1:8080"
+
+            [left_channels]
+            control = 12
+            forward = 13
+            backward = 14
+            inverted = true
+
+            [steering]
+            channel = 15
+
+            [aux]
+            headlight = 3
+        "#).unwrap();
+
+        assert_eq!(config.pca9685_address, 0x41);
+        assert_eq!(config.min_speed_policy, MinSpeedPolicy::Stop);
+        assert_eq!(config.target_policy, TargetPolicy::Queue);
+        assert_eq!(config.layout, Layout::FourMotors);
+        assert_eq!(config.left_channels, MotorChannels { inverted: true, ..MotorChannels::new(12, 13, 14) });
+        assert_eq!(config.steering, Some(ServoConfig::new(15)));
+        assert_eq!(config.aux["headlight"], AuxChannel::new(3));
+        // The defaults fill in the rest.
+        assert_eq!(config.right_channels, RoverConfig::default().right_channels);
+        assert_eq!(config.watchdog_ms, 500);
+        assert_eq!(config.check(), Ok(()));
+    }
+
+    #[test]
+    fn channels_can_be_on_other_boards() {
+        let mut config: RoverConfig = toml::from_str(r#"
+            [boards.extra]
+            address = 0x41
+
+            [steering]
+            channel = 0
+            board = "extra"
+
+            [aux]
+            headlight = { channel = 1, board = "extra", neutral_level = 20 }
+        "#).unwrap();
+
+        // Channels 0 and 1 of the default board drive the right motor.
+        assert_eq!(config.check(), Ok(()));
+        assert_eq!(config.boards()["extra"], ("/dev/i2c-1", 0x41));
+        assert_eq!(config.aux["headlight"].neutral_level, 20);
+
+        config.aux.get_mut("headlight").unwrap().channel = 0;
+        assert!(config.check().unwrap_err().contains("more than one output"));
+
+        config.aux.get_mut("headlight").unwrap().board = "missing".to_string();
+        assert!(config.check().unwrap_err().contains("no \"missing\" board"));
+    }
+
+    #[test]
+    fn rgb_leds_show_the_status() {
+        let mut config: RoverConfig = toml::from_str(r#"
+            status_led = "status"
+
+            [rgb_leds.status]
+            red = 12
+            green = 13
+            blue = 14
+
+            [status_colors]
+            armed = { r = 255, g = 160, b = 0 }
+        "#).unwrap();
+
+        assert_eq!(config.check(), Ok(()));
+        assert_eq!(config.status_colors.armed, Color::new(255, 160, 0));
+        // The defaults fill in the rest.
+        assert_eq!(config.status_colors.ready, Color::new(0, 255, 0));
+
+        config.status_led = Some("missing".to_string());
+        assert!(config.check().unwrap_err().contains("\"missing\" RGB LED"));
+
+        config.status_led = None;
+        config.rgb_leds.get_mut("status").unwrap().blue = 0;
+        assert!(config.check().unwrap_err().contains("more than one output"));
+    }
+
+    #[test]
+    fn boards_initialize_in_order() {
+        let mut config: RoverConfig = toml::from_str(r#"
+            init_order = ["rear"]
+
+            [boards.front]
+            address = 0x41
+
+            [boards.rear]
+            address = 0x42
+            init_delay_ms = 300
+        "#).unwrap();
+
+        assert_eq!(config.check(), Ok(()));
+        // The boards left out follow by id.
+        assert_eq!(config.board_inits(), [
+            ("rear", Duration::from_millis(300)),
+            (DEFAULT_BOARD, Duration::from_millis(0)),
+            ("front", Duration::from_millis(0)),
+        ]);
+
+        config.init_order.push("rear".to_string());
+        assert!(config.check().unwrap_err().contains("more than once"));
+
+        config.init_order = vec!["middle".to_string()];
+        assert!(config.check().unwrap_err().contains("\"middle\" board, which doesn't exist"));
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Which connection controls the rover, the other ones only observing it.
+#[derive(Debug)]
+pub struct Control {
+    /// Whether only the controller can drive the rover.
+    exclusive: bool,
+    /// How long each controller keeps control in demo mode, see
+    /// `with_session()`.
+    session: Option<Duration>,
+    /// Whether `expire()` takes control back once the session is over, or
+    /// the countdown is only shown.
+    enforce: bool,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    controller: Option<SocketAddr>,
+    /// When the controller got control.
+    since: Option<Instant>,
+    /// Observers, in the order they get control.
+    waiting: VecDeque<SocketAddr>,
+}
+
+impl State {
+    fn hand_over(&mut self, controller: Option<SocketAddr>) {
+        self.controller = controller;
+        self.since = controller.map(|_| Instant::now());
+    }
+}
+
+impl Control {
+    pub fn new(exclusive: bool) -> Self {
+        Control {
+            exclusive,
+            session: None,
+            enforce: false,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Demo mode: each controller only keeps control for `session`, the
+    /// time left being reported by `remaining()`. If `enforce`, `expire()`
+    /// then hands control to the next connection in line.
+    pub fn with_session(self, session: Duration, enforce: bool) -> Self {
+        Control {
+            session: Some(session),
+            enforce,
+            ..self
+        }
+    }
+
+    /// Registers a new connection, giving it control if nobody has it.
+    pub fn join(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.controller.is_none() {
+            info!("{} now controls the rover", addr);
+            state.hand_over(Some(addr));
+        } else {
+            state.waiting.push_back(addr);
+        }
+    }
+
+    /// Unregisters a connection. If it had control, control goes to the
+    /// connection waiting for the longest.
+    ///
+    /// Returns whether the rover must stop: when the controller leaves in
+    /// exclusive mode, the next one starting from a stopped rover, or when
+    /// the last connection leaves otherwise. Observers come and go without
+    /// stopping the rover.
+    pub fn leave(&self, addr: SocketAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        state.waiting.retain(|waiting| *waiting != addr);
+        if state.controller != Some(addr) {
+            return false;
+        }
+
+        let next = state.waiting.pop_front();
+
+        state.hand_over(next);
+        if let Some(controller) = state.controller {
+            info!("{} now controls the rover", controller);
+        }
+
+        self.exclusive || state.controller.is_none()
+    }
+
+    /// Gives control to `addr`, the previous controller going back to the
+    /// end of the queue.
+    ///
+    /// Returns the previous controller, if control changed hands.
+    pub fn take(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.controller == Some(addr) {
+            return None;
+        }
+
+        state.waiting.retain(|waiting| *waiting != addr);
+
+        let previous = state.controller;
+
+        state.hand_over(Some(addr));
+        info!("{} took control of the rover from {:?}", addr, previous);
+        state.waiting.extend(previous);
+
+        previous
+    }
+
+    /// Time left in the session of the controller in demo mode, `None`
+    /// otherwise or without a controller.
+    pub fn remaining(&self) -> Option<Duration> {
+        let since = self.state.lock().unwrap().since?;
+
+        Some(self.session?.saturating_sub(since.elapsed()))
+    }
+
+    /// Ends the session of the controller once it is over in demo mode, if
+    /// enforced: control goes to the connection waiting for the longest,
+    /// the previous controller going back to the end of the queue. With
+    /// nobody waiting, nobody has control until a connection requests it.
+    ///
+    /// Returns the previous controller, if its session ended.
+    pub fn expire(&self) -> Option<SocketAddr> {
+        if !self.enforce || self.remaining()? > Duration::from_secs(0) {
+            return None;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let previous = state.controller?;
+        let next = state.waiting.pop_front();
+
+        state.hand_over(next);
+        state.waiting.push_back(previous);
+        info!("the session of {} is over, {:?} now controls the rover", previous, next);
+
+        Some(previous)
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    /// Whether `addr` can drive the rover.
+    pub fn has_control(&self, addr: SocketAddr) -> bool {
+        !self.exclusive || self.state.lock().unwrap().controller == Some(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_controller_or_the_last_connection_stops_the_rover() {
+        let (first, second) = (SocketAddr::from(([127, 0, 0, 1], 1)), SocketAddr::from(([127, 0, 0, 1], 2)));
+
+        let exclusive = Control::new(true);
+        exclusive.join(first);
+        exclusive.join(second);
+        assert!(exclusive.leave(first));
+        exclusive.join(first);
+        assert!(!exclusive.leave(first));
+
+        let shared = Control::new(false);
+        shared.join(first);
+        shared.join(second);
+        assert!(!shared.leave(first));
+        assert!(shared.has_control(second));
+        assert!(shared.leave(second));
+    }
+
+    #[test]
+    fn demo_sessions_hand_control_over() {
+        let (first, second) = (SocketAddr::from(([127, 0, 0, 1], 1)), SocketAddr::from(([127, 0, 0, 1], 2)));
+
+        let shown = Control::new(true).with_session(Duration::from_secs(0), false);
+        shown.join(first);
+        assert_eq!(shown.remaining(), Some(Duration::from_secs(0)));
+        assert_eq!(shown.expire(), None);
+
+        let demo = Control::new(true).with_session(Duration::from_secs(0), true);
+        demo.join(first);
+        demo.join(second);
+        assert_eq!(demo.expire(), Some(first));
+        assert!(demo.has_control(second));
+        assert_eq!(demo.expire(), Some(second));
+        assert!(demo.has_control(first));
+
+        // With nobody waiting, until someone requests control.
+        assert!(!demo.leave(second));
+        assert_eq!(demo.expire(), Some(first));
+        assert!(!demo.has_control(first));
+        assert_eq!(demo.remaining(), None);
+        assert_eq!(demo.take(first), None);
+        assert!(demo.has_control(first));
+
+        assert_eq!(Control::new(true).remaining(), None);
+    }
+}
@@ -0,0 +1,55 @@
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+
+/// How long browsers can cache the reply to a preflight request, in seconds.
+const PREFLIGHT_MAX_AGE: &str = "600";
+
+/// Lets browser clients served from other origins, e.g. a development
+/// server, call the HTTP endpoints. WebSocket connections don't need it.
+#[derive(Debug)]
+pub struct Cors {
+    /// Allowed origins such as `http://localhost:8080`, `*` allowing any.
+    /// Empty to add no CORS headers at all.
+    origins: Vec<String>,
+}
+
+impl Cors {
+    pub fn new(origins: Vec<String>) -> Self {
+        Cors { origins }
+    }
+
+    /// The origin of a request, if it is allowed.
+    pub fn allowed_origin(&self, headers: &HeaderMap) -> Option<HeaderValue> {
+        let origin = headers.get(header::ORIGIN)?;
+        let allowed = self.origins
+            .iter()
+            .any(|allowed| allowed == "*" || origin == allowed.as_str());
+
+        allowed.then(|| origin.clone())
+    }
+
+    /// Replies to a preflight request from an allowed `origin`.
+    pub fn preflight(origin: HeaderValue) -> Response<Body> {
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST")
+            // JSON bodies aren't "simple" requests.
+            .header(header::ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type")
+            .header(header::ACCESS_CONTROL_MAX_AGE, PREFLIGHT_MAX_AGE)
+            .body(Body::empty())
+            .unwrap();
+
+        Cors::allow(origin, &mut response);
+
+        response
+    }
+
+    /// Lets `origin` read `response`.
+    pub fn allow(origin: HeaderValue, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        // The reply depends on the origin, caches must not mix them up.
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
@@ -0,0 +1,76 @@
+use serde_json::Value;
+use tungstenite::Message;
+
+/// How a WebSocket message is encoded, its reply using the same encoding.
+///
+/// Clients on constrained links can send binary MessagePack messages
+/// instead of JSON, told apart by their first byte: a MessagePack command,
+/// a map or a string, never starts with an ASCII character unlike JSON.
+/// Binary messages starting with one are still read as UTF-8 JSON.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    /// Encoding of a binary message.
+    pub fn of(data: &[u8]) -> Self {
+        match data.first() {
+            Some(byte) if !byte.is_ascii() => Encoding::MessagePack,
+            _ => Encoding::Json,
+        }
+    }
+
+    /// Decodes a binary message into JSON text, for it to be parsed like a
+    /// text message.
+    pub fn decode(self, data: Vec<u8>) -> Result<String, String> {
+        match self {
+            Encoding::Json => String::from_utf8(data).map_err(|e| format!("binary messages must be UTF-8 JSON: {}", e)),
+            Encoding::MessagePack => rmp_serde::from_slice::<Value>(&data)
+                .map(|value| value.to_string())
+                .map_err(|e| format!("invalid MessagePack message: {}", e)),
+        }
+    }
+
+    /// Encodes a JSON `reply` into a message.
+    pub fn encode(self, reply: String) -> Message {
+        match self {
+            Encoding::Json => Message::Text(reply),
+            Encoding::MessagePack => {
+                let value: Value = serde_json::from_str(&reply).expect("replies are JSON");
+
+                // Named, so that structs are maps as in JSON.
+                Message::Binary(rmp_serde::to_vec_named(&value).expect("JSON values always serialize to MessagePack"))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_pack_round_trips() {
+        let command = serde_json::json!({ "Drive": { "linear": 50, "angular": -20 }, "seq": 7 });
+        let data = rmp_serde::to_vec_named(&command).unwrap();
+
+        assert_eq!(Encoding::of(&data), Encoding::MessagePack);
+        assert_eq!(serde_json::from_str::<Value>(&Encoding::MessagePack.decode(data).unwrap()).unwrap(), command);
+
+        match Encoding::MessagePack.encode(command.to_string()) {
+            Message::Binary(data) => assert_eq!(rmp_serde::from_slice::<Value>(&data).unwrap(), command),
+            message => panic!("expected a binary message, not {:?}", message),
+        }
+    }
+
+    #[test]
+    fn binary_json_is_still_json() {
+        let data = br#""GetStatus""#.to_vec();
+
+        assert_eq!(Encoding::of(&data), Encoding::Json);
+        assert_eq!(Encoding::Json.decode(data).unwrap(), r#""GetStatus""#);
+        assert_eq!(Encoding::Json.encode("{}".to_string()), Message::Text("{}".to_string()));
+    }
+}
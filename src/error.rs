@@ -0,0 +1,53 @@
+use std::fmt;
+
+use linux_embedded_hal::i2cdev::linux::LinuxI2CError;
+
+/// Errors driving the rover's hardware.
+#[derive(Debug)]
+pub enum RoverError {
+    /// The I2C device could not be opened, e.g. because I2C is not enabled.
+    Device { path: String, source: LinuxI2CError },
+    /// An I2C transaction failed.
+    I2c(LinuxI2CError),
+    /// The PCA9685 driver failed.
+    Pca9685(pwm_pca9685::Error<LinuxI2CError>),
+    /// A board could not be initialized, see `RoverConfig::boards`.
+    Board { id: String, address: u8, source: Box<RoverError> },
+}
+
+impl fmt::Display for RoverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoverError::Device { path, source } => write!(f, "I2C device {} unavailable: {}", path, source),
+            RoverError::I2c(e) | RoverError::Pca9685(pwm_pca9685::Error::I2C(e)) => write!(f, "I2C error: {}", e),
+            RoverError::Pca9685(pwm_pca9685::Error::InvalidInputData) => write!(f, "invalid PCA9685 input data"),
+            RoverError::Board { id, address, source } => {
+                write!(f, "unable to initialize the {:?} PCA9685 at address {:#04x}: {}", id, address, source)
+            },
+        }
+    }
+}
+
+impl std::error::Error for RoverError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RoverError::Device { source: e, .. }
+            | RoverError::I2c(e)
+            | RoverError::Pca9685(pwm_pca9685::Error::I2C(e)) => Some(e),
+            RoverError::Pca9685(pwm_pca9685::Error::InvalidInputData) => None,
+            RoverError::Board { source, .. } => Some(source.as_ref()),
+        }
+    }
+}
+
+impl From<LinuxI2CError> for RoverError {
+    fn from(e: LinuxI2CError) -> Self {
+        RoverError::I2c(e)
+    }
+}
+
+impl From<pwm_pca9685::Error<LinuxI2CError>> for RoverError {
+    fn from(e: pwm_pca9685::Error<LinuxI2CError>) -> Self {
+        RoverError::Pca9685(e)
+    }
+}
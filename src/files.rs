@@ -0,0 +1,265 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+
+/// File served for a directory.
+const INDEX: &str = "index.html";
+
+/// Media type of a file, guessed from its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Validators of a file, for clients to only download it again when it
+/// changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Validators {
+    etag: String,
+    /// Truncated to the second, like HTTP dates.
+    last_modified: SystemTime,
+}
+
+impl Validators {
+    fn new(metadata: &std::fs::Metadata) -> Self {
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", since_epoch.as_nanos(), metadata.len()),
+            last_modified: UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()),
+        }
+    }
+
+    /// Whether the copy the client has, according to the conditional
+    /// headers of its request, is still up to date. `If-None-Match` takes
+    /// precedence over `If-Modified-Since`.
+    fn is_fresh(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+            let if_none_match = if_none_match.to_str().unwrap_or_default();
+
+            return if_none_match.trim() == "*"
+                || if_none_match
+                    .split(',')
+                    // Weak comparison, as GET requests call for.
+                    .any(|etag| etag.trim().trim_start_matches("W/") == self.etag);
+        }
+
+        headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| httpdate::parse_http_date(since.to_str().ok()?).ok())
+            .is_some_and(|since| self.last_modified <= since)
+    }
+
+    fn add_to(&self, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+
+        headers.insert(header::ETAG, HeaderValue::from_str(&self.etag).expect("ETags are ASCII"));
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&httpdate::fmt_http_date(self.last_modified)).expect("HTTP dates are ASCII"),
+        );
+        // Cached, but checked again on every use, for the UI not to get
+        // stale.
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    }
+}
+
+/// Decodes the %XX escapes of a URL path, `None` if they don't make a valid
+/// UTF-8 string.
+pub fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+
+        let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &rest[2..];
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Static files served from a web root directory.
+#[derive(Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(StaticFiles { root: root.as_ref().canonicalize()? })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves `url` to a file within the web root, following `..` and
+    /// symbolic links: anything resolving outside of the root is forbidden.
+    /// Directories resolve to their `index.html`.
+    fn resolve(&self, url: &str) -> Result<PathBuf, StatusCode> {
+        let url = percent_decode(url).ok_or(StatusCode::BAD_REQUEST)?;
+        // Leading slashes would make the path absolute, replacing the root.
+        let mut path = self.canonicalize(self.root.join(url.trim_start_matches('/')))?;
+
+        if path.is_dir() {
+            // index.html may itself be a link, hence checked again.
+            path = self.canonicalize(path.join(INDEX))?;
+        }
+        if !path.is_file() {
+            warn!("static file {:?} is not a file", &path);
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        Ok(path)
+    }
+
+    /// Canonicalizes `path`, which must exist within the web root.
+    fn canonicalize(&self, path: PathBuf) -> Result<PathBuf, StatusCode> {
+        let path = path.canonicalize().map_err(|_| {
+            warn!("static file {:?} does not exist", &path);
+            StatusCode::NOT_FOUND
+        })?;
+
+        if !path.starts_with(&self.root) {
+            warn!("refusing to serve {:?}, outside of {:?}", &path, &self.root);
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        Ok(path)
+    }
+
+    /// Serves the static file at `url`, or `304 Not Modified` if the
+    /// conditional `headers` of the request show that the client already
+    /// has it.
+    pub fn serve(&self, url: &str, headers: &HeaderMap) -> Response<Body> {
+        let path = match self.resolve(url) {
+            Ok(path) => path,
+            Err(code) => return status(code),
+        };
+        // Of the resolved file, so that of index.html for a directory.
+        let validators = match std::fs::metadata(&path) {
+            Ok(metadata) => Validators::new(&metadata),
+            Err(e) => {
+                error!("unable to read the metadata of static file {:?}: {}", &path, e);
+                return status(StatusCode::INTERNAL_SERVER_ERROR);
+            },
+        };
+
+        if validators.is_fresh(headers) {
+            debug!("static file {:?} not modified", &path);
+
+            let mut response = status(StatusCode::NOT_MODIFIED);
+
+            validators.add_to(&mut response);
+            return response;
+        }
+
+        match std::fs::read(&path) {
+            Ok(contents) => {
+                debug!("serving static file {:?}", &path);
+
+                let mut response = Response::builder()
+                    .header(header::CONTENT_TYPE, content_type(&path))
+                    .body(Body::from(contents))
+                    .unwrap();
+
+                validators.add_to(&mut response);
+                response
+            },
+            Err(e) => {
+                error!("unable to read static file {:?}: {}", &path, e);
+                status(StatusCode::INTERNAL_SERVER_ERROR)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditional_requests_match_the_validators() {
+        let validators = Validators {
+            etag: "\"1-2\"".to_string(),
+            last_modified: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        };
+        let request = |name, value| {
+            let mut headers = HeaderMap::new();
+
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+
+        assert!(!validators.is_fresh(&HeaderMap::new()));
+        assert!(validators.is_fresh(&request(header::IF_NONE_MATCH, "\"0-0\", W/\"1-2\"")));
+        assert!(!validators.is_fresh(&request(header::IF_NONE_MATCH, "\"1-3\"")));
+        assert!(validators.is_fresh(&request(header::IF_MODIFIED_SINCE, "Sun, 13 Sep 2020 12:26:40 GMT")));
+        assert!(!validators.is_fresh(&request(header::IF_MODIFIED_SINCE, "Sun, 13 Sep 2020 12:26:39 GMT")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_resolve_within_the_root() {
+        let dir = std::env::temp_dir().join(format!("rover-files-{}", std::process::id()));
+        let root = dir.join("root");
+
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::fs::write(root.join("docs").join(INDEX), "docs").unwrap();
+        std::fs::write(root.join("app.js"), "app").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("link.txt")).unwrap();
+
+        let files = StaticFiles::new(&root).unwrap();
+        let root = files.root().to_path_buf();
+
+        assert_eq!(files.resolve("/app.js"), Ok(root.join("app.js")));
+        assert_eq!(files.resolve("/docs"), Ok(root.join("docs").join(INDEX)));
+        assert_eq!(files.resolve("/../secret.txt"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(files.resolve("/%2e%2e/secret.txt"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(files.resolve("/docs/%2E%2E/%2e%2e/secret.txt"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(files.resolve("/link.txt"), Err(StatusCode::FORBIDDEN));
+        // Outside of the root whether it exists or not.
+        assert!(files.resolve("/../../etc/passwd").is_err());
+        assert_eq!(files.resolve("/missing.js"), Err(StatusCode::NOT_FOUND));
+        // A directory without index.html.
+        assert_eq!(files.resolve("/"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(files.resolve("/%zz"), Err(StatusCode::BAD_REQUEST));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
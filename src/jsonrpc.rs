@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{RoverCommand, RoverResponse};
+
+/// WebSocket subprotocol selecting the JSON-RPC 2.0 framing.
+pub const SUBPROTOCOL: &str = "jsonrpc";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Errors reported by the rover itself, such as an exceeded quota.
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    id: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorObject>,
+    id: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<'a, T> {
+    jsonrpc: &'static str,
+    method: &'a str,
+    params: T,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorObject {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl Response {
+    fn result(id: Value, result: Value) -> Self {
+        Response { jsonrpc: "2.0", result: Some(result), error: None, id }
+    }
+
+    fn error(id: Value, code: i64, message: String, data: Option<Value>) -> Self {
+        Response {
+            jsonrpc: "2.0",
+            result: None,
+            error: Some(ErrorObject { code, message, data }),
+            id,
+        }
+    }
+}
+
+/// Maps a snake_case method name to the matching `RoverCommand` variant,
+/// e.g. "motor_run" to "MotorRun".
+fn variant_name(method: &str) -> String {
+    method
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn parse_command(method: &str, params: Value) -> Result<RoverCommand, (i64, String)> {
+    let variant = variant_name(method);
+    let unknown_method = format!("unknown variant `{}`", variant);
+    // Use the externally tagged representation RoverCommand is parsed from.
+    let command = match params {
+        Value::Null => Value::String(variant),
+        params => Value::Object(std::iter::once((variant, params)).collect()),
+    };
+
+    serde_json::from_value(command).map_err(|e| {
+        let message = e.to_string();
+
+        if message.starts_with(&unknown_method) {
+            (METHOD_NOT_FOUND, format!("method not found: {}", method))
+        } else {
+            (INVALID_PARAMS, message)
+        }
+    })
+}
+
+/// Handles a JSON-RPC request, applying the command it maps to with
+/// `apply`. `rejected` is called with the reason of any error.
+///
+/// Returns the serialized response, or `None` for notifications.
+pub fn handle(
+    text: &str,
+    apply: impl FnOnce(RoverCommand) -> Option<RoverResponse>,
+    rejected: impl FnOnce(&str),
+) -> Option<String> {
+    let response = match serde_json::from_str::<Value>(text) {
+        Err(e) => Some(Response::error(Value::Null, PARSE_ERROR, e.to_string(), None)),
+        Ok(request) => match serde_json::from_value::<Request>(request) {
+            Err(e) => Some(Response::error(Value::Null, INVALID_REQUEST, e.to_string(), None)),
+            Ok(request) if request.jsonrpc != "2.0" => Some(Response::error(
+                request.id.unwrap_or(Value::Null),
+                INVALID_REQUEST,
+                "unsupported JSON-RPC version".to_string(),
+                None,
+            )),
+            Ok(request) => {
+                let result = parse_command(&request.method, request.params)
+                    .map(apply);
+
+                // Notifications don't get a response, even on errors.
+                request.id.map(|id| match result {
+                    Err((code, message)) => Response::error(id, code, message, None),
+                    Ok(Some(RoverResponse::Error { code, message })) => {
+                        Response::error(id, SERVER_ERROR, message, Some(Value::from(code)))
+                    },
+                    Ok(Some(response)) => Response::result(
+                        id,
+                        serde_json::to_value(response).expect("responses always serialize to JSON"),
+                    ),
+                    Ok(None) => Response::result(id, Value::Null),
+                })
+            },
+        },
+    };
+
+    if let Some(error) = response.as_ref().and_then(|response| response.error.as_ref()) {
+        rejected(&error.message);
+    }
+
+    response.map(|response| {
+        serde_json::to_string(&response).expect("responses always serialize to JSON")
+    })
+}
+
+/// Serializes a notification sent by the rover, such as telemetry.
+pub fn notification(method: &str, params: impl Serialize) -> String {
+    serde_json::to_string(&Notification { jsonrpc: "2.0", method, params })
+        .expect("notifications always serialize to JSON")
+}
+
+/// Serializes the response to a message that isn't even JSON text.
+pub fn parse_error(message: String) -> String {
+    serde_json::to_string(&Response::error(Value::Null, PARSE_ERROR, message, None))
+        .expect("responses always serialize to JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Handles `text`, the command it maps to responding `response`, and
+    /// returns the parsed reply.
+    fn reply(text: &str, response: Option<RoverResponse>) -> Value {
+        let reply = handle(text, |_| response, |_| {}).expect("requests get a response");
+
+        serde_json::from_str(&reply).unwrap()
+    }
+
+    #[test]
+    fn methods_map_to_commands() {
+        assert_eq!(variant_name("motor_run"), "MotorRun");
+        assert_eq!(variant_name("pause"), "Pause");
+        assert!(matches!(
+            parse_command("drive", serde_json::json!({ "linear": 50, "angular": -20 })),
+            Ok(RoverCommand::Drive { linear: 50, angular: -20 }),
+        ));
+        assert!(matches!(parse_command("pause", Value::Null), Ok(RoverCommand::Pause)));
+    }
+
+    #[test]
+    fn errors_have_the_standard_codes() {
+        let code = |text| reply(text, None)["error"]["code"].clone();
+
+        assert_eq!(code("{"), PARSE_ERROR);
+        assert_eq!(code(r#"{"jsonrpc":"2.0","method":"fly","id":1}"#), METHOD_NOT_FOUND);
+        assert_eq!(code(r#"{"jsonrpc":"2.0","method":"drive","params":{"linear":"fast"},"id":1}"#), INVALID_PARAMS);
+        assert_eq!(code(r#"{"jsonrpc":"1.0","method":"pause","id":1}"#), INVALID_REQUEST);
+
+        let error = RoverResponse::Error { code: "PAUSED", message: "paused".to_string() };
+        let reply = reply(r#"{"jsonrpc":"2.0","method":"pause","id":1}"#, Some(error));
+
+        assert_eq!(reply["error"]["code"], SERVER_ERROR);
+        assert_eq!(reply["error"]["data"], "PAUSED");
+    }
+
+    #[test]
+    fn responses_echo_the_id() {
+        for id in [serde_json::json!(7), serde_json::json!("abc")] {
+            let request = serde_json::json!({ "jsonrpc": "2.0", "method": "pause", "id": id }).to_string();
+
+            assert_eq!(reply(&request, None), serde_json::json!({ "jsonrpc": "2.0", "result": null, "id": id }));
+        }
+
+        // Not even parsed, so the id can't be known.
+        assert_eq!(reply("{", None)["id"], Value::Null);
+        // Notifications get no response.
+        assert_eq!(handle(r#"{"jsonrpc":"2.0","method":"pause"}"#, |_| None, |_| {}), None);
+    }
+}
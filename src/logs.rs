@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, Log, Metadata, Record};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Maximum number of log records kept in memory.
+pub const CAPACITY: usize = 256;
+
+static RECORDS: Mutex<VecDeque<LogRecord>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LogRecord {
+    /// Milliseconds since the UNIX epoch.
+    timestamp: u64,
+    level: Level,
+    target: String,
+    message: String,
+}
+
+/// Forwards records to the pretty_env_logger logger, keeping a copy of the
+/// most recent ones in memory.
+struct BufferedLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for BufferedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        // Only buffer what the configured log level lets through.
+        if !self.inner.matches(record) {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        push(&mut RECORDS.lock().unwrap(), LogRecord {
+            timestamp,
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        });
+
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Appends `record` to `records`, dropping the oldest one beyond
+/// `CAPACITY`.
+fn push(records: &mut VecDeque<LogRecord>, record: LogRecord) {
+    if records.len() == CAPACITY {
+        records.pop_front();
+    }
+    records.push_back(record);
+}
+
+/// Same as `pretty_env_logger::init_custom_env()`, but also records the
+/// most recent log lines so they can be retrieved with `recent()`.
+pub fn init_custom_env(environment_variable_name: &str) {
+    let mut builder = pretty_env_logger::formatted_builder();
+
+    if let Ok(filters) = std::env::var(environment_variable_name) {
+        builder.parse_filters(&filters);
+    }
+
+    let inner = builder.build();
+    let max_level = inner.filter();
+
+    log::set_boxed_logger(Box::new(BufferedLogger { inner }))
+        .expect("failed to install the logger");
+    log::set_max_level(max_level);
+}
+
+/// Returns (up to) the `count` most recent records at `min_level` or more
+/// severe, oldest first.
+pub fn recent(count: usize, min_level: Level) -> Vec<LogRecord> {
+    recent_in(&RECORDS.lock().unwrap(), count, min_level)
+}
+
+fn recent_in(records: &VecDeque<LogRecord>, count: usize, min_level: Level) -> Vec<LogRecord> {
+    let mut recent = records
+        .iter()
+        .rev()
+        .filter(|record| record.level <= min_level)
+        .take(count)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    recent.reverse();
+
+    recent
+}
+
+/// Number of records currently kept in memory.
+pub fn len() -> usize {
+    RECORDS.lock().unwrap().len()
+}
+
+/// Keys whose values are never logged, e.g. an authentication token.
+const SECRET_KEYS: [&str; 3] = ["token", "password", "secret"];
+
+/// Replaces the values of the `SECRET_KEYS` in `value`, recursively.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                let key = key.to_lowercase();
+
+                if SECRET_KEYS.iter().any(|secret| key.contains(secret)) {
+                    *value = Value::from("<redacted>");
+                } else {
+                    redact(value);
+                }
+            }
+        },
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {},
+    }
+}
+
+/// Returns a loggable excerpt of a received message: secrets redacted, and
+/// truncated to `max_len` characters.
+pub fn excerpt(text: &str, max_len: usize) -> String {
+    let text = match serde_json::from_str::<Value>(text) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value.to_string()
+        },
+        // Can't tell where the secret is in a malformed message.
+        Err(_) if SECRET_KEYS.iter().any(|secret| text.to_lowercase().contains(secret)) => {
+            return "<malformed message with a secret>".to_string();
+        },
+        Err(_) => text.to_string(),
+    };
+
+    match text.char_indices().nth(max_len) {
+        Some((end, _)) => format!("{}... ({} bytes)", &text[..end], text.len()),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(level: Level, message: &str) -> LogRecord {
+        LogRecord { timestamp: 0, level, target: "rover".to_string(), message: message.to_string() }
+    }
+
+    fn messages(records: &[LogRecord]) -> Vec<&str> {
+        records.iter().map(|record| record.message.as_str()).collect()
+    }
+
+    #[test]
+    fn the_oldest_records_are_dropped() {
+        let mut records = VecDeque::new();
+
+        for i in 0..CAPACITY + 2 {
+            push(&mut records, record(Level::Info, &i.to_string()));
+        }
+
+        assert_eq!(records.len(), CAPACITY);
+        assert_eq!(records.front().unwrap().message, "2");
+        assert_eq!(messages(&recent_in(&records, 2, Level::Info)), ["256", "257"]);
+    }
+
+    #[test]
+    fn recent_records_are_filtered_by_level() {
+        let mut records = VecDeque::new();
+
+        for (level, message) in [
+            (Level::Error, "error"),
+            (Level::Debug, "debug"),
+            (Level::Warn, "warn"),
+            (Level::Info, "info"),
+        ] {
+            push(&mut records, record(level, message));
+        }
+
+        assert_eq!(messages(&recent_in(&records, 10, Level::Warn)), ["error", "warn"]);
+        assert_eq!(messages(&recent_in(&records, 2, Level::Trace)), ["warn", "info"]);
+        assert_eq!(messages(&recent_in(&records, 10, Level::Error)), ["error"]);
+    }
+}
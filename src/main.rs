@@ -1,247 +1,3298 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use pretty_env_logger;
 #[macro_use]
 extern crate log;
-use hyper::{header, upgrade, StatusCode, Body, Request, Response, Server, server::conn::AddrStream};
+use hyper::{header, upgrade, Method, StatusCode, Body, Request, Response, Server, server::conn::AddrStream};
+use hyper::body::HttpBody;
 use hyper::service::{make_service_fn, service_fn};
 use tokio_tungstenite::WebSocketStream;
-use futures::{future};
-use futures_util::{TryStreamExt, StreamExt};
+use futures_util::{SinkExt, TryStreamExt, StreamExt};
+use tokio::sync::broadcast;
 use tungstenite::{handshake, error::Error};
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
 use serde::{Deserialize, Serialize};
 
+mod auxiliary;
+mod axis;
+mod backend;
+mod config;
+mod control;
+mod cors;
+mod encoding;
+mod error;
+mod files;
+mod jsonrpc;
+mod logs;
+#[cfg(feature = "mdns")]
+mod mdns;
+mod metrics;
+mod motors;
+mod output;
+mod pattern;
+mod quota;
+mod ratelimit;
+mod recording;
+mod resources;
+mod rgb;
 mod rover;
+mod schedule;
+mod servo;
+mod shutdown;
+mod tagging;
+mod telemetry;
+#[cfg(feature = "tls")]
+mod tls;
+mod tunables;
 
-use rover::{Rover, DCMotorDirection};
+use logs::LogRecord;
+use axis::Inversion;
+use config::{RoverConfig, TRIM_RANGE};
+use control::Control;
+use cors::Cors;
+use encoding::Encoding;
+use error::RoverError;
+use files::StaticFiles;
+use metrics::{Metrics, Snapshot};
+use motors::Motors;
+use quota::Quotas;
+use pattern::{PatternKind, PatternState};
+use ratelimit::{Policy, RateLimiter, Throttled};
+use recording::Recorder;
+use resources::ResourceStats;
+use rover::{ChannelReading, DCMotor, DCMotorDirection, I2cBenchmark, MotorState, Rover, Side, TurnDirection, MAX_SPEED, MOTOR_OUTPUTS};
+use schedule::Scheduler;
+use shutdown::{Refused, Shutdown};
+use tagging::Tagging;
+use telemetry::{Health, ServerInfo, SpeedLimit, Status, Telemetry};
+use tunables::{DriveParams, Tunables, TunablesUpdate};
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 enum RoverMotorId {
+    /// All the motors on the left side.
     Left,
+    /// All the motors on the right side.
     Right,
+    FrontLeft,
+    FrontRight,
+    /// Only with four motors, see `Layout`.
+    RearLeft,
+    RearRight,
+}
+
+impl RoverMotorId {
+    /// The motors designated by this id, none if the rover has no such
+    /// motor.
+    fn motors(self, rover: &mut Rover) -> Vec<&mut DCMotor> {
+        match self {
+            RoverMotorId::Left => rover.side_mut(Side::Left),
+            RoverMotorId::Right => rover.side_mut(Side::Right),
+            RoverMotorId::FrontLeft => vec![&mut rover.left_motor],
+            RoverMotorId::FrontRight => vec![&mut rover.right_motor],
+            RoverMotorId::RearLeft => rover.rear_left_motor.iter_mut().collect(),
+            RoverMotorId::RearRight => rover.rear_right_motor.iter_mut().collect(),
+        }
+    }
+
+    /// The motor of the `id` output, if it is one, see `MOTOR_OUTPUTS`.
+    fn of_output(id: &str) -> Option<Self> {
+        let motors = [RoverMotorId::FrontLeft, RoverMotorId::FrontRight, RoverMotorId::RearLeft, RoverMotorId::RearRight];
+
+        MOTOR_OUTPUTS.iter().zip(motors.iter()).find(|(output, _)| **output == id).map(|(_, motor)| *motor)
+    }
+
+    fn side(self) -> Side {
+        match self {
+            RoverMotorId::Left | RoverMotorId::FrontLeft | RoverMotorId::RearLeft => Side::Left,
+            RoverMotorId::Right | RoverMotorId::FrontRight | RoverMotorId::RearRight => Side::Right,
+        }
+    }
+}
+
+/// Mixes linear and angular speeds into the (left, right) signed speeds of
+/// a differential drive, each clamped to -MAX_SPEED..=MAX_SPEED.
+fn differential_drive(linear: i16, angular: i16) -> (i16, i16) {
+    let linear = i32::from(linear);
+    let angular = i32::from(angular);
+    let max = i32::from(MAX_SPEED);
+    let clamp = |speed: i32| speed.clamp(-max, max) as i16;
+
+    (clamp(linear + angular), clamp(linear - angular))
+}
+
+/// Maximum number of writes of an I2C benchmark, which blocks the server
+/// while it runs.
+const MAX_BENCHMARK_WRITES: u32 = 1000;
+
+/// Speed of the wiggle test, low enough not to move the rover much.
+const WIGGLE_SPEED: u16 = 30;
+/// How long the motor turns in each direction during the wiggle test.
+const WIGGLE_STEP: Duration = Duration::from_millis(250);
+/// Number of forward-backward cycles of the wiggle test.
+const WIGGLE_CYCLES: usize = 3;
+
+/// Longest `DriveFor`, during which the rover drives without its client.
+const MAX_DRIVE_FOR_MS: u32 = 10_000;
+/// Most steps of a `Sequence`.
+const MAX_SEQUENCE_STEPS: usize = 50;
+/// Longest `Sequence`, adding up the duration of all its steps.
+const MAX_SEQUENCE_MS: u32 = 60_000;
+/// How long the shutdown waits for the WebSocket connections to close.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Window of the per-connection rate limit, see `RateLimiter`.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+/// Largest `POST /config` body.
+const MAX_CONFIG_BODY: usize = 4 * 1024;
+/// Largest `POST /command` body, plenty for the longest sequence.
+const MAX_COMMAND_BODY: usize = 64 * 1024;
+/// How long `GET /api/poll` waits for telemetry by default, under the
+/// idle timeout of most proxies, and at most.
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A step of a `Sequence`: drive like `Drive` for `duration_ms`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Step {
+    drive: StepDrive,
+    duration_ms: u32,
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct StepDrive {
+    linear: i16,
+    angular: i16,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 enum RoverCommand {
+    /// Run a motor at `speed`, a percentage of full power (0 to 100).
     MotorRun { motor: RoverMotorId, direction: DCMotorDirection, speed: u16 },
-    MotorStop { motor: RoverMotorId },
+    /// Stop a motor, letting it coast unless `brake` is set, see
+    /// `DCMotor::brake()`.
+    MotorStop {
+        motor: RoverMotorId,
+        #[serde(default)]
+        brake: bool,
+    },
+    /// Same as `MotorRun`, the sign of `speed` (-100 to 100) giving the
+    /// direction: positive is forward, negative backward, and zero stops the
+    /// motor.
+    MotorRunSigned { motor: RoverMotorId, speed: i16 },
+    /// Drive both motors at once: `linear` (-100 to 100) moves forward or
+    /// backward, `angular` (-100 to 100) turns right when positive, left
+    /// when negative.
+    Drive { linear: i16, angular: i16 },
+    /// Same as `Drive`, then stop after `duration_ms` (up to 10s) unless
+    /// another command took over in the meantime.
+    DriveFor { linear: i16, angular: i16, duration_ms: u32 },
+    /// Same as `Drive`, from the position of a gamepad stick: `throttle`
+    /// (-1.0 to 1.0) moves forward when positive, `steer` (-1.0 to 1.0)
+    /// turns right when positive. See `RoverConfig::axis_deadzone` and
+    /// `RoverConfig::axis_expo`.
+    Axis { throttle: f32, steer: f32 },
+    /// Spin in place: both motors run at `speed` (0 to 100) in opposite
+    /// directions.
+    Spin { direction: TurnDirection, speed: u16 },
+    GetLogs { count: usize, min_level: log::Level },
+    /// Stop the motors and put the other outputs in their neutral position,
+    /// see `Rover::neutral()`.
+    Neutral,
+    /// Stop the motors, remembering what they were doing.
+    Pause,
+    /// Restore what the motors were doing before `Pause`.
+    Resume,
+    /// Briefly drive a single motor back and forth to identify which wheel
+    /// it is. Any other motor command cancels it.
+    WiggleMotor { motor: RoverMotorId },
+    /// Read back the on/off counts programmed on every channel.
+    ReadChannels,
+    /// Measure the I2C bus throughput with `writes` dummy channel writes
+    /// (1 to 1000). The motors must be stopped.
+    BenchmarkI2c { writes: u32 },
+    /// Report memory usage and the size of the bounded buffers.
+    GetResourceStats,
+    /// Report the highest speed each motor can currently be driven at and
+    /// what limits it, see `SpeedLimit`. The limited motors are also in the
+    /// telemetry.
+    GetSpeedLimit,
+    /// Run `command` after `delay_ms`, replying with the id it can be
+    /// cancelled with. Scheduled commands are cancelled on disconnect.
+    Schedule { delay_ms: u64, command: Box<RoverCommand> },
+    CancelScheduled { id: u64 },
+    /// Take control of the rover from the current controller, see
+    /// `Control`.
+    RequestControl,
+    /// Change the motors' trims (-25 to 25), see `RoverConfig::left_trim`.
+    SetTrim { left: i8, right: i8 },
+    /// Cap the speed of the motors to `speed` (1 to 100) whatever the
+    /// other commands ask, see `RoverConfig::max_speed`.
+    SetMaxSpeed { speed: u16 },
+    /// Change how the rover accelerates all at once, replying with the
+    /// resulting `DriveParams`: the maximum speed, the speed change of the
+    /// motors every 20ms when speeding up (`accel`) and slowing down
+    /// (`decel`), 0 to apply it right away, and the `expo` of `Axis`. The
+    /// ones left out stay as they are, none changing if one is invalid.
+    SetDriveParams {
+        #[serde(default)]
+        max_speed: Option<u16>,
+        #[serde(default)]
+        accel: Option<u16>,
+        #[serde(default)]
+        decel: Option<u16>,
+        #[serde(default)]
+        expo: Option<f32>,
+    },
+    /// Flip forward and backward (`forward`) and/or left and right
+    /// (`steering`) of the operator's `Drive`, `DriveFor`, `Axis` and `Spin`
+    /// commands from now on, e.g. when driving the rover facing it. Sequences
+    /// and test patterns aren't flipped. Reported in the status, and set at
+    /// startup by `RoverConfig::invert_forward` and
+    /// `RoverConfig::invert_steering`.
+    SetSteeringInvert { forward: bool, steering: bool },
+    /// Report the motors' speed and direction, the watchdog timeout,
+    /// whether the rover is emergency stopped and the uptime.
+    GetStatus,
+    /// Turn the steering servo to `angle` degrees, clamped to -90..=90:
+    /// negative is left, positive right. Only for rovers with a steering
+    /// servo, see `ServoConfig`.
+    SetSteering { angle: i8 },
+    /// Set the auxiliary output `name` to `level`, from 0 (off) to 100
+    /// (full brightness), see `RoverConfig::aux`.
+    SetAux { name: String, level: u16 },
+    /// Set the RGB LED `id` to a color, each component from 0 (off) to 255
+    /// (full brightness), see `RoverConfig::rgb_leds`. Stopping the rover
+    /// keeps it, only the status LED changing with the state of the rover.
+    SetColor { id: String, r: u8, g: u8, b: u8 },
+    /// Set the output `id` to `value`, normalized whatever the output: a
+    /// motor (`left`, `right`, `rear_left` or `rear_right`, from -1.0 for
+    /// full speed backward to 1.0 forward), the steering servo (`steering`,
+    /// from full left to full right) or an auxiliary output by name (from
+    /// 0.0 to 1.0), see `PwmOutput`. Motors are driven like with
+    /// `MotorRunSigned`.
+    SetOutput { id: String, value: f32 },
+    /// Record the commands applied from now on as `name` (letters, digits,
+    /// '-' and '_'), see `Recorder`.
+    StartRecording { name: String },
+    /// Stop recording and save the recording.
+    StopRecording,
+    /// Re-apply the commands of the `name` recording with their original
+    /// timing, then stop. Any other motor command aborts the replay.
+    Replay { name: String },
+    /// Drive through `steps` back to back (up to 50 steps and 60s in
+    /// total), then stop. Any other motor command cancels the sequence.
+    Sequence { steps: Vec<Step> },
+    /// Drive a predefined pattern as a `Sequence`, its size and speed coming
+    /// from the configuration (see `RoverConfig::pattern_speed`). Any other
+    /// motor command cancels it, how it went being reported in the telemetry
+    /// and the status.
+    TestPattern { kind: PatternKind },
+}
+
+impl RoverCommand {
+    /// Checks that the speeds are within range, so that an invalid command
+    /// is rejected before it gets anywhere near the motors.
+    fn validate(&self) -> Result<(), String> {
+        let check = |name: &str, speed: i32| {
+            let max = i32::from(MAX_SPEED);
+
+            if speed.abs() <= max {
+                Ok(())
+            } else {
+                Err(format!("{} must be between -{} and {}, not {}", name, max, max, speed))
+            }
+        };
+
+        match self {
+            RoverCommand::MotorRun { speed, .. } | RoverCommand::Spin { speed, .. } if *speed > MAX_SPEED => {
+                Err(format!("speed must be between 0 and {}, not {}", MAX_SPEED, speed))
+            },
+            RoverCommand::MotorRunSigned { speed, .. } => check("speed", i32::from(*speed)),
+            RoverCommand::Drive { linear, angular } => {
+                check("linear", i32::from(*linear))?;
+                check("angular", i32::from(*angular))
+            },
+            RoverCommand::DriveFor { duration_ms, .. } if *duration_ms > MAX_DRIVE_FOR_MS => {
+                Err(format!("duration_ms must be at most {}, not {}", MAX_DRIVE_FOR_MS, duration_ms))
+            },
+            RoverCommand::DriveFor { linear, angular, .. } => {
+                check("linear", i32::from(*linear))?;
+                check("angular", i32::from(*angular))
+            },
+            RoverCommand::Axis { throttle, steer } => {
+                for (name, axis) in [("throttle", throttle), ("steer", steer)].iter() {
+                    if !(-1.0..=1.0).contains(*axis) {
+                        return Err(format!("{} must be between -1.0 and 1.0, not {}", name, axis));
+                    }
+                }
+
+                Ok(())
+            },
+            RoverCommand::Sequence { steps } => {
+                if steps.len() > MAX_SEQUENCE_STEPS {
+                    return Err(format!("a sequence has at most {} steps, not {}", MAX_SEQUENCE_STEPS, steps.len()));
+                }
+
+                let duration_ms: u64 = steps.iter().map(|step| u64::from(step.duration_ms)).sum();
+
+                if duration_ms > u64::from(MAX_SEQUENCE_MS) {
+                    return Err(format!("a sequence lasts at most {}ms, not {}", MAX_SEQUENCE_MS, duration_ms));
+                }
+                for step in steps {
+                    check("linear", i32::from(step.drive.linear))?;
+                    check("angular", i32::from(step.drive.angular))?;
+                }
+
+                Ok(())
+            },
+            RoverCommand::SetTrim { left, right } => {
+                for (name, trim) in [("left", left), ("right", right)].iter() {
+                    if !TRIM_RANGE.contains(trim) {
+                        return Err(format!(
+                            "{} must be between {} and {}, not {}",
+                            name,
+                            TRIM_RANGE.start(),
+                            TRIM_RANGE.end(),
+                            trim,
+                        ));
+                    }
+                }
+
+                Ok(())
+            },
+            RoverCommand::SetMaxSpeed { speed } if !(1..=MAX_SPEED).contains(speed) => {
+                Err(format!("speed must be between 1 and {}, not {}", MAX_SPEED, speed))
+            },
+            RoverCommand::SetAux { level, .. } if *level > auxiliary::MAX_LEVEL => {
+                Err(format!("level must be between 0 and {}, not {}", auxiliary::MAX_LEVEL, level))
+            },
+            RoverCommand::SetOutput { value, .. } if !(-1.0..=1.0).contains(value) => {
+                Err(format!("value must be between -1.0 and 1.0, not {}", value))
+            },
+            RoverCommand::Schedule { command, .. } => match **command {
+                RoverCommand::RequestControl
+                | RoverCommand::StartRecording { .. }
+                | RoverCommand::StopRecording
+                | RoverCommand::Replay { .. } => Err(format!("{:?} can't be scheduled", command)),
+                ref command => command.validate(),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether only the controller can send the command, which drives the
+    /// motors or records them, as opposed to only querying the rover.
+    fn controls_motors(&self) -> bool {
+        match self {
+            RoverCommand::GetLogs { .. }
+            | RoverCommand::ReadChannels
+            | RoverCommand::GetResourceStats
+            | RoverCommand::GetSpeedLimit
+            | RoverCommand::GetStatus
+            | RoverCommand::CancelScheduled { .. }
+            | RoverCommand::RequestControl => false,
+            RoverCommand::Schedule { command, .. } => command.controls_motors(),
+            _ => true,
+        }
+    }
+
+    /// Whether the command stops the motors, which the motor task applies
+    /// ahead of the others, see `Motors`.
+    fn stops(&self) -> bool {
+        match self {
+            RoverCommand::MotorStop { .. }
+            | RoverCommand::MotorRun { speed: 0, .. }
+            | RoverCommand::MotorRunSigned { speed: 0, .. }
+            | RoverCommand::Drive { linear: 0, angular: 0 }
+            | RoverCommand::Spin { speed: 0, .. }
+            | RoverCommand::Neutral
+            | RoverCommand::Pause => true,
+            RoverCommand::Axis { throttle, steer } => *throttle == 0f32 && *steer == 0f32,
+            RoverCommand::SetOutput { id, value } => {
+                RoverMotorId::of_output(id).is_some() && output::scale(*value, i32::from(MAX_SPEED)) == 0
+            },
+            _ => false,
+        }
+    }
+
+    /// Whether the command sets the speed of the motors, and is superseded
+    /// by a stop sent after it.
+    fn drives(&self) -> bool {
+        match self {
+            RoverCommand::MotorRun { .. }
+            | RoverCommand::MotorRunSigned { .. }
+            | RoverCommand::Drive { .. }
+            | RoverCommand::DriveFor { .. }
+            | RoverCommand::Axis { .. }
+            | RoverCommand::Spin { .. }
+            | RoverCommand::WiggleMotor { .. }
+            | RoverCommand::Sequence { .. }
+            | RoverCommand::TestPattern { .. } => !self.stops(),
+            RoverCommand::SetOutput { id, .. } => RoverMotorId::of_output(id).is_some() && !self.stops(),
+            _ => false,
+        }
+    }
+
+    /// What happens to the command when rate limited. Commands setting
+    /// something are coalesced with the newer commands setting the same
+    /// thing, the other ones changing the state of the rover are queued and
+    /// only the queries are dropped.
+    fn rate_limit_policy(&self) -> Policy<CoalesceKey> {
+        match self {
+            RoverCommand::MotorRun { motor, .. }
+            | RoverCommand::MotorStop { motor, .. }
+            | RoverCommand::MotorRunSigned { motor, .. } => Policy::Coalesce(CoalesceKey::Motor(*motor)),
+            RoverCommand::Drive { .. }
+            | RoverCommand::DriveFor { .. }
+            | RoverCommand::Axis { .. }
+            | RoverCommand::Spin { .. }
+            | RoverCommand::Sequence { .. }
+            | RoverCommand::TestPattern { .. } => Policy::Coalesce(CoalesceKey::Motors),
+            RoverCommand::SetSteering { .. } => Policy::Coalesce(CoalesceKey::Steering),
+            RoverCommand::SetAux { name, .. } => Policy::Coalesce(CoalesceKey::Aux(name.clone())),
+            RoverCommand::SetOutput { id, .. } => Policy::Coalesce(CoalesceKey::Output(id.clone())),
+            RoverCommand::SetColor { id, .. } => Policy::Coalesce(CoalesceKey::Color(id.clone())),
+            RoverCommand::GetLogs { .. }
+            | RoverCommand::ReadChannels
+            | RoverCommand::GetResourceStats
+            | RoverCommand::GetSpeedLimit
+            | RoverCommand::GetStatus => Policy::Drop,
+            _ => Policy::Queue,
+        }
+    }
+
+    /// Name of the command, as it is tagged in JSON.
+    fn name(&self) -> &'static str {
+        match self {
+            RoverCommand::MotorRun { .. } => "MotorRun",
+            RoverCommand::MotorStop { .. } => "MotorStop",
+            RoverCommand::MotorRunSigned { .. } => "MotorRunSigned",
+            RoverCommand::Drive { .. } => "Drive",
+            RoverCommand::DriveFor { .. } => "DriveFor",
+            RoverCommand::Axis { .. } => "Axis",
+            RoverCommand::Spin { .. } => "Spin",
+            RoverCommand::GetLogs { .. } => "GetLogs",
+            RoverCommand::Neutral => "Neutral",
+            RoverCommand::Pause => "Pause",
+            RoverCommand::Resume => "Resume",
+            RoverCommand::WiggleMotor { .. } => "WiggleMotor",
+            RoverCommand::ReadChannels => "ReadChannels",
+            RoverCommand::BenchmarkI2c { .. } => "BenchmarkI2c",
+            RoverCommand::GetResourceStats => "GetResourceStats",
+            RoverCommand::GetSpeedLimit => "GetSpeedLimit",
+            RoverCommand::Schedule { .. } => "Schedule",
+            RoverCommand::CancelScheduled { .. } => "CancelScheduled",
+            RoverCommand::RequestControl => "RequestControl",
+            RoverCommand::SetTrim { .. } => "SetTrim",
+            RoverCommand::SetMaxSpeed { .. } => "SetMaxSpeed",
+            RoverCommand::SetDriveParams { .. } => "SetDriveParams",
+            RoverCommand::SetSteeringInvert { .. } => "SetSteeringInvert",
+            RoverCommand::GetStatus => "GetStatus",
+            RoverCommand::SetSteering { .. } => "SetSteering",
+            RoverCommand::SetAux { .. } => "SetAux",
+            RoverCommand::SetOutput { .. } => "SetOutput",
+            RoverCommand::SetColor { .. } => "SetColor",
+            RoverCommand::StartRecording { .. } => "StartRecording",
+            RoverCommand::StopRecording => "StopRecording",
+            RoverCommand::Replay { .. } => "Replay",
+            RoverCommand::Sequence { .. } => "Sequence",
+            RoverCommand::TestPattern { .. } => "TestPattern",
+        }
+    }
+
+    /// Converts alternative forms of a command to their canonical form.
+    fn normalized(self) -> Self {
+        match self {
+            RoverCommand::MotorRunSigned { motor, speed: 0 } => RoverCommand::MotorStop { motor, brake: false },
+            RoverCommand::MotorRunSigned { motor, speed } => {
+                let (speed, direction) = DCMotorDirection::split_signed(speed);
+
+                RoverCommand::MotorRun { motor, direction, speed }
+            },
+            RoverCommand::SetOutput { id, value } => match RoverMotorId::of_output(&id) {
+                Some(motor) => {
+                    let speed = output::scale(value, i32::from(MAX_SPEED)) as i16;
+
+                    RoverCommand::MotorRunSigned { motor, speed }.normalized()
+                },
+                None => RoverCommand::SetOutput { id, value },
+            },
+            command => command,
+        }
+    }
+}
+
+/// See `RoverCommand::rate_limit_policy()`.
+#[derive(Debug, PartialEq, Eq)]
+enum CoalesceKey {
+    /// All the motors at once.
+    Motors,
+    Motor(RoverMotorId),
+    Steering,
+    Aux(String),
+    Output(String),
+    Color(String),
+}
+
+type CommandLimiter = Mutex<RateLimiter<CoalesceKey, RoverCommand>>;
+
+#[derive(Clone, Debug, Serialize)]
+enum RoverResponse {
+    Logs { records: Vec<LogRecord> },
+    Channels { channels: Vec<ChannelReading> },
+    I2cBenchmark(I2cBenchmark),
+    ResourceStats(ResourceStats),
+    Status(Box<Status>),
+    DriveParams(DriveParams),
+    SpeedLimits { motors: BTreeMap<&'static str, SpeedLimit> },
+    /// Pushed periodically, not in response to a command.
+    Telemetry(Telemetry),
+    Scheduled { id: u64 },
+    /// Held back by the rate limiter, only the latest of the coalesced
+    /// commands being applied at the end of the window.
+    Coalesced { window_ms: u64 },
+    /// Held back by the rate limiter until the end of the window.
+    Queued { window_ms: u64 },
+    Error { code: &'static str, message: String },
+}
+
+impl Framing {
+    fn format_telemetry(self, telemetry: Telemetry) -> String {
+        match self {
+            Framing::Plain(tagging) => tagging
+                .format(&RoverResponse::Telemetry(telemetry))
+                .expect("responses always serialize to JSON"),
+            Framing::JsonRpc => jsonrpc::notification("telemetry", telemetry),
+        }
+    }
+
+    /// Formats the error reply to a message that isn't even text.
+    fn format_error(self, message: String) -> String {
+        match self {
+            Framing::Plain(_) => serde_json::to_string(&Ack::error(None, message))
+                .expect("acks always serialize to JSON"),
+            Framing::JsonRpc => jsonrpc::parse_error(message),
+        }
+    }
+}
+
+/// Reply to a command without a response of its own, or to a command that
+/// was rejected.
+#[derive(Debug, Serialize)]
+struct Ack {
+    ok: bool,
+    /// The command as it was parsed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    applied: Option<serde_json::Value>,
+    /// Same codes as `RoverResponse::Error`, absent for parse errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// "coalesced" or "queued" when the command isn't applied yet, see
+    /// `RoverResponse::Coalesced` and `RoverResponse::Queued`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'static str>,
+    /// Sequence number of the command, if the client gave it one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    /// Commands the client can still send in the current quota window,
+    /// absent when quotas are disabled, see `Quotas`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_remaining: Option<u32>,
+}
+
+impl Ack {
+    fn applied(command: serde_json::Value) -> Self {
+        Ack { ok: true, applied: Some(command), code: None, error: None, status: None, seq: None, quota_remaining: None }
+    }
+
+    fn coalesced() -> Self {
+        Ack { ok: true, applied: None, code: None, error: None, status: Some("coalesced"), seq: None, quota_remaining: None }
+    }
+
+    fn queued() -> Self {
+        Ack { ok: true, applied: None, code: None, error: None, status: Some("queued"), seq: None, quota_remaining: None }
+    }
+
+    fn error(code: Option<&'static str>, error: String) -> Self {
+        Ack { ok: false, applied: None, code, error: Some(error), status: None, seq: None, quota_remaining: None }
+    }
+
+    fn with_seq(self, seq: Option<u64>) -> Self {
+        Ack { seq, ..self }
+    }
+
+    fn with_quota_remaining(self, quota_remaining: Option<u32>) -> Self {
+        Ack { quota_remaining, ..self }
+    }
+}
+
+/// How commands and responses are framed on a WebSocket connection.
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    /// Bare `RoverCommand`/`RoverResponse` JSON objects, tagged as given.
+    Plain(Tagging),
+    /// JSON-RPC 2.0 requests and responses, see the jsonrpc module.
+    JsonRpc,
 }
 
 fn handle_message(
     addr: SocketAddr,
     msg: tungstenite::Message,
-    rover: Arc<Mutex<Rover>>,
-) -> Result<(), ()> {
-    if let tungstenite::Message::Close(_) = msg {
-        debug!("received 'close' from {}", addr);
-        return Ok(())
-    }
+    services: &Services,
+    limiter: &CommandLimiter,
+    framing: Framing,
+    rejected_log_len: usize,
+) -> Option<tungstenite::Message> {
+    let received = Instant::now();
+    let (text, encoding) = match msg {
+        tungstenite::Message::Text(text) => (text, Encoding::Json),
+        // MessagePack, or UTF-8 JSON for clients that only send binary, see
+        // Encoding.
+        tungstenite::Message::Binary(data) => {
+            let encoding = Encoding::of(&data);
+
+            match encoding.decode(data) {
+                Ok(text) => (text, encoding),
+                Err(e) => {
+                    services.metrics.command_received();
+                    services.metrics.command_unparsable();
+                    debug!("rejected a binary message from {}: {}", addr, e);
+                    return Some(encoding.encode(framing.format_error(e)));
+                },
+            }
+        },
+        tungstenite::Message::Close(_) => {
+            debug!("received 'close' from {}", addr);
+            return None
+        },
+        // Handled by the connection itself.
+        tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_) => return None,
+    };
+
+    handle_text(addr, &text, received, services, limiter, framing, rejected_log_len).map(|reply| encoding.encode(reply))
+}
+
+/// Handles a command message, decoded into JSON text, and returns the JSON
+/// reply.
+fn handle_text(
+    addr: SocketAddr,
+    text: &str,
+    received: Instant,
+    services: &Services,
+    limiter: &CommandLimiter,
+    framing: Framing,
+    rejected_log_len: usize,
+) -> Option<String> {
+    debug!("received a message from {}: {}", addr, logs::excerpt(text, rejected_log_len));
+    services.metrics.command_received();
+
+    let parsed = Cell::new(false);
+    let apply = |command: RoverCommand| {
+        parsed.set(true);
 
-    debug!(
-        "received a message from {}: {}",
+        let policy = command.rate_limit_policy();
+        let mut limiter = limiter.lock().unwrap();
+
+        match limiter.admit(policy, command) {
+            Ok(command) => {
+                drop(limiter);
+                handle_command(addr, command, received, services)
+            },
+            Err(Throttled::Coalesced) => Some(RoverResponse::Coalesced {
+                window_ms: limiter.window().as_millis() as u64,
+            }),
+            Err(Throttled::Queued) => Some(RoverResponse::Queued {
+                window_ms: limiter.window().as_millis() as u64,
+            }),
+            Err(Throttled::Dropped) => Some(RoverResponse::Error {
+                code: "THROTTLED",
+                message: "too many commands, dropped".to_string(),
+            }),
+        }
+    };
+    let rejected = |reason: &str| debug!(
+        "rejected a message from {} ({}): {}",
         addr,
-        msg.to_text().unwrap()
+        reason,
+        logs::excerpt(text, rejected_log_len)
     );
 
-    let command = serde_json::from_str(msg.to_text().unwrap());
+    match framing {
+        Framing::Plain(tagging) => {
+            // Clients can number their commands to match them with the
+            // replies, which echo the number.
+            let (seq, command) = tagging.parse_sequenced::<RoverCommand>(text);
 
-    match command {
-        Ok(command) => {
             match command {
-                RoverCommand::MotorRun { motor, direction, speed } => {
-                    let mut rover = rover.lock().unwrap();
+                Ok(command) => {
+                    let response = apply(command.clone());
 
-                    match motor {
-                        RoverMotorId::Right => rover.right_motor.set_speed(speed, direction),
-                        RoverMotorId::Left => rover.left_motor.set_speed(speed, direction),
+                    if let Some(RoverResponse::Error { code, .. }) = &response {
+                        rejected(code);
                     }
-                }
-                RoverCommand::MotorStop { motor } => {
-                    let mut rover = rover.lock().unwrap();
 
-                    match motor {
-                        RoverMotorId::Right => rover.right_motor.stop(),
-                        RoverMotorId::Left => rover.left_motor.stop(),
-                    }
-                }
+                    Some(plain_reply(tagging, &command, seq, response, services.quotas.remaining(addr.ip())))
+                },
+                Err(e) => {
+                    error!("unable to parse command: {}", e);
+                    services.metrics.command_unparsable();
+                    rejected(&e.to_string());
+
+                    Some(
+                        serde_json::to_string(&Ack::error(None, e.to_string()).with_seq(seq))
+                            .expect("acks always serialize to JSON")
+                    )
+                },
             }
         },
-        Err(e) => {
-            // ! FIXME: return as future error
-            error!("unable to parse command: {}", e);
-        }
+        Framing::JsonRpc => jsonrpc::handle(text, apply, |reason| {
+            if !parsed.get() {
+                services.metrics.command_unparsable();
+            }
+            rejected(reason);
+        }),
+    }
+}
+
+/// Replies to `command` in the plain framing: its response if it has one,
+/// an `Ack` otherwise, along with the sequence number of the command and
+/// the `quota_remaining` of the client.
+fn plain_reply(
+    tagging: Tagging,
+    command: &RoverCommand,
+    seq: Option<u64>,
+    response: Option<RoverResponse>,
+    quota_remaining: Option<u32>,
+) -> String {
+    let ack = match response {
+        None => Ack::applied(tagging.to_value(command).expect("commands always serialize to JSON")),
+        Some(RoverResponse::Error { code, message }) => Ack::error(Some(code), message),
+        Some(RoverResponse::Coalesced { .. }) => Ack::coalesced(),
+        Some(RoverResponse::Queued { .. }) => Ack::queued(),
+        Some(response) => {
+            let mut response = tagging.to_value(&response).expect("responses always serialize to JSON");
+
+            // Next to the tag, as in the command.
+            if let (Some(seq), Some(response)) = (seq, response.as_object_mut()) {
+                response.insert("seq".to_string(), seq.into());
+            }
+
+            return response.to_string();
+        },
     };
 
-    Ok(())
+    serde_json::to_string(&ack.with_seq(seq).with_quota_remaining(quota_remaining)).expect("acks always serialize to JSON")
 }
 
-async fn handle_request(
-    mut request: Request<Body>,
-    remote_addr: SocketAddr,
-    rover: Arc<Mutex<Rover>>,
-) -> Result<Response<Body>, Infallible> {
-    match (request.uri().path(), request.headers().contains_key(header::UPGRADE)) {
-        //if the request is ws_echo and the request headers contains an Upgrade key
-        ("/websocket", true) => {
-            //assume request is a handshake, so create the handshake response
-            let response = 
-            match handshake::server::create_response_with_body(&request, || Body::empty()) {
-                Ok(response) => {
-                    //in case the handshake response creation succeeds,
-                    //spawn a task to handle the websocket connection
-                    tokio::spawn(async move {
-                        //using the hyper feature of upgrading a connection
-                        match upgrade::on(&mut request).await {
-                            //if successfully upgraded
-                            Ok(upgraded) => {
-                                //create a websocket stream from the upgraded object
-                                let ws_stream = WebSocketStream::from_raw_socket(
-                                    //pass the upgraded object
-                                    //as the base layer stream of the Websocket
-                                    upgraded,
-                                    tokio_tungstenite::tungstenite::protocol::Role::Server,
-                                    None,
-                                ).await;
+/// Applies a command received with `POST /command`. The client controls
+/// the rover for this command only, and only if no connection controls it.
+fn handle_http_command(
+    addr: SocketAddr,
+    command: RoverCommand,
+    received: Instant,
+    services: &Services,
+) -> Option<RoverResponse> {
+    if let RoverCommand::RequestControl = command {
+        return Some(RoverResponse::Error {
+            code: "WEBSOCKET_ONLY",
+            message: "control can only be requested over a WebSocket connection".to_string(),
+        });
+    }
 
-                                info!("new WebSocket connection: {}", remote_addr);
+    services.control.join(addr);
 
-                                //we can split the stream into a sink and a stream
-                                let (_ws_write, ws_read) = ws_stream.split();
-                                let receive = ws_read.try_for_each(|msg| {
-                                    handle_message(remote_addr, msg, rover.clone());
-
-                                    future::ok(())
-                                });
-
-                                match receive.await {
-                                    Ok(_) => {
-                                        rover.lock().unwrap().stop();
-                                    },
-                                    Err(Error::ConnectionClosed) => {
-                                        rover.lock().unwrap().stop();
-                                        info!("connection closed normally")
-                                    },
-                                    Err(e) => {
-                                        rover.lock().unwrap().stop();
-                                        error!("error: {:?}", e)
-                                    },
-                                }
-                            },
-                            Err(e) =>
-                                error!(
-                                    "error when trying to upgrade connection \
-                                    from address {} to websocket connection: \
-                                    {}",
-                                    remote_addr,
-                                    e
-                                ),
-                        }
-                    });
-                    //return the response to the handshake request
-                    response
-                },
-                Err(error) => {
-                    //probably the handshake request is not up to spec for websocket
-                    error!(
-                        "Failed to create websocket response \
-                        to request from address {}: {}",
-                        remote_addr,
-                        error,
-                    );
-                    let mut res = Response::new(Body::from(format!("failed to create websocket: {}", error)));
-                    *res.status_mut() = StatusCode::BAD_REQUEST;
-                    return Ok(res);
-                }
-            };
-        
-            Ok::<_, Infallible>(response)
-        },
-        ("/websocket", false) => {
-            //handle the case where the url is /websocket, but does not have an Upgrade field
-            Ok(Response::new(Body::from(format!(
-                "Getting even warmer, \
-                try connecting to this url \
-                using a websocket client.\n"
-            ))))
-        },
-        (url, false) => {
-            info!("serving URL {}", &url);
+    let response = handle_command(addr, command, received, services);
 
-            let mut path = std::path::PathBuf::new();
-            path.push(r".");
-            path.push(&url[1..]);
+    // Unlike when a WebSocket controller disconnects, the rover isn't
+    // stopped whatever `leave()` says, that would undo the command right
+    // away. The watchdog stops it unless more commands follow.
+    services.control.leave(addr);
 
-            if path.exists() && path.is_file() {
-                let contents = std::fs::read_to_string(&path)
-                    .expect("something went wrong reading the file");
+    response
+}
 
-                debug!("serving static file {:?}", &path);
-                Ok(Response::new(Body::from(contents)))
-            } else {
-                warn!("static file {:?} does not exist", &path);
-                Ok(
-                    Response::builder()
-                        .status(StatusCode::NOT_FOUND)
-                        .body(Body::empty())
-                        .unwrap()
-                )
-            }
+/// HTTP status of the reply to a command, see `RoverResponse::Error`.
+fn command_status(response: &Option<RoverResponse>) -> StatusCode {
+    match response {
+        Some(RoverResponse::Error { code, .. }) => match *code {
+            "OUT_OF_RANGE" | "WEBSOCKET_ONLY" => StatusCode::BAD_REQUEST,
+            "NOT_CONTROLLER" => StatusCode::FORBIDDEN,
+            "NO_SUCH_MOTOR" | "NO_STEERING" | "NO_SUCH_AUX" | "NO_SUCH_OUTPUT" | "NO_SUCH_RECORDING" => StatusCode::NOT_FOUND,
+            "MOTOR_UNAVAILABLE" => StatusCode::SERVICE_UNAVAILABLE,
+            "QUOTA_EXCEEDED" | "THROTTLED" => StatusCode::TOO_MANY_REQUESTS,
+            "I2C_ERROR" => StatusCode::INTERNAL_SERVER_ERROR,
+            "BUSY" | "MOTORS_STOPPED" => StatusCode::SERVICE_UNAVAILABLE,
+            // The rover is paused, emergency stopped...
+            _ => StatusCode::CONFLICT,
         },
-        (_, true) => {
-            //handle any other url with an Upgrade header field
-            Ok(Response::new(Body::from(format!(
-                "Getting warmer, but I'm \
-                only letting you connect \
-                via websocket over on \
-                /websocket, try that url.\n"
-            ))))
-        }
+        _ => StatusCode::OK,
     }
 }
 
-async fn shutdown_signal(rover: Arc<Mutex<Rover>>) {
-    // Wait for the CTRL+C signal
-    tokio::signal::ctrl_c()
-        .await
-        .expect("failed to install CTRL+C signal handler");
+/// Reads the body of `request`, `None` if it is larger than `limit` or
+/// can't be read.
+async fn read_body(request: &mut Request<Body>, limit: usize) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+
+    while let Some(chunk) = request.body_mut().data().await {
+        let chunk = chunk.ok()?;
+
+        if body.len() + chunk.len() > limit {
+            return None;
+        }
+        body.extend_from_slice(&chunk);
+    }
 
-    rover.lock().unwrap().stop();
+    Some(body)
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    pretty_env_logger::init_custom_env("ROVER_LOG");
+/// Applies `command`, received at `received`, on behalf of `addr`.
+///
+/// The latency of the commands applied by the motor task is logged at debug
+/// level with the rover::latency target, e.g. with
+/// ROVER_LOG=rover::latency=debug, in the logfmt format: the time from the
+/// reception of the command to the motor task picking it up (queue_ms),
+/// applying it (apply_ms), and from the reception to its completion
+/// (total_ms).
+fn handle_command(
+    addr: SocketAddr,
+    command: RoverCommand,
+    received: Instant,
+    services: &Services,
+) -> Option<RoverResponse> {
+    let Services { quotas, scheduler, control, recorder, motors, .. } = services;
+
+    match quotas.consume(addr.ip()) {
+        Some(remaining) => trace!("{} has {} commands left in its quota", addr, remaining),
+        None => {
+            warn!("{} exceeded its command quota", addr);
+            return Some(RoverResponse::Error {
+                code: "QUOTA_EXCEEDED",
+                message: format!(
+                    "command quota exceeded, try again within {}s",
+                    quotas.window().as_secs(),
+                ),
+            });
+        }
+    }
 
-    let rover = Arc::new(Mutex::new(Rover::new()));
+    if let Err(message) = command.validate() {
+        services.metrics.command_invalid();
 
-    rover.lock().unwrap().stop();
+        return Some(RoverResponse::Error { code: "OUT_OF_RANGE", message });
+    }
 
-    // hyper server boilerplate code from https://hyper.rs/guides/server/hello-world/
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    if let RoverCommand::RequestControl = command {
+        if let Some(previous) = control.take(addr) {
+            // Start from a stopped rover, without the previous controller's
+            // pending commands.
+            scheduler.cancel_all(previous);
+            recorder.abort_replay();
+            motors.with_rover_blocking(stop_rover);
+        }
 
-    info!("listening on {} for http or websocket connections", addr);
+        return None;
+    }
+    // Only the controller keeps the rover going.
+    let is_controller = control.has_control(addr);
 
-    // A `Service` is needed for every connection, so this
-    // creates one from our `handle_request` function.
-    let make_svc = make_service_fn(|conn: & AddrStream| {
-        let remote_addr = conn.remote_addr();
-        let rover = rover.clone();
+    if !is_controller && command.controls_motors() {
+        return Some(RoverResponse::Error {
+            code: "NOT_CONTROLLER",
+            message: "not controller".to_string(),
+        });
+    }
 
-        async move {
-            // service_fn converts our function into a `Service`
-            Ok::<_, Infallible>(service_fn(move |request: Request<Body>|
-                handle_request(request, remote_addr, rover.clone())
-            ))
+    if command.controls_motors() && !matches!(command, RoverCommand::StartRecording { .. } | RoverCommand::StopRecording) {
+        // Manual commands take over from the replay.
+        recorder.abort_replay();
+    }
+
+    let recording_error = |message| Some(RoverResponse::Error { code: "RECORDING_ERROR", message });
+
+    match command {
+        RoverCommand::StartRecording { name } => recorder.start(name).err().and_then(recording_error),
+        RoverCommand::StopRecording => recorder.stop().err().and_then(recording_error),
+        RoverCommand::Replay { name } => replay(addr, &name, services),
+        command => {
+            let name = command.name();
+            let (response, timing) = motors.execute(addr, command.clone(), is_controller);
+
+            if let Some(timing) = timing {
+                let result = match &response {
+                    Some(RoverResponse::Error { code, .. }) => code,
+                    _ => "ok",
+                };
+
+                debug!(
+                    target: "rover::latency",
+                    "command={} addr={} result={} queue_ms={:.3} apply_ms={:.3} total_ms={:.3}",
+                    name,
+                    addr,
+                    result,
+                    timing.queued.as_secs_f64() * 1000f64,
+                    timing.applied.as_secs_f64() * 1000f64,
+                    received.elapsed().as_secs_f64() * 1000f64,
+                );
+            }
+
+            if command.controls_motors() && !matches!(response, Some(RoverResponse::Error { .. })) {
+                recorder.record(&command);
+            }
+
+            response
+        },
+    }
+}
+
+/// Replays the `name` recording from a spawned task, see `Recorder`.
+fn replay(addr: SocketAddr, name: &str, services: &Services) -> Option<RoverResponse> {
+    let replay = match services.recorder.load(name) {
+        Ok(replay) => replay,
+        Err(message) => return Some(RoverResponse::Error { code: "NO_SUCH_RECORDING", message }),
+    };
+    let motors = services.motors.clone();
+    let start = tokio::time::Instant::now();
+
+    info!("{} replays the {:?} recording ({} commands)", addr, name, replay.commands.len());
+
+    let task = tokio::spawn(async move {
+        let mut commands = replay.commands.into_iter().peekable();
+
+        while let Some((at, command)) = commands.next() {
+            let next_at = commands.peek().map_or(replay.duration, |(next_at, _)| *next_at);
+            let until_next = next_at.saturating_sub(at);
+
+            tokio::time::sleep_until(start + at).await;
+            if let Some(response) = motors.apply(addr, command).await {
+                debug!("replayed command of {} responded {:?}", addr, response);
+            }
+            // The replay drives without its client.
+            motors.with_rover(move |rover| rover.feed_watchdog_for(until_next)).await;
         }
+
+        tokio::time::sleep_until(start + replay.duration).await;
+        debug!("replay of {} done", addr);
+        motors.with_rover(stop_rover).await;
     });
 
-    let server = Server::bind(&addr).serve(make_svc);
-    let graceful = server.with_graceful_shutdown(shutdown_signal(rover.clone()));
+    services.recorder.replaying(addr, task);
 
-    // Run this server for... forever!
-    if let Err(e) = graceful.await {
-        error!("server error: {}", e);
+    None
+}
+
+/// Applies `command` from the motor task, starting its background tasks
+/// (e.g. DriveFor stopping the rover) with `motors`.
+fn execute_command(
+    addr: SocketAddr,
+    command: RoverCommand,
+    rover: &mut Rover,
+    scheduler: &Arc<Scheduler>,
+    motors: &Motors,
+) -> Option<RoverResponse> {
+    match try_execute_command(addr, command.clone(), rover, scheduler, motors) {
+        Ok(response) => response,
+        Err(e) => {
+            // The motors are in an unknown state, at least try to stop them.
+            error!("unable to apply {:?}: {}, stopping the rover", command, e);
+            stop_rover(rover);
+
+            Some(RoverResponse::Error {
+                code: "I2C_ERROR",
+                message: e.to_string(),
+            })
+        },
+    }
+}
+
+fn try_execute_command(
+    addr: SocketAddr,
+    command: RoverCommand,
+    rover: &mut Rover,
+    scheduler: &Arc<Scheduler>,
+    motors: &Motors,
+) -> Result<Option<RoverResponse>, RoverError> {
+    let inversion = rover.inversion;
+    let (command, pattern) = match command.normalized() {
+        // The operator's inputs, flipped as asked before being mixed.
+        RoverCommand::Drive { linear, angular } => {
+            let (linear, angular) = inversion.drive(linear, angular);
+
+            (RoverCommand::Drive { linear, angular }, None)
+        },
+        RoverCommand::DriveFor { linear, angular, duration_ms } => {
+            let (linear, angular) = inversion.drive(linear, angular);
+
+            (RoverCommand::DriveFor { linear, angular, duration_ms }, None)
+        },
+        RoverCommand::Spin { direction, speed } => {
+            (RoverCommand::Spin { direction: inversion.spin(direction), speed }, None)
+        },
+        // Shaped with the settings of the rover, then driven like `Drive`.
+        RoverCommand::Axis { throttle, steer } => {
+            let (linear, angular) = rover.axis.shape(throttle, steer);
+            let (linear, angular) = inversion.drive(linear, angular);
+
+            (RoverCommand::Drive { linear, angular }, None)
+        },
+        // Sized with the settings of the rover, then run like `Sequence`.
+        RoverCommand::TestPattern { kind } => {
+            (RoverCommand::Sequence { steps: rover.patterns.steps(kind) }, Some(kind))
+        },
+        command => (command, None),
+    };
+
+    // Cancel background actions (such as a wiggle test) superseded by this
+    // command.
+    let generation = match command {
+        RoverCommand::GetLogs { .. }
+        | RoverCommand::ReadChannels
+        | RoverCommand::GetResourceStats
+        | RoverCommand::GetSpeedLimit
+        | RoverCommand::GetStatus
+        | RoverCommand::Schedule { .. }
+        | RoverCommand::CancelScheduled { .. }
+        | RoverCommand::SetTrim { .. }
+        | RoverCommand::SetMaxSpeed { .. }
+        | RoverCommand::SetDriveParams { .. }
+        | RoverCommand::SetSteeringInvert { .. }
+        | RoverCommand::SetSteering { .. }
+        | RoverCommand::SetAux { .. }
+        | RoverCommand::SetOutput { .. }
+        | RoverCommand::SetColor { .. } => rover.generation(),
+        _ => {
+            // Hand the motors back as they were before a cancelled wiggle
+            // test, this command applying on top of that.
+            rover.end_wiggle()?;
+            rover.next_generation()
+        },
+    };
+
+    match command {
+        RoverCommand::Schedule { delay_ms, command } => {
+            let motors = motors.clone();
+            let action = move || {
+                // The command is applied (or rejected) according to the state
+                // of the rover when it runs, not when it was scheduled.
+                if let Some(response) = futures::executor::block_on(motors.apply(addr, *command)) {
+                    debug!("scheduled command of {} responded {:?}", addr, response);
+                }
+            };
+
+            Ok(Some(match scheduler.schedule(addr, Duration::from_millis(delay_ms), action) {
+                Some(id) => RoverResponse::Scheduled { id },
+                None => RoverResponse::Error {
+                    code: "TOO_MANY_SCHEDULED",
+                    message: "too many scheduled commands, cancel some first".to_string(),
+                },
+            }))
+        }
+        RoverCommand::CancelScheduled { id } => {
+            if !scheduler.cancel(id) {
+                return Ok(Some(RoverResponse::Error {
+                    code: "NOT_SCHEDULED",
+                    message: format!("no pending scheduled command #{}", id),
+                }));
+            }
+
+            Ok(None)
+        }
+        command => {
+            if let Some(response) = apply_command(rover, command.clone())? {
+                return Ok(Some(response));
+            }
+
+            match command {
+                RoverCommand::DriveFor { duration_ms, .. } => {
+                    let duration = Duration::from_millis(duration_ms.into());
+                    let motors = motors.clone();
+
+                    tokio::spawn(async move {
+                        tokio::time::sleep(duration).await;
+                        motors.with_rover(move |rover| {
+                            if rover.generation() == generation {
+                                debug!("stopping after driving for {:?}", duration);
+                                stop_rover(rover);
+                            }
+                        }).await;
+                    });
+                }
+                RoverCommand::WiggleMotor { motor } => {
+                    tokio::spawn(wiggle_motor(motors.clone(), motor, generation));
+                }
+                RoverCommand::Sequence { steps } => {
+                    if let Some(kind) = pattern {
+                        info!("running the {:?} test pattern", kind);
+                        rover.start_pattern(kind);
+                    }
+                    tokio::spawn(run_sequence(motors.clone(), steps, generation));
+                }
+                _ => {}
+            }
+
+            Ok(None)
+        }
+    }
+}
+
+/// Applies a normalized `command` to `rover`, only changing the rover.
+///
+/// The commands going on in the background only get checked: starting them
+/// (or stopping the rover after `DriveFor`) and scheduling commands is up to
+/// `try_execute_command()`.
+fn apply_command(rover: &mut Rover, command: RoverCommand) -> Result<Option<RoverResponse>, RoverError> {
+    match command {
+        RoverCommand::MotorRun { motor, .. }
+        | RoverCommand::MotorStop { motor, .. }
+        | RoverCommand::WiggleMotor { motor }
+            if motor.motors(rover).is_empty() =>
+        {
+            return Ok(Some(RoverResponse::Error {
+                code: "NO_SUCH_MOTOR",
+                message: format!("the rover has no {:?} motor, see ROVER_MOTORS", motor),
+            }));
+        }
+        RoverCommand::MotorRun { motor, .. }
+        | RoverCommand::MotorStop { motor, .. }
+        | RoverCommand::WiggleMotor { motor }
+            if motor.motors(rover).iter().any(|motor| !motor.is_available()) =>
+        {
+            return Ok(Some(motor_unavailable()));
+        }
+        RoverCommand::Drive { .. }
+        | RoverCommand::DriveFor { .. }
+        | RoverCommand::Spin { .. }
+        | RoverCommand::Sequence { .. }
+            if rover.motors().any(|motor| !motor.is_available()) =>
+        {
+            return Ok(Some(motor_unavailable()));
+        }
+        RoverCommand::MotorRun { .. }
+        | RoverCommand::Drive { .. }
+        | RoverCommand::DriveFor { .. }
+        | RoverCommand::Spin { .. }
+        | RoverCommand::WiggleMotor { .. }
+        | RoverCommand::Sequence { .. }
+        | RoverCommand::SetSteering { .. }
+        | RoverCommand::SetAux { .. }
+        | RoverCommand::SetOutput { .. }
+            if rover.emergency_stopped() =>
+        {
+            return Ok(Some(RoverResponse::Error {
+                code: "EMERGENCY_STOPPED",
+                message: "the rover is emergency stopped, POST /reset first".to_string(),
+            }));
+        }
+        RoverCommand::MotorRun { .. }
+        | RoverCommand::MotorStop { .. }
+        | RoverCommand::Drive { .. }
+        | RoverCommand::DriveFor { .. }
+        | RoverCommand::Spin { .. }
+        | RoverCommand::WiggleMotor { .. }
+        | RoverCommand::BenchmarkI2c { .. }
+        | RoverCommand::Sequence { .. }
+            if rover.paused_mut().is_some() =>
+        {
+            return Ok(handle_paused_command(command, rover));
+        }
+        RoverCommand::MotorRun { motor, direction, speed } => {
+            let speed = speed.min(rover.max_speed());
+            let mut motors = motor.motors(rover);
+
+            // Check all the motors first, so that none moves if one can't.
+            if let Some(error) = motors.iter().find_map(|motor| check_reversal_cooldown(motor, direction)) {
+                return Ok(Some(error));
+            }
+
+            for motor in motors.iter_mut() {
+                motor.set_target_speed(speed, direction)?;
+            }
+        }
+        RoverCommand::Drive { linear, angular } => {
+            return drive(rover, linear, angular);
+        }
+        RoverCommand::Spin { direction, speed } => {
+            let (left, right) = direction.speeds(speed);
+
+            if let Some(error) = check_drive_cooldowns(rover, left, right) {
+                return Ok(Some(error));
+            }
+
+            match direction {
+                TurnDirection::Clockwise => rover.turn_right(speed)?,
+                TurnDirection::CounterClockwise => rover.turn_left(speed)?,
+            }
+        }
+        RoverCommand::DriveFor { linear, angular, duration_ms } => {
+            if let Some(error) = drive(rover, linear, angular)? {
+                return Ok(Some(error));
+            }
+            // The rover stops by itself.
+            rover.feed_watchdog_for(Duration::from_millis(duration_ms.into()));
+        }
+        RoverCommand::MotorStop { motor, brake } => {
+            for motor in motor.motors(rover) {
+                if brake {
+                    motor.brake()?;
+                } else {
+                    motor.stop()?;
+                }
+            }
+        }
+        RoverCommand::MotorRunSigned { .. } => unreachable!("normalized to MotorRun or MotorStop"),
+        RoverCommand::Axis { .. } => unreachable!("shaped into Drive by try_execute_command()"),
+        RoverCommand::TestPattern { .. } => unreachable!("sized into Sequence by try_execute_command()"),
+        RoverCommand::GetLogs { count, min_level } => {
+            return Ok(Some(RoverResponse::Logs {
+                records: logs::recent(count, min_level),
+            }));
+        }
+        RoverCommand::Neutral => rover.neutral()?,
+        RoverCommand::Pause => rover.pause()?,
+        RoverCommand::Resume => rover.resume()?,
+        // Run in the background by try_execute_command().
+        RoverCommand::WiggleMotor { .. } => rover.start_wiggle(),
+        RoverCommand::Sequence { .. } => {}
+        RoverCommand::BenchmarkI2c { writes } => {
+            if !rover.motors().all(DCMotor::is_stopped) {
+                return Ok(Some(RoverResponse::Error {
+                    code: "MOTORS_RUNNING",
+                    message: "stop the motors before benchmarking the I2C bus".to_string(),
+                }));
+            }
+
+            return Ok(Some(RoverResponse::I2cBenchmark(
+                rover.benchmark_i2c(writes.clamp(1, MAX_BENCHMARK_WRITES))?,
+            )));
+        }
+        RoverCommand::GetResourceStats => {
+            return Ok(Some(RoverResponse::ResourceStats(resources::stats())));
+        }
+        RoverCommand::GetSpeedLimit => {
+            return Ok(Some(RoverResponse::SpeedLimits { motors: SpeedLimit::all(rover) }));
+        }
+        RoverCommand::GetStatus => {
+            return Ok(Some(RoverResponse::Status(Box::new(Status::of(rover)))));
+        }
+        RoverCommand::SetTrim { left, right } => {
+            for &(side, trim) in &[(Side::Left, left), (Side::Right, right)] {
+                for motor in rover.side_mut(side) {
+                    motor.set_trim(trim)?;
+                }
+            }
+        }
+        RoverCommand::SetMaxSpeed { speed } => rover.set_max_speed(speed)?,
+        RoverCommand::SetDriveParams { max_speed, accel, decel, expo } => {
+            let current = DriveParams::of(rover);
+
+            return Ok(Some(
+                match DriveParams::merged_into(current, max_speed, accel, decel, expo, rover.left_motor.min_speed) {
+                    Ok(params) => {
+                        params.apply(rover)?;
+                        info!("drive parameters changed from {:?} to {:?}", current, params);
+
+                        RoverResponse::DriveParams(DriveParams::of(rover))
+                    },
+                    Err(message) => RoverResponse::Error { code: "OUT_OF_RANGE", message },
+                },
+            ));
+        }
+        RoverCommand::SetSteeringInvert { forward, steering } => {
+            let inversion = Inversion { forward, steering };
+
+            if inversion != rover.inversion {
+                info!("operator inputs inverted: {:?}", inversion);
+            }
+            rover.inversion = inversion;
+        }
+        RoverCommand::SetSteering { angle } => match &mut rover.steering {
+            Some(steering) => steering.set_angle(angle)?,
+            None => {
+                return Ok(Some(RoverResponse::Error {
+                    code: "NO_STEERING",
+                    message: "the rover has no steering servo, see ROVER_STEERING_CHANNEL".to_string(),
+                }));
+            },
+        },
+        RoverCommand::SetOutput { ref id, .. } if RoverMotorId::of_output(id).is_some() => {
+            unreachable!("normalized to MotorRun or MotorStop")
+        }
+        RoverCommand::SetOutput { id, value } => match rover.output_mut(&id) {
+            Some(output) => output.set_value(value)?,
+            None => {
+                return Ok(Some(RoverResponse::Error {
+                    code: "NO_SUCH_OUTPUT",
+                    message: format!("no output named {:?}, see ROVER_STEERING_CHANNEL and ROVER_AUX", id),
+                }));
+            },
+        },
+        RoverCommand::SetColor { id, r, g, b } => match rover.rgb_leds.get_mut(&id) {
+            Some(led) => led.set_color(rgb::Color::new(r, g, b))?,
+            None => {
+                return Ok(Some(RoverResponse::Error {
+                    code: "NO_SUCH_OUTPUT",
+                    message: format!("no RGB LED named {:?}, see rgb_leds", id),
+                }));
+            },
+        },
+        RoverCommand::SetAux { name, level } => match rover.aux.get_mut(&name) {
+            Some(output) => output.set_level(level)?,
+            None => {
+                return Ok(Some(RoverResponse::Error {
+                    code: "NO_SUCH_AUX",
+                    message: format!("no auxiliary output named {:?}, see ROVER_AUX", name),
+                }));
+            },
+        },
+        RoverCommand::ReadChannels => {
+            return Ok(Some(match rover.read_channels() {
+                Ok(channels) => RoverResponse::Channels { channels },
+                Err(e) => RoverResponse::Error {
+                    code: "I2C_ERROR",
+                    message: format!("unable to read the channels: {}", e),
+                },
+            }));
+        }
+        RoverCommand::Schedule { .. } | RoverCommand::CancelScheduled { .. } => {
+            unreachable!("handled by try_execute_command()")
+        }
+        RoverCommand::RequestControl
+        | RoverCommand::StartRecording { .. }
+        | RoverCommand::StopRecording
+        | RoverCommand::Replay { .. } => unreachable!("handled by handle_command(), can't be scheduled"),
+    }
+
+    Ok(None)
+}
+
+/// Drives the rover with mixed linear and angular speeds, see
+/// `differential_drive()`.
+fn drive(rover: &mut Rover, linear: i16, angular: i16) -> Result<Option<RoverResponse>, RoverError> {
+    let (left, right) = differential_drive(linear, angular);
+
+    if let Some(error) = check_drive_cooldowns(rover, left, right) {
+        return Ok(Some(error));
+    }
+
+    rover.drive(left, right)?;
+
+    Ok(None)
+}
+
+/// Rejects driving the motors at signed speeds if that reverses a motor too
+/// soon after its last reversal. All the motors are checked before any
+/// moves, so that none does if one can't.
+fn check_drive_cooldowns(rover: &mut Rover, left: i16, right: i16) -> Option<RoverResponse> {
+    for &(side, speed) in &[(Side::Left, left), (Side::Right, right)] {
+        let state = MotorState::signed(speed);
+
+        if state.speed == 0 {
+            continue;
+        }
+        for motor in rover.side_mut(side) {
+            if let Some(error) = check_reversal_cooldown(motor, state.direction) {
+                return Some(error);
+            }
+        }
+    }
+
+    None
+}
+
+/// Stops the rover, only logging errors since there is nothing more to do
+/// about them.
+fn stop_rover(rover: &mut Rover) {
+    if let Err(e) = rover.stop() {
+        error!("unable to stop the rover: {}", e);
+    }
+}
+
+/// Same as `stop_rover()`, ramping the motors down, see `Rover::soft_stop()`.
+fn soft_stop_rover(rover: &mut Rover) {
+    if let Err(e) = rover.soft_stop() {
+        error!("unable to stop the rover: {}", e);
+    }
+}
+
+/// Rejects reversing `motor` to `direction` while its reversal cooldown is
+/// not over.
+fn check_reversal_cooldown(motor: &DCMotor, direction: DCMotorDirection) -> Option<RoverResponse> {
+    let remaining = motor.reversal_cooldown_remaining(direction)?;
+
+    info!("rejected reversal of {:?} to {:?}: {:?} of cooldown left", motor, direction, remaining);
+
+    Some(RoverResponse::Error {
+        code: "REVERSAL_COOLDOWN",
+        message: format!("motor reversed too recently, retry in {}ms", remaining.as_millis()),
+    })
+}
+
+/// Error of the commands to motors whose board failed to initialize, see
+/// `RoverConfig::allow_degraded`.
+fn motor_unavailable() -> RoverResponse {
+    RoverResponse::Error {
+        code: "MOTOR_UNAVAILABLE",
+        message: "the board of the motor failed to initialize".to_string(),
+    }
+}
+
+/// Handles a motor command received while the rover is paused: it is either
+/// rejected, or queued to be applied on `Resume`. Since a side is resumed as
+/// a whole, a queued command for one motor applies to its whole side.
+fn handle_paused_command(command: RoverCommand, rover: &mut Rover) -> Option<RoverResponse> {
+    let queue = rover.queue_while_paused;
+    let paused = rover.paused_mut().expect("the rover is paused");
+
+    match command {
+        RoverCommand::MotorRun { motor, direction, speed } if queue => {
+            let state = match motor.side() {
+                Side::Right => &mut paused.right,
+                Side::Left => &mut paused.left,
+            };
+
+            state.speed = speed;
+            state.direction = direction;
+
+            None
+        },
+        RoverCommand::MotorStop { motor, .. } if queue => {
+            match motor.side() {
+                Side::Right => paused.right.speed = 0,
+                Side::Left => paused.left.speed = 0,
+            }
+
+            None
+        },
+        RoverCommand::Drive { linear, angular } if queue => {
+            let (left, right) = differential_drive(linear, angular);
+
+            paused.left = MotorState::signed(left);
+            paused.right = MotorState::signed(right);
+
+            None
+        },
+        RoverCommand::Spin { direction, speed } if queue => {
+            let (left, right) = direction.speeds(speed);
+
+            paused.left = MotorState::signed(left);
+            paused.right = MotorState::signed(right);
+
+            None
+        },
+        _ => Some(RoverResponse::Error {
+            code: "PAUSED",
+            message: "the rover is paused, send Resume first".to_string(),
+        }),
+    }
+}
+
+/// Drives `motor` forward and backward a few times, then restores what the
+/// motors were doing, see `Rover::end_wiggle()`. A newer command taking over
+/// in the meantime restores them first.
+async fn wiggle_motor(motors: Motors, motor: RoverMotorId, generation: u64) {
+    let steps = [DCMotorDirection::Forward, DCMotorDirection::Backward]
+        .iter()
+        .cycle()
+        .take(2 * WIGGLE_CYCLES);
+
+    info!("wiggling {:?}", motor);
+
+    for direction in steps.copied() {
+        let cancelled = motors.with_rover(move |rover| {
+            if rover.generation() != generation {
+                return true;
+            }
+
+            let speed = WIGGLE_SPEED.min(rover.max_speed());
+            let result = motor.motors(rover)
+                .into_iter()
+                .try_for_each(|motor| motor.set_speed(speed, direction).map(drop));
+
+            if let Err(e) = result {
+                error!("unable to wiggle {:?}: {}, stopping the rover", motor, e);
+                stop_rover(rover);
+
+                return true;
+            }
+            // The test is short and stops by itself.
+            rover.feed_watchdog();
+
+            false
+        }).await;
+
+        if cancelled {
+            debug!("wiggling {:?} cancelled", motor);
+            return;
+        }
+
+        tokio::time::sleep(WIGGLE_STEP).await;
+    }
+
+    motors.with_rover(move |rover| {
+        // Otherwise the newer command restored the motors.
+        if rover.generation() != generation {
+            return;
+        }
+
+        if let Err(e) = rover.end_wiggle() {
+            error!("unable to restore {:?} after wiggling it: {}, stopping the rover", motor, e);
+            stop_rover(rover);
+        }
+    }).await;
+}
+
+/// Drives through `steps` back to back then stops, unless a newer command
+/// took over in the meantime.
+async fn run_sequence(motors: Motors, steps: Vec<Step>, generation: u64) {
+    info!("running a sequence of {} steps", steps.len());
+
+    for (i, step) in steps.into_iter().enumerate() {
+        let duration = Duration::from_millis(step.duration_ms.into());
+        let cancelled = motors.with_rover(move |rover| {
+            if rover.generation() != generation {
+                return true;
+            }
+
+            match drive(rover, step.drive.linear, step.drive.angular) {
+                Ok(None) => {
+                    // The sequence drives without its client.
+                    rover.feed_watchdog_for(duration);
+
+                    false
+                },
+                Ok(Some(error)) => {
+                    warn!("sequence step #{} rejected: {:?}, stopping the rover", i, error);
+                    stop_rover(rover);
+                    rover.end_pattern(generation, PatternState::Failed);
+
+                    true
+                },
+                Err(e) => {
+                    error!("unable to run sequence step #{}: {}, stopping the rover", i, e);
+                    stop_rover(rover);
+                    rover.end_pattern(generation, PatternState::Failed);
+
+                    true
+                },
+            }
+        }).await;
+
+        if cancelled {
+            debug!("sequence cancelled at step #{}", i);
+            return;
+        }
+
+        tokio::time::sleep(duration).await;
+    }
+
+    motors.with_rover(move |rover| {
+        if rover.generation() == generation {
+            debug!("sequence done");
+            stop_rover(rover);
+            rover.end_pattern(generation, PatternState::Done);
+        }
+    }).await;
+}
+
+fn json_response(body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+/// State shared by all the requests.
+#[derive(Clone)]
+struct Services {
+    quotas: Arc<Quotas>,
+    scheduler: Arc<Scheduler>,
+    files: Arc<StaticFiles>,
+    telemetry: broadcast::Sender<Telemetry>,
+    control: Arc<Control>,
+    /// Path of the WebSocket endpoint.
+    ws_path: Arc<str>,
+    /// Token WebSocket clients must connect with, if any.
+    token: Option<Arc<str>>,
+    /// Commands each connection can apply per `RATE_LIMIT_WINDOW`, `None`
+    /// when unlimited.
+    rate_limit: Option<u32>,
+    /// WebSocket connections open at once, `None` when unlimited.
+    max_connections: Option<usize>,
+    recorder: Arc<Recorder>,
+    shutdown: Arc<Shutdown>,
+    metrics: Arc<Metrics>,
+    cors: Arc<Cors>,
+    motors: Motors,
+}
+
+/// Value of the `name` query parameter of `request`, still percent-encoded.
+fn query_param<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Whether `request` carries the token clients must give, if any, as its
+/// `token` query parameter.
+fn authorized(request: &Request<Body>, token: Option<&str>) -> bool {
+    match token {
+        Some(token) => query_param(request, "token")
+            .and_then(files::percent_decode)
+            .is_some_and(|given| token_matches(&given, token)),
+        None => true,
+    }
+}
+
+/// Compares tokens in constant time, so that the time it takes doesn't
+/// tell how much of a guess was right.
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Lets the allowed origins call the HTTP endpoints, see `Cors`, then routes
+/// `request`.
+async fn handle_request(
+    request: Request<Body>,
+    remote_addr: SocketAddr,
+    services: Services,
+    tagging: Tagging,
+    rejected_log_len: usize,
+) -> Result<Response<Body>, Infallible> {
+    // WebSocket handshakes aren't subject to CORS.
+    let origin = Some(&request)
+        .filter(|request| !request.headers().contains_key(header::UPGRADE))
+        .and_then(|request| services.cors.allowed_origin(request.headers()));
+    let origin = match origin {
+        Some(origin) => origin,
+        None => return route_request(request, remote_addr, services, tagging, rejected_log_len).await,
+    };
+
+    if request.method() == Method::OPTIONS
+        && request.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return Ok(Cors::preflight(origin));
+    }
+
+    let mut response = route_request(request, remote_addr, services, tagging, rejected_log_len).await?;
+
+    Cors::allow(origin, &mut response);
+
+    Ok(response)
+}
+
+async fn route_request(
+    mut request: Request<Body>,
+    remote_addr: SocketAddr,
+    services: Services,
+    tagging: Tagging,
+    rejected_log_len: usize,
+) -> Result<Response<Body>, Infallible> {
+    let Services {
+        scheduler,
+        files,
+        telemetry,
+        control,
+        ws_path,
+        token,
+        rate_limit,
+        max_connections,
+        recorder,
+        shutdown,
+        metrics,
+        motors,
+        ..
+    } = services.clone();
+
+    match (request.uri().path(), request.headers().contains_key(header::UPGRADE)) {
+        //if the request is ws_echo and the request headers contains an Upgrade key
+        (path, true) if path == &*ws_path => {
+            if !authorized(&request, token.as_deref()) {
+                warn!("rejected WebSocket connection from {}: missing or wrong token", remote_addr);
+
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+
+                return Ok(response);
+            }
+
+            // Counted from now on, until the connection closes whichever
+            // way.
+            let connection = match shutdown.connection(max_connections) {
+                Ok(connection) => connection,
+                Err(Refused::ShuttingDown) => {
+                    let mut response = Response::new(Body::from("shutting down\n"));
+                    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+                    return Ok(response);
+                },
+                Err(Refused::TooMany) => {
+                    let limit = max_connections.unwrap_or_default();
+
+                    warn!("rejected WebSocket connection from {}: {} connections are open already", remote_addr, limit);
+
+                    // Rejected before the handshake, so the reason can't be
+                    // a close frame.
+                    let mut response = Response::new(Body::from(format!(
+                        "too many connections ({} at most), try again later\n",
+                        limit,
+                    )));
+                    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+                    return Ok(response);
+                },
+            };
+
+            // Clients can opt into JSON-RPC framing with its subprotocol, or
+            // into another tagging of the commands with ?tagging=<tagging>.
+            let tagging = query_param(&request, "tagging")
+                .and_then(|param| param.parse().ok())
+                .unwrap_or(tagging);
+            let framing = match request.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
+                Some(protocols) if protocols
+                    .to_str()
+                    .unwrap_or_default()
+                    .split(',')
+                    .any(|protocol| protocol.trim() == jsonrpc::SUBPROTOCOL) => Framing::JsonRpc,
+                _ => Framing::Plain(tagging),
+            };
+
+            //assume request is a handshake, so create the handshake response
+            let response = 
+            match handshake::server::create_response_with_body(&request, Body::empty) {
+                Ok(mut response) => {
+                    if let Framing::JsonRpc = framing {
+                        response.headers_mut().insert(
+                            header::SEC_WEBSOCKET_PROTOCOL,
+                            header::HeaderValue::from_static(jsonrpc::SUBPROTOCOL),
+                        );
+                    }
+
+                    //in case the handshake response creation succeeds,
+                    //spawn a task to handle the websocket connection
+                    tokio::spawn(async move {
+                        // The shutdown waits for this task until the end.
+                        let _connection = connection;
+
+                        //using the hyper feature of upgrading a connection
+                        match upgrade::on(&mut request).await {
+                            //if successfully upgraded
+                            Ok(upgraded) => {
+                                //create a websocket stream from the upgraded object
+                                let ws_stream = WebSocketStream::from_raw_socket(
+                                    //pass the upgraded object
+                                    //as the base layer stream of the Websocket
+                                    upgraded,
+                                    tokio_tungstenite::tungstenite::protocol::Role::Server,
+                                    None,
+                                ).await;
+
+                                info!("new WebSocket connection: {} ({:?} framing)", remote_addr, framing);
+
+                                let mut telemetry = telemetry.subscribe();
+                                let limiter = Arc::new(Mutex::new(RateLimiter::new(rate_limit, RATE_LIMIT_WINDOW)));
+
+                                control.join(remote_addr);
+
+                                //we can split the stream into a sink and a stream
+                                let (mut ws_write, mut ws_read) = ws_stream.split();
+                                let receive = async {
+                                    loop {
+                                        let flush_at = limiter.lock().unwrap().next_flush();
+
+                                        tokio::select! {
+                                            msg = ws_read.try_next() => {
+                                                let msg = match msg? {
+                                                    // tungstenite answers pings by itself, sending
+                                                    // another pong would answer them twice.
+                                                    Some(tungstenite::Message::Ping(_))
+                                                    | Some(tungstenite::Message::Pong(_)) => continue,
+                                                    Some(msg) => msg,
+                                                    None => break,
+                                                };
+                                                let services = services.clone();
+                                                let limiter = limiter.clone();
+                                                // Handled from a blocking thread, waiting for the motor task.
+                                                let response = tokio::task::spawn_blocking(move || {
+                                                    handle_message(remote_addr, msg, &services, &limiter, framing, rejected_log_len)
+                                                }).await.expect("message handling panicked");
+
+                                                if let Some(response) = response {
+                                                    ws_write.send(response).await?;
+                                                }
+                                            },
+                                            // Apply the latest of the commands coalesced by the
+                                            // rate limiter.
+                                            _ = async { tokio::time::sleep_until(flush_at.unwrap().into()).await }, if flush_at.is_some() => {
+                                                let commands = limiter.lock().unwrap().take_due();
+                                                let services = services.clone();
+
+                                                tokio::task::spawn_blocking(move || {
+                                                    for command in commands {
+                                                        debug!("applying coalesced command of {}: {:?}", remote_addr, command);
+                                                        // Timed from now on, they were held back until now.
+                                                        if let Some(RoverResponse::Error { code, message }) = handle_command(remote_addr, command, Instant::now(), &services) {
+                                                            debug!("rejected a coalesced command of {} ({}): {}", remote_addr, code, message);
+                                                        }
+                                                    }
+                                                }).await.expect("message handling panicked");
+                                            },
+                                            frame = telemetry.recv() => match frame {
+                                                Ok(frame) => {
+                                                    ws_write.send(tungstenite::Message::Text(framing.format_telemetry(frame))).await?;
+                                                },
+                                                // A slow client only misses frames.
+                                                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                                                    debug!("{} lagged behind, skipped {} telemetry frames", remote_addr, skipped);
+                                                },
+                                                Err(broadcast::error::RecvError::Closed) => unreachable!("Services holds a sender"),
+                                            },
+                                            _ = shutdown.started() => {
+                                                debug!("closing the connection of {}, shutting down", remote_addr);
+                                                ws_write.send(tungstenite::Message::Close(Some(CloseFrame {
+                                                    code: CloseCode::Away,
+                                                    reason: "server shutting down".into(),
+                                                }))).await?;
+
+                                                break;
+                                            },
+                                        }
+                                    }
+
+                                    Ok(())
+                                };
+
+                                let result = receive.await;
+
+                                scheduler.cancel_all(remote_addr);
+                                recorder.abort_replay_of(remote_addr);
+                                // The shutdown stops the rover by itself, once
+                                // all the connections are closed.
+                                if control.leave(remote_addr) && !shutdown.is_started() {
+                                    motors.with_rover(soft_stop_rover).await;
+                                }
+
+                                match result {
+                                    Ok(_) => {},
+                                    Err(Error::ConnectionClosed) => info!("connection closed normally"),
+                                    Err(e) => error!("error: {:?}", e),
+                                }
+                            },
+                            Err(e) => {
+                                metrics.upgrade_failed();
+                                error!(
+                                    "error when trying to upgrade connection \
+                                    from address {} to websocket connection: \
+                                    {}",
+                                    remote_addr,
+                                    e
+                                );
+                            },
+                        }
+                    });
+                    //return the response to the handshake request
+                    response
+                },
+                Err(error) => {
+                    //probably the handshake request is not up to spec for websocket
+                    error!(
+                        "Failed to create websocket response \
+                        to request from address {}: {}",
+                        remote_addr,
+                        error,
+                    );
+                    let mut res = Response::new(Body::from(format!("failed to create websocket: {}", error)));
+                    *res.status_mut() = StatusCode::BAD_REQUEST;
+                    return Ok(res);
+                }
+            };
+        
+            Ok::<_, Infallible>(response)
+        },
+        ("/estop", false)
+        | ("/reset", false)
+        | ("/command", false)
+        | ("/api/command", false)
+        | ("/api/restart", false) if request.method() != Method::POST => {
+            Ok(
+                Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(header::ALLOW, "POST")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+        },
+        ("/estop", false) => {
+            if let Err(e) = motors.with_rover(Rover::emergency_stop).await {
+                error!("unable to emergency stop the rover: {}", e);
+
+                let mut response = json_response(r#"{"stopped":false}"#);
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+
+                return Ok(response);
+            }
+
+            Ok(json_response(r#"{"stopped":true}"#))
+        },
+        ("/reset", false) => {
+            motors.with_rover(Rover::reset_emergency_stop).await;
+
+            Ok(json_response(r#"{"stopped":false}"#))
+        },
+        // One-off commands, for clients that don't want to keep a WebSocket
+        // connection open. Replies like over a WebSocket connection, with
+        // ?tagging=<tagging> too.
+        //
+        // /api/command is the same, to go with /api/poll.
+        ("/command", false) | ("/api/command", false) => {
+            let received = Instant::now();
+
+            if !authorized(&request, token.as_deref()) {
+                warn!("rejected a command from {}: missing or wrong token", remote_addr);
+
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+
+                return Ok(response);
+            }
+            // The rover is about to stop, it must not start again.
+            if shutdown.is_started() {
+                let ack = Ack::error(Some("SHUTTING_DOWN"), "the server is shutting down".to_string());
+                let mut response = json_response(serde_json::to_string(&ack).expect("acks always serialize to JSON"));
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+                return Ok(response);
+            }
+
+            let tagging = query_param(&request, "tagging")
+                .and_then(|param| param.parse().ok())
+                .unwrap_or(tagging);
+            services.metrics.command_received();
+
+            let (seq, command) = match read_body(&mut request, MAX_COMMAND_BODY).await {
+                Some(body) => match String::from_utf8(body) {
+                    Ok(text) => {
+                        let (seq, command) = tagging.parse_sequenced::<RoverCommand>(&text);
+
+                        (seq, command.map_err(|e| e.to_string()))
+                    },
+                    Err(e) => (None, Err(e.to_string())),
+                },
+                None => (None, Err(format!("unreadable body, or larger than {} bytes", MAX_COMMAND_BODY))),
+            };
+            let command = match command {
+                Ok(command) => command,
+                Err(e) => {
+                    services.metrics.command_unparsable();
+                    debug!("rejected a command from {}: {}", remote_addr, e);
+
+                    let ack = serde_json::to_string(&Ack::error(None, e).with_seq(seq)).expect("acks always serialize to JSON");
+                    let mut response = json_response(ack);
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
+
+                    return Ok(response);
+                },
+            };
+
+            debug!("received a command from {}: {:?}", remote_addr, command);
+
+            let reply_to = command.clone();
+            let quotas = services.quotas.clone();
+            let response = tokio::task::spawn_blocking(move || handle_http_command(remote_addr, command, received, &services))
+                .await
+                .expect("command handling panicked");
+            let status = command_status(&response);
+            let quota_remaining = quotas.remaining(remote_addr.ip());
+            let mut response = json_response(plain_reply(tagging, &reply_to, seq, response, quota_remaining));
+            *response.status_mut() = status;
+
+            Ok(response)
+        },
+        // Stops the rover and shuts the server down cleanly, then starts it
+        // again with its configuration and files read anew, see restart().
+        // Only with a token, not to let anyone on the network restart it.
+        ("/api/restart", false) => {
+            if token.is_none() {
+                warn!("rejected a restart from {}: ROVER_TOKEN isn't set", remote_addr);
+
+                let mut response = Response::new(Body::from("set ROVER_TOKEN to allow restarts\n"));
+                *response.status_mut() = StatusCode::FORBIDDEN;
+
+                return Ok(response);
+            }
+            if !authorized(&request, token.as_deref()) {
+                warn!("rejected a restart from {}: missing or wrong token", remote_addr);
+
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+
+                return Ok(response);
+            }
+            if shutdown.is_started() {
+                let mut response = Response::new(Body::from("shutting down\n"));
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+                return Ok(response);
+            }
+
+            info!("{} asked for a restart", remote_addr);
+            motors.with_rover(stop_rover).await;
+            shutdown.restart();
+
+            let mut response = json_response(r#"{"restarting":true}"#);
+            *response.status_mut() = StatusCode::ACCEPTED;
+
+            Ok(response)
+        },
+        ("/api/poll", false) if request.method() != Method::GET => {
+            Ok(
+                Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(header::ALLOW, "GET")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+        },
+        // Long-poll fallback for the networks blocking WebSockets: waits
+        // for the next telemetry frame, formatted like over a WebSocket
+        // connection, for ?timeout_ms=<ms> (25s by default, 60s at most),
+        // replying 204 No Content if none came. Along with /api/command, this
+        // emulates a WebSocket connection, at the cost of a request per frame
+        // and of the latency of opening it: prefer WebSockets when they go
+        // through.
+        ("/api/poll", false) => {
+            if !authorized(&request, token.as_deref()) {
+                warn!("rejected a poll from {}: missing or wrong token", remote_addr);
+
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+
+                return Ok(response);
+            }
+
+            let tagging = query_param(&request, "tagging")
+                .and_then(|param| param.parse().ok())
+                .unwrap_or(tagging);
+            let timeout = query_param(&request, "timeout_ms")
+                .and_then(|param| param.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(POLL_TIMEOUT)
+                .min(MAX_POLL_TIMEOUT);
+            let mut telemetry = telemetry.subscribe();
+            let frame = async {
+                loop {
+                    match telemetry.recv().await {
+                        Ok(frame) => return frame,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => unreachable!("Services holds a sender"),
+                    }
+                }
+            };
+
+            tokio::select! {
+                frame = tokio::time::timeout(timeout, frame) => Ok(match frame {
+                    Ok(frame) => json_response(Framing::Plain(tagging).format_telemetry(frame)),
+                    Err(_) => {
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::NO_CONTENT;
+                        response
+                    },
+                }),
+                _ = shutdown.started() => {
+                    let mut response = Response::new(Body::from("shutting down\n"));
+                    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+                    Ok(response)
+                },
+            }
+        },
+        ("/config", false) if request.method() != Method::GET && request.method() != Method::POST => {
+            Ok(
+                Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(header::ALLOW, "GET, POST")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+        },
+        // The settings that can be changed without a restart, see Tunables.
+        ("/config", false) if request.method() == Method::GET => {
+            let tunables = motors.with_rover(|rover| Tunables::of(rover)).await;
+
+            Ok(json_response(serde_json::to_string(&tunables).expect("tunables always serialize to JSON")))
+        },
+        // Changes some of them, replying with all of them.
+        ("/config", false) => {
+            if !authorized(&request, token.as_deref()) {
+                warn!("rejected a configuration change from {}: missing or wrong token", remote_addr);
+
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+
+                return Ok(response);
+            }
+
+            let update = match read_body(&mut request, MAX_CONFIG_BODY).await {
+                Some(body) => serde_json::from_slice::<TunablesUpdate>(&body).map_err(|e| {
+                    // Unknown fields are the settings that require a restart.
+                    (StatusCode::BAD_REQUEST, format!("{}, the other settings require a restart", e))
+                }),
+                None => Err((StatusCode::BAD_REQUEST, format!("unreadable body, or larger than {} bytes", MAX_CONFIG_BODY))),
+            };
+            let result = match update {
+                Ok(update) => motors.with_rover(move |rover| {
+                    let current = Tunables::of(rover);
+                    let tunables = update.merged_into(&current).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+                    if let Err(e) = tunables.apply(rover) {
+                        return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+                    }
+                    if tunables != current {
+                        info!("{} changed the configuration from {:?} to {:?}", remote_addr, current, tunables);
+                    }
+
+                    Ok(tunables)
+                }).await,
+                Err(e) => Err(e),
+            };
+
+            Ok(match result {
+                Ok(tunables) => json_response(serde_json::to_string(&tunables).expect("tunables always serialize to JSON")),
+                Err((status, e)) => {
+                    debug!("rejected a configuration change from {}: {}", remote_addr, e);
+
+                    let mut response = json_response(serde_json::to_string(&Ack::error(None, e)).expect("acks always serialize to JSON"));
+                    *response.status_mut() = status;
+                    response
+                },
+            })
+        },
+        ("/health", false) | ("/metrics", false) | ("/info", false) if request.method() != Method::GET => {
+            Ok(
+                Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(header::ALLOW, "GET")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+        },
+        // Readiness check for monitoring, 503 while the PCA9685 can't be
+        // reached. Doesn't talk to the board, see I2cHealth.
+        ("/health", false) => {
+            let health = motors.with_rover(|rover| Health::of(rover)).await;
+            let status = if health.is_ok() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+            let mut response = json_response(serde_json::to_string(&health).expect("health always serializes to JSON"));
+            *response.status_mut() = status;
+
+            Ok(response)
+        },
+        ("/metrics", false) => {
+            let snapshot = motors.with_rover(|rover| Snapshot {
+                left_speed: MotorState::current(&rover.left_motor).to_signed(),
+                right_speed: MotorState::current(&rover.right_motor).to_signed(),
+                connections: resources::connections(),
+                i2c_errors: rover.i2c_errors(),
+                reset_recoveries: rover.reset_recoveries(),
+                stop_faults: rover.stop_faults(),
+            }).await;
+
+            Ok(
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(Body::from(services.metrics.render(snapshot)))
+                    .unwrap()
+            )
+        },
+        // Lets clients find the WebSocket endpoint, see ROVER_WS_PATH.
+        ("/info", false) => {
+            Ok(json_response(serde_json::to_string(&ServerInfo::new(&ws_path)).expect("info always serializes to JSON")))
+        },
+        (path, false) if path == &*ws_path => {
+            //handle the case where the url is the websocket one, but does not have an Upgrade field
+            Ok(Response::new(Body::from(
+                "Getting even warmer, \
+                try connecting to this url \
+                using a websocket client.\n"
+            )))
+        },
+        (url, false) => {
+            info!("serving URL {}", &url);
+
+            Ok(files.serve(url, request.headers()))
+        },
+        (_, true) => {
+            //handle any other url with an Upgrade header field
+            Ok(Response::new(Body::from(format!(
+                "Getting warmer, but I'm \
+                only letting you connect \
+                via websocket over on \
+                {}, try that url.\n",
+                ws_path,
+            ))))
+        }
+    }
+}
+
+async fn recover_from_resets(motors: Motors, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = motors.with_rover(Rover::recover_from_reset).await {
+            error!("unable to check the PCA9685 for a reset: {}", e);
+        }
+    }
+}
+
+/// Number of telemetry frames a connection can lag behind before missing
+/// some.
+const TELEMETRY_CAPACITY: usize = 16;
+
+async fn publish_telemetry(motors: Motors, control: Arc<Control>, telemetry: broadcast::Sender<Telemetry>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        let mut frame = motors.with_rover(|rover| Telemetry::of(rover)).await;
+
+        frame.session_remaining_ms = control.remaining().map(|remaining| remaining.as_millis() as u64);
+
+        // Fails when no client is connected, which is fine.
+        let _ = telemetry.send(frame);
+    }
+}
+
+/// How often `end_sessions()` checks whether the session of the controller
+/// is over.
+const SESSION_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+/// Takes control back from the controllers whose demo session is over, see
+/// `Control::expire()`, stopping the rover for the next one to start from a
+/// stopped rover, like when it requests control.
+async fn end_sessions(control: Arc<Control>, scheduler: Arc<Scheduler>, recorder: Arc<Recorder>, motors: Motors) {
+    let mut interval = tokio::time::interval(SESSION_CHECK_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        if let Some(previous) = control.expire() {
+            scheduler.cancel_all(previous);
+            recorder.abort_replay();
+            motors.with_rover(stop_rover).await;
+        }
+    }
+}
+
+async fn shutdown_signal(motors: Motors, shutdown: Arc<Shutdown>) {
+    // Wait for the CTRL+C signal, or for POST /api/restart.
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.expect("failed to install CTRL+C signal handler"),
+        _ = shutdown.restart_requested() => info!("restarting"),
+    }
+
+    info!("shutting down");
+
+    // Close the WebSocket connections first, so that no command comes in
+    // after the rover stopped. Their commands in progress complete.
+    let open = shutdown.close_connections(CLOSE_TIMEOUT).await;
+
+    if open > 0 {
+        warn!("{} WebSocket connections didn't close within {:?}", open, CLOSE_TIMEOUT);
+    }
+
+    // Let the motors ramp down before the runtime goes away with the task
+    // ramping them, then make sure they stopped.
+    let window = motors.with_rover(|rover| {
+        soft_stop_rover(rover);
+        rover.soft_stop_window
+    }).await;
+
+    tokio::time::sleep(window).await;
+    motors.with_rover(stop_rover).await;
+}
+
+/// Replaces the process with a new one running the same executable with the
+/// same arguments and environment, for it to read its configuration and
+/// files again, once the server shut down cleanly.
+///
+/// Exits with an error if that fails (e.g. the executable was removed), so
+/// this assumes a supervisor (systemd's `Restart=on-failure`, a container
+/// restart policy...) starts the server again, or it stays down.
+fn restart() -> ! {
+    let error = match std::env::current_exe() {
+        Ok(exe) => exec(std::process::Command::new(exe).args(std::env::args_os().skip(1))),
+        Err(e) => e,
+    };
+
+    error!("unable to restart: {}, exiting for a supervisor to start the server again", error);
+    std::process::exit(1);
+}
+
+#[cfg(unix)]
+fn exec(command: &mut std::process::Command) -> std::io::Error {
+    use std::os::unix::process::CommandExt;
+
+    command.exec()
+}
+
+#[cfg(not(unix))]
+fn exec(_command: &mut std::process::Command) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, "restarting in place requires a Unix system")
+}
+
+/// Creates the rover, retrying with exponential backoff as configured by
+/// `RoverConfig::init_attempts`, so that a slow bus on cold boot doesn't
+/// make the service fail.
+async fn init_rover(config: &RoverConfig) -> Result<Rover, RoverError> {
+    let mut backoff = Duration::from_millis(config.init_backoff_ms);
+    let mut attempt = 1;
+
+    loop {
+        // Only give up on the boards failing to initialize after the last
+        // attempt.
+        let degraded = config.allow_degraded && attempt >= config.init_attempts;
+
+        match Rover::new(config, degraded) {
+            Err(e) if attempt < config.init_attempts => {
+                warn!(
+                    "initialization attempt {} of {} failed: {}, retrying in {:?}",
+                    attempt,
+                    config.init_attempts,
+                    e,
+                    backoff,
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Value of the `--bind <addr>` (or `--bind=<addr>`) command line argument.
+fn bind_arg() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "--bind" {
+            return args.next();
+        }
+        if let Some(addr) = arg.strip_prefix("--bind=") {
+            return Some(addr.to_string());
+        }
+    }
+
+    None
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    logs::init_custom_env("ROVER_LOG");
+
+    // Read the configuration from ROVER_CONFIG (rover.toml by default), the
+    // ROVER_* environment variables overriding it, see RoverConfig.
+    let config = match RoverConfig::load() {
+        Ok((config, path)) => {
+            match path {
+                Some(path) => info!("read the configuration from {:?}", path),
+                None => info!("no configuration file, using the environment and the defaults"),
+            }
+            info!("configuration: {:?}", config);
+
+            config
+        },
+        Err(e) => {
+            error!("invalid configuration: {}", e);
+            std::process::exit(1);
+        },
+    };
+
+    // Run without the PCA9685 board, e.g. on a development machine, with
+    // ROVER_BACKEND=mock.
+    let rover = match std::env::var("ROVER_BACKEND") {
+        Ok(backend) if backend == "mock" => Ok(Rover::mock(&config)),
+        Ok(backend) if backend == "pca9685" => init_rover(&config).await,
+        Ok(backend) => panic!("ROVER_BACKEND must be \"pca9685\" or \"mock\", not {:?}", backend),
+        Err(_) => init_rover(&config).await,
+    };
+    let mut rover = match rover {
+        Ok(rover) => rover,
+        // Names the board, its address and its bus.
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    // Correct a rover pulling to one side with ROVER_LEFT_TRIM and
+    // ROVER_RIGHT_TRIM, and avoid speeds too low to turn the motors with
+    // ROVER_MIN_SPEED, see RoverConfig.
+    {
+        for &(side, trim) in &[(Side::Left, config.left_trim), (Side::Right, config.right_trim)] {
+            for motor in rover.side_mut(side) {
+                motor.trim = trim;
+            }
+        }
+        for motor in rover.motors_mut() {
+            motor.min_speed = config.min_speed;
+            motor.min_speed_policy = config.min_speed_policy;
+        }
+    }
+    // Read the registers back after each stop with ROVER_VERIFY_STOP=1.
+    rover.verify_stop = std::env::var("ROVER_VERIFY_STOP")
+        .map(|verify| verify == "1" || verify == "true")
+        .unwrap_or(false);
+    // Queue motor commands received while paused with
+    // ROVER_PAUSE_POLICY=queue, they are rejected by default.
+    rover.queue_while_paused = match std::env::var("ROVER_PAUSE_POLICY") {
+        Ok(policy) if policy == "queue" => true,
+        Ok(policy) if policy == "reject" => false,
+        Ok(policy) => panic!("ROVER_PAUSE_POLICY must be \"reject\" or \"queue\", not {:?}", policy),
+        Err(_) => false,
+    };
+    // Limit how often each motor can reverse with ROVER_REVERSAL_COOLDOWN_MS.
+    if let Ok(ms) = std::env::var("ROVER_REVERSAL_COOLDOWN_MS") {
+        let cooldown = Duration::from_millis(
+            ms.parse().expect("ROVER_REVERSAL_COOLDOWN_MS must be a number of milliseconds")
+        );
+        for motor in rover.motors_mut() {
+            motor.reversal_cooldown = cooldown;
+        }
+    }
+    // Stop the fast motors for a while before reversing them, see
+    // RoverConfig::reversal_dwell_ms.
+    let reversal_dwell = Duration::from_millis(config.reversal_dwell_ms);
+    for motor in rover.motors_mut() {
+        motor.reversal_dwell = reversal_dwell;
+        motor.reversal_guard_speed = config.reversal_guard_speed;
+    }
+    // Kick the motors started slowly, see RoverConfig::kick_duty.
+    for motor in rover.motors_mut() {
+        motor.kick_duty = config.kick_duty;
+        motor.kick_duration = Duration::from_millis(config.kick_ms);
+    }
+    // See RoverConfig::target_policy.
+    for motor in rover.motors_mut() {
+        motor.target_policy = config.target_policy;
+    }
+    // Ramp the motors' speed by at most ROVER_RAMP_STEP every 20ms instead
+    // of applying it right away (0, the default, disables ramping).
+    let ramp_step = match std::env::var("ROVER_RAMP_STEP") {
+        Ok(step) => step.parse().expect("ROVER_RAMP_STEP must be a speed"),
+        Err(_) => 0,
+    };
+    if ramp_step > 0 {
+        for motor in rover.motors_mut() {
+            motor.max_delta_per_tick = ramp_step;
+        }
+    }
+    // Keep the motors stopped after POST /estop until POST /reset with
+    // ROVER_ESTOP_LATCH=1.
+    rover.latch_emergency_stop = std::env::var("ROVER_ESTOP_LATCH")
+        .map(|latch| latch == "1" || latch == "true")
+        .unwrap_or(false);
+    // Ramp the motors down for ROVER_SOFT_STOP_MS (250ms by default) when
+    // the client disconnects or the server shuts down, 0 stops them right
+    // away.
+    let soft_stop_window = match std::env::var("ROVER_SOFT_STOP_MS") {
+        Ok(ms) => Duration::from_millis(ms.parse().expect("ROVER_SOFT_STOP_MS must be a number of milliseconds")),
+        Err(_) => Duration::from_millis(250),
+    };
+    rover.soft_stop_window = soft_stop_window;
+    // Start from the neutral pose, not from whatever the boards powered up
+    // with.
+    if let Err(e) = rover.neutral() {
+        error!("unable to put the rover in its neutral pose: {}", e);
+    }
+
+    // Periodically check whether the PCA9685 was reset by a brownout,
+    // unless disabled with ROVER_BROWNOUT_CHECK_MS=0.
+    let brownout_check_ms = match std::env::var("ROVER_BROWNOUT_CHECK_MS") {
+        Ok(ms) => ms.parse().expect("ROVER_BROWNOUT_CHECK_MS must be a number of milliseconds"),
+        Err(_) => 1000,
+    };
+
+    // Exported by GET /metrics.
+    let metrics = Arc::new(Metrics::default());
+
+    // Stop the rover when it receives no command for ROVER_WATCHDOG_MS
+    // (500ms by default) while moving, unless disabled with 0.
+    if config.watchdog_ms > 0 {
+        rover.watchdog_timeout = Some(Duration::from_millis(config.watchdog_ms));
+    }
+
+    // Let all the clients drive the rover at once with ROVER_CONTROL=shared,
+    // instead of only the first one (ROVER_CONTROL=exclusive, the default).
+    let control = match std::env::var("ROVER_CONTROL") {
+        Ok(control) if control == "exclusive" => Control::new(true),
+        Ok(control) if control == "shared" => Control::new(false),
+        Ok(control) => panic!("ROVER_CONTROL must be \"exclusive\" or \"shared\", not {:?}", control),
+        Err(_) => Control::new(true),
+    };
+    // Hand control over to the next connection in line every
+    // ROVER_DEMO_SESSION_MS in demo mode, see RoverConfig::demo_session_ms.
+    let control = match config.demo_session_ms {
+        0 => control,
+        _ if !control.is_exclusive() => {
+            error!("demo sessions (ROVER_DEMO_SESSION_MS) require ROVER_CONTROL=exclusive");
+            std::process::exit(1);
+        },
+        ms => {
+            info!(
+                "demo mode: sessions of {}ms{}",
+                ms,
+                if config.demo_enforce { "" } else { ", not enforced" },
+            );
+            control.with_session(Duration::from_millis(ms), config.demo_enforce)
+        },
+    };
+    let control = Arc::new(control);
+
+    // Push telemetry to the clients every ROVER_TELEMETRY_MS (100ms by
+    // default), unless disabled with 0.
+    let telemetry_ms = match std::env::var("ROVER_TELEMETRY_MS") {
+        Ok(ms) => ms.parse().expect("ROVER_TELEMETRY_MS must be a number of milliseconds"),
+        Err(_) => 100,
+    };
+    let (telemetry, _) = broadcast::channel(TELEMETRY_CAPACITY);
+
+    // Limit how many commands each client can send per window, e.g. with
+    // ROVER_QUOTA=600 and ROVER_QUOTA_WINDOW_MS=60000 (the default window).
+    let quota = std::env::var("ROVER_QUOTA")
+        .ok()
+        .map(|quota| quota.parse().expect("ROVER_QUOTA must be a number of commands"));
+    let quota_window_ms = match std::env::var("ROVER_QUOTA_WINDOW_MS") {
+        Ok(ms) => ms.parse().expect("ROVER_QUOTA_WINDOW_MS must be a number of milliseconds"),
+        Err(_) => 60_000,
+    };
+    let quotas = Arc::new(Quotas::new(quota, Duration::from_millis(quota_window_ms)));
+    let scheduler = Arc::new(Scheduler::default());
+    // Apply the commands from a task of their own, which owns the rover
+    // from now on, ramps the motors and runs the watchdog.
+    let motors = Motors::spawn(rover, scheduler.clone(), metrics.clone());
+    if brownout_check_ms > 0 {
+        tokio::spawn(recover_from_resets(motors.clone(), Duration::from_millis(brownout_check_ms)));
+    }
+    if telemetry_ms > 0 {
+        tokio::spawn(publish_telemetry(
+            motors.clone(),
+            control.clone(),
+            telemetry.clone(),
+            Duration::from_millis(telemetry_ms),
+        ));
+    }
+    // Serve the static files from ROVER_WEB_ROOT (the current directory by
+    // default), and nothing outside of it.
+    let files = Arc::new(StaticFiles::new(&config.web_root).expect("ROVER_WEB_ROOT must be an existing directory"));
+
+    info!("serving static files from {:?}", files.root());
+
+    // Tag the commands and responses differently by default with
+    // ROVER_TAGGING=adjacent or internal (external by default).
+    let tagging = match std::env::var("ROVER_TAGGING") {
+        Ok(tagging) => tagging.parse().expect("invalid ROVER_TAGGING"),
+        Err(_) => Tagging::External,
+    };
+    // Received and rejected messages are logged at debug level, secrets
+    // redacted, up to ROVER_REJECTED_LOG_LEN characters (256 by default).
+    let rejected_log_len = match std::env::var("ROVER_REJECTED_LOG_LEN") {
+        Ok(len) => len.parse().expect("ROVER_REJECTED_LOG_LEN must be a number of characters"),
+        Err(_) => 256,
+    };
+
+    // Accept WebSocket connections on ROVER_WS_PATH (/websocket by default),
+    // e.g. to namespace it behind a proxy.
+    let ws_path: Arc<str> = config.ws_path.as_str().into();
+
+    // Require WebSocket clients to connect with ?token=<ROVER_TOKEN>, when
+    // set, so that not just anyone on the network can drive the rover.
+    let token = std::env::var("ROVER_TOKEN").ok().map(Arc::from);
+
+    // Listen on another address with `--bind`, or ROVER_BIND_ADDR (e.g.
+    // 127.0.0.1:8080 behind a reverse proxy), or on port 3000 of all the
+    // interfaces by default.
+    let addr = match bind_arg() {
+        Some(addr) => match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("invalid bind address {:?}: {}", addr, e);
+                std::process::exit(1);
+            },
+        },
+        None => config.bind_addr,
+    };
+
+    info!(
+        "listening on {} for {} connections",
+        addr,
+        if config.tls_cert.is_some() { "https or wss" } else { "http or websocket" },
+    );
+
+    // Advertise the service on the local network when given a name with
+    // ROVER_MDNS_NAME (requires the "mdns" feature).
+    #[cfg(feature = "mdns")]
+    let _mdns = std::env::var("ROVER_MDNS_NAME").ok().and_then(|name| {
+        mdns::advertise(&name, addr.port(), &config.ws_path)
+            .map_err(|e| error!("unable to advertise the service over mDNS: {}", e))
+            .ok()
+    });
+
+    // Limit how many commands each connection applies per second with
+    // ROVER_RATE_LIMIT (disabled by default, or when 0), see RateLimiter.
+    // Off unless set, since a limit suiting a bus depends on the boards.
+    let rate_limit = match std::env::var("ROVER_RATE_LIMIT") {
+        Ok(limit) => limit.parse().expect("ROVER_RATE_LIMIT must be a number of commands per second"),
+        Err(_) => 0,
+    };
+    let rate_limit = Some(rate_limit).filter(|limit| *limit > 0);
+
+    // Save the recordings in ROVER_RECORDINGS_DIR ("recordings" by default).
+    let recorder = Arc::new(Recorder::new(
+        std::env::var("ROVER_RECORDINGS_DIR").unwrap_or_else(|_| "recordings".to_string()).into(),
+    ));
+
+    if config.demo_session_ms > 0 && config.demo_enforce {
+        tokio::spawn(end_sessions(control.clone(), scheduler.clone(), recorder.clone(), motors.clone()));
+    }
+
+    let shutdown = Arc::new(Shutdown::default());
+
+    // Let browser clients served from ROVER_CORS_ORIGINS call the HTTP
+    // endpoints, e.g. a UI under development.
+    if !config.cors_origins.is_empty() {
+        info!("allowing cross-origin requests from {}", config.cors_origins.join(", "));
+    }
+    let cors = Arc::new(Cors::new(config.cors_origins.clone()));
+
+    // A `Service` is needed for every connection, so this
+    // creates one from our `handle_request` function.
+    let services = Services {
+        quotas,
+        scheduler,
+        files,
+        telemetry,
+        control,
+        ws_path,
+        token,
+        rate_limit,
+        max_connections: Some(config.max_connections).filter(|connections| *connections > 0),
+        recorder,
+        shutdown: shutdown.clone(),
+        metrics,
+        cors,
+        motors: motors.clone(),
+    };
+    let make_svc = |remote_addr: SocketAddr| {
+        let services = services.clone();
+
+        async move {
+            // service_fn converts our function into a `Service`
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>|
+                handle_request(request, remote_addr, services.clone(), tagging, rejected_log_len)
+            ))
+        }
+    };
+    let signal = shutdown_signal(motors.clone(), shutdown);
+
+    // Serve HTTPS and WSS when given a certificate with ROVER_TLS_CERT and
+    // ROVER_TLS_KEY (requires the "tls" feature).
+    let result = match (&config.tls_cert, &config.tls_key) {
+        #[cfg(feature = "tls")]
+        (Some(cert), Some(key)) => {
+            let tls_config = tls::server_config(cert, key).unwrap_or_else(|e| {
+                error!("unable to set up TLS: {}", e);
+                std::process::exit(1);
+            });
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap_or_else(|e| {
+                error!("unable to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            });
+
+            Server::builder(hyper::server::accept::from_stream(tls::incoming(listener, tls_config)))
+                .serve(make_service_fn(|conn| make_svc(tls::remote_addr(conn))))
+                .with_graceful_shutdown(signal)
+                .await
+        },
+        #[cfg(not(feature = "tls"))]
+        (Some(_), Some(_)) => {
+            error!("ROVER_TLS_CERT requires the \"tls\" feature");
+            std::process::exit(1);
+        },
+        _ => {
+            Server::bind(&addr)
+                .serve(make_service_fn(|conn: &AddrStream| make_svc(conn.remote_addr())))
+                .with_graceful_shutdown(signal)
+                .await
+        },
+    };
+
+    // Run this server for... forever!
+    if let Err(e) = result {
+        error!("server error: {}", e);
+    }
+
+    info!("server stopped ({} WebSocket connections left)", resources::connections());
+
+    if services.shutdown.is_restarting() {
+        restart();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::LimitReason;
+
+    fn rover() -> Rover {
+        Rover::mock(&RoverConfig::default())
+    }
+
+    fn services(rover: Rover) -> Services {
+        let scheduler = Arc::new(Scheduler::default());
+        let metrics = Arc::new(Metrics::default());
+
+        Services {
+            quotas: Arc::new(Quotas::new(None, Duration::from_secs(60))),
+            scheduler: scheduler.clone(),
+            files: Arc::new(StaticFiles::new(".").unwrap()),
+            telemetry: broadcast::channel(TELEMETRY_CAPACITY).0,
+            control: Arc::new(Control::new(true)),
+            ws_path: "/websocket".into(),
+            token: None,
+            rate_limit: None,
+            max_connections: None,
+            recorder: Arc::new(Recorder::new(std::env::temp_dir())),
+            shutdown: Arc::new(Shutdown::default()),
+            metrics: metrics.clone(),
+            cors: Arc::new(Cors::new(Vec::new())),
+            motors: Motors::spawn(rover, scheduler, metrics),
+        }
+    }
+
+    fn speeds(rover: &Rover) -> (i16, i16) {
+        (MotorState::of(&rover.left_motor).to_signed(), MotorState::of(&rover.right_motor).to_signed())
+    }
+
+    #[test]
+    fn drive_mixes_linear_and_angular_speeds() {
+        let mut rover = rover();
+
+        assert!(apply_command(&mut rover, RoverCommand::Drive { linear: 50, angular: 20 }).unwrap().is_none());
+        assert_eq!(speeds(&rover), (70, 30));
+    }
+
+    #[test]
+    fn signed_motor_runs_keep_their_direction() {
+        let run = |speed| RoverCommand::MotorRunSigned { motor: RoverMotorId::Left, speed }.normalized();
+
+        assert!(matches!(
+            run(40),
+            RoverCommand::MotorRun { motor: RoverMotorId::Left, direction: DCMotorDirection::Forward, speed: 40 },
+        ));
+        assert!(matches!(
+            run(-40),
+            RoverCommand::MotorRun { motor: RoverMotorId::Left, direction: DCMotorDirection::Backward, speed: 40 },
+        ));
+        assert!(matches!(run(0), RoverCommand::MotorStop { motor: RoverMotorId::Left, brake: false }));
+
+        let mut rover = rover();
+
+        apply_command(&mut rover, run(-40)).unwrap();
+
+        assert_eq!(speeds(&rover), (-40, 0));
+    }
+
+    #[test]
+    fn motor_run_is_capped_to_the_max_speed() {
+        let mut rover = rover();
+        let run = RoverCommand::MotorRun { motor: RoverMotorId::Left, direction: DCMotorDirection::Backward, speed: 80 };
+
+        apply_command(&mut rover, RoverCommand::SetMaxSpeed { speed: 30 }).unwrap();
+        apply_command(&mut rover, run).unwrap();
+
+        assert_eq!(speeds(&rover), (-30, 0));
+    }
+
+    #[test]
+    fn reversals_wait_for_the_cooldown() {
+        let mut rover = rover();
+        let run = |direction| RoverCommand::MotorRun { motor: RoverMotorId::Left, direction, speed: 40 };
+
+        rover.left_motor.reversal_cooldown = Duration::from_millis(50);
+
+        assert!(apply_command(&mut rover, run(DCMotorDirection::Backward)).unwrap().is_none());
+
+        let response = apply_command(&mut rover, run(DCMotorDirection::Forward)).unwrap();
+
+        assert!(matches!(response, Some(RoverResponse::Error { code: "REVERSAL_COOLDOWN", .. })));
+        assert_eq!(speeds(&rover), (-40, 0));
+
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert!(apply_command(&mut rover, run(DCMotorDirection::Forward)).unwrap().is_none());
+        assert_eq!(speeds(&rover), (40, 0));
+    }
+
+    #[test]
+    fn paused_rovers_reject_motor_commands() {
+        let mut rover = rover();
+
+        apply_command(&mut rover, RoverCommand::Pause).unwrap();
+
+        let response = apply_command(&mut rover, RoverCommand::Drive { linear: 50, angular: 0 }).unwrap();
+
+        assert!(matches!(response, Some(RoverResponse::Error { code: "PAUSED", .. })));
+        assert_eq!(speeds(&rover), (0, 0));
+    }
+
+    #[test]
+    fn latched_emergency_stops_hold_every_output() {
+        let config: RoverConfig = toml::from_str(r#"
+            [steering]
+            channel = 15
+
+            [aux]
+            headlight = 12
+        "#).unwrap();
+        let mut rover = Rover::mock(&config);
+
+        rover.latch_emergency_stop = true;
+        rover.emergency_stop().unwrap();
+
+        for command in [
+            RoverCommand::Drive { linear: 50, angular: 0 },
+            RoverCommand::SetSteering { angle: 30 },
+            RoverCommand::SetAux { name: "headlight".to_string(), level: 50 },
+        ] {
+            let response = apply_command(&mut rover, command).unwrap();
+
+            assert!(matches!(response, Some(RoverResponse::Error { code: "EMERGENCY_STOPPED", .. })));
+        }
+        assert_eq!(rover.steering.as_ref().map(servo::Servo::angle), Some(0));
+    }
+
+    #[test]
+    fn outputs_are_set_by_id() {
+        let config: RoverConfig = toml::from_str(r#"
+            [steering]
+            channel = 15
+
+            [aux]
+            headlight = 12
+        "#).unwrap();
+        let mut rover = Rover::mock(&config);
+        let mut set = |id: &str, value| {
+            let command = RoverCommand::SetOutput { id: id.to_string(), value };
+
+            command.validate().map_err(|_| "OUT_OF_RANGE")?;
+            match apply_command(&mut rover, command.normalized()).unwrap() {
+                Some(RoverResponse::Error { code, .. }) => Err(code),
+                _ => Ok(()),
+            }
+        };
+
+        assert_eq!(set("left", -0.4), Ok(()));
+        assert_eq!(set("steering", 0.5), Ok(()));
+        assert_eq!(set("headlight", 0.25), Ok(()));
+        assert_eq!(set("rear_left", 0.5), Err("NO_SUCH_MOTOR"));
+        assert_eq!(set("horn", 0.5), Err("NO_SUCH_OUTPUT"));
+        assert_eq!(set("headlight", 1.5), Err("OUT_OF_RANGE"));
+
+        assert_eq!(speeds(&rover), (-40, 0));
+        assert_eq!(rover.steering.as_ref().map(servo::Servo::angle), Some(45));
+        assert_eq!(rover.output_mut("headlight").map(|output| output.value()), Some(0.25));
+    }
+
+    #[test]
+    fn motors_on_missing_boards_are_unavailable() {
+        let mut config = RoverConfig::default();
+
+        config.boards.insert("absent".to_string(), config::BoardConfig { i2c_path: None, address: 0x41, init_delay_ms: 0 });
+        config.right_channels.board = "absent".to_string();
+
+        let backends = std::iter::once((config::DEFAULT_BOARD.to_string(), backend::MockBackend::default())).collect();
+        let mut rover = Rover::with_backends(backends, &config);
+        let run = |motor| RoverCommand::MotorRun { motor, direction: DCMotorDirection::Forward, speed: 40 };
+
+        let response = apply_command(&mut rover, run(RoverMotorId::Right)).unwrap();
+        assert!(matches!(response, Some(RoverResponse::Error { code: "MOTOR_UNAVAILABLE", .. })));
+        let response = apply_command(&mut rover, RoverCommand::Drive { linear: 50, angular: 0 }).unwrap();
+        assert!(matches!(response, Some(RoverResponse::Error { code: "MOTOR_UNAVAILABLE", .. })));
+
+        let health = Health::of(&rover);
+        assert_eq!(health.status, "degraded");
+        assert_eq!((health.unavailable_boards, health.unavailable_motors), (vec!["absent".to_string()], vec!["right"]));
+
+        assert!(apply_command(&mut rover, run(RoverMotorId::Left)).unwrap().is_none());
+        assert_eq!(speeds(&rover), (40, 0));
+    }
+
+    #[test]
+    fn coalesced_commands_are_acknowledged() {
+        let command = RoverCommand::Drive { linear: 50, angular: 0 };
+        let response = Some(RoverResponse::Coalesced { window_ms: 20 });
+        let reply: serde_json::Value = serde_json::from_str(&plain_reply(Tagging::External, &command, Some(7), response, None)).unwrap();
+
+        assert_eq!(reply, serde_json::json!({ "ok": true, "status": "coalesced", "seq": 7 }));
+    }
+
+    #[test]
+    fn state_changes_over_the_rate_limit_are_queued() {
+        let services = services(rover());
+        let addr: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        let limiter = Mutex::new(RateLimiter::new(Some(1), Duration::from_secs(60)));
+        let send = |text: &str| -> serde_json::Value {
+            let reply = handle_text(addr, text, Instant::now(), &services, &limiter, Framing::Plain(Tagging::External), 0);
+
+            serde_json::from_str(&reply.unwrap()).unwrap()
+        };
+
+        assert_eq!(send(r#""RequestControl""#)["ok"], true);
+        assert_eq!(send(r#"{"Drive":{"linear":50,"angular":0}}"#)["status"], "coalesced");
+        assert_eq!(send(r#""Pause""#)["status"], "queued");
+        assert_eq!(send(r#""GetStatus""#)["code"], "THROTTLED");
+    }
+
+    #[test]
+    fn recordings_require_control() {
+        let services = services(rover());
+        let addr: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+
+        for command in [RoverCommand::StartRecording { name: "demo".to_string() }, RoverCommand::StopRecording].iter() {
+            let response = handle_command(addr, command.clone(), Instant::now(), &services);
+
+            assert!(matches!(response, Some(RoverResponse::Error { code: "NOT_CONTROLLER", .. })));
+        }
+    }
+
+    #[test]
+    fn commands_over_the_quota_are_rejected() {
+        let services = Services { quotas: Arc::new(Quotas::new(Some(2), Duration::from_secs(60))), ..services(rover()) };
+        let addr: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        let reply = |response| -> serde_json::Value {
+            let remaining = services.quotas.remaining(addr.ip());
+
+            serde_json::from_str(&plain_reply(Tagging::External, &RoverCommand::GetStatus, None, response, remaining)).unwrap()
+        };
+
+        let response = handle_command(addr, RoverCommand::Pause, Instant::now(), &services);
+        assert_eq!(reply(response)["quota_remaining"], 1);
+        handle_command(addr, RoverCommand::Pause, Instant::now(), &services);
+
+        let response = handle_command(addr, RoverCommand::Pause, Instant::now(), &services);
+        assert!(matches!(response, Some(RoverResponse::Error { code: "QUOTA_EXCEEDED", .. })));
+        assert_eq!(command_status(&response), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(reply(response)["quota_remaining"], 0);
+    }
+
+    #[test]
+    fn replies_echo_the_sequence_number() {
+        let mut rover = rover();
+
+        for (tagging, text) in [
+            (Tagging::External, r#"{"Pause":null,"seq":42}"#),
+            (Tagging::Internal, r#"{"type":"Pause","seq":42}"#),
+        ] {
+            let (seq, command) = tagging.parse_sequenced::<RoverCommand>(text);
+            let command = command.unwrap();
+            let response = apply_command(&mut rover, command.clone()).unwrap();
+            let reply: serde_json::Value = serde_json::from_str(&plain_reply(tagging, &command, seq, response, None)).unwrap();
+
+            assert_eq!(reply["seq"], 42);
+        }
+
+        let (seq, command) = Tagging::External.parse_sequenced::<RoverCommand>(r#""Pause""#);
+
+        assert_eq!(seq, None);
+        assert!(command.is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_upgrades_are_counted() {
+        let services = services(rover());
+        // A valid handshake, but not coming from a connection hyper could
+        // hand over.
+        let request = Request::get("/websocket")
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap();
+        let addr = "127.0.0.1:4242".parse().unwrap();
+
+        let response = route_request(request, addr, services.clone(), Tagging::External, 0).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        // Waits for the upgrade to fail.
+        assert_eq!(services.shutdown.close_connections(Duration::from_secs(1)).await, 0);
+
+        let snapshot = Snapshot { left_speed: 0, right_speed: 0, connections: 0, i2c_errors: 0, reset_recoveries: 0, stop_faults: 0 };
+
+        assert!(services.metrics.render(snapshot).contains("\nrover_websocket_upgrade_failures_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_patterns_report_how_they_went() {
+        let mut rover = rover();
+
+        rover.patterns = pattern::PatternSettings { speed: 40, side_ms: 1, turn_ms: 1, loop_ms: 1 };
+
+        let motors = services(rover).motors;
+        let addr = "127.0.0.1:4242".parse().unwrap();
+        let state = || motors.with_rover(|rover| rover.test_pattern().map(|run| run.state));
+
+        assert_eq!(state().await, None);
+        assert!(motors.apply(addr, RoverCommand::TestPattern { kind: PatternKind::Square }).await.is_none());
+        assert_eq!(state().await, Some(PatternState::Running));
+
+        let done = async {
+            while state().await != Some(PatternState::Done) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+
+        assert!(tokio::time::timeout(Duration::from_secs(5), done).await.is_ok());
+        assert_eq!(motors.with_rover(|rover| speeds(rover)).await, (0, 0));
+
+        // Any newer motor command cancels the pattern.
+        motors.apply(addr, RoverCommand::TestPattern { kind: PatternKind::FigureEight }).await;
+        motors.apply(addr, RoverCommand::Drive { linear: 30, angular: 0 }).await;
+
+        assert_eq!(state().await, Some(PatternState::Cancelled));
+    }
+
+    #[test]
+    fn drive_params_apply_at_once() {
+        let mut rover = rover();
+        let set = |max_speed, accel, expo| RoverCommand::SetDriveParams { max_speed, accel, decel: None, expo };
+
+        assert!(matches!(
+            apply_command(&mut rover, set(Some(50), Some(10), None)).unwrap(),
+            Some(RoverResponse::DriveParams(DriveParams { max_speed: 50, accel: 10, decel: 0, .. })),
+        ));
+
+        // Ramped from now on.
+        apply_command(&mut rover, RoverCommand::Drive { linear: 40, angular: 0 }).unwrap();
+        rover.tick().unwrap();
+
+        assert_eq!(MotorState::current(&rover.left_motor).speed, 10);
+
+        // None of them applies if one is invalid.
+        assert!(matches!(
+            apply_command(&mut rover, set(Some(80), Some(20), Some(2.0))).unwrap(),
+            Some(RoverResponse::Error { code: "OUT_OF_RANGE", .. }),
+        ));
+        assert_eq!(rover.max_speed(), 50);
+        assert_eq!(rover.left_motor.max_delta_per_tick, 10);
+    }
+
+    #[tokio::test]
+    async fn inversions_flip_the_operator_inputs() {
+        let motors = services(rover()).motors;
+        let addr = "127.0.0.1:4242".parse().unwrap();
+        let drive = |command| async {
+            assert!(motors.apply(addr, command).await.is_none());
+            motors.with_rover(|rover| speeds(rover)).await
+        };
+
+        assert_eq!(drive(RoverCommand::Drive { linear: 50, angular: 20 }).await, (70, 30));
+
+        motors.apply(addr, RoverCommand::SetSteeringInvert { forward: false, steering: true }).await;
+
+        assert_eq!(drive(RoverCommand::Drive { linear: 50, angular: 20 }).await, (30, 70));
+        assert_eq!(drive(RoverCommand::Spin { direction: TurnDirection::Clockwise, speed: 40 }).await, (-40, 40));
+
+        motors.apply(addr, RoverCommand::SetSteeringInvert { forward: true, steering: false }).await;
+
+        assert_eq!(drive(RoverCommand::Axis { throttle: 0.6, steer: 0.0 }).await, (-56, -56));
+        assert!(motors.with_rover(|rover| Status::of(rover).inversion.forward).await);
+    }
+
+    #[test]
+    fn speed_limits_tell_what_holds_each_motor_back() {
+        let mut rover = rover();
+        let limits = |rover: &mut Rover| match apply_command(rover, RoverCommand::GetSpeedLimit).unwrap() {
+            Some(RoverResponse::SpeedLimits { motors }) => {
+                motors.into_iter().map(|(name, limit)| (name, limit.max_speed, limit.reason)).collect::<Vec<_>>()
+            },
+            response => panic!("unexpected response {:?}", response),
+        };
+
+        assert_eq!(limits(&mut rover), [("left", 100, None), ("right", 100, None)]);
+
+        apply_command(&mut rover, RoverCommand::SetTrim { left: -10, right: 0 }).unwrap();
+
+        assert_eq!(limits(&mut rover), [("left", 90, Some(LimitReason::Trim)), ("right", 100, None)]);
+        assert_eq!(Telemetry::of(&rover).speed_limits.len(), 1);
+
+        apply_command(&mut rover, RoverCommand::SetMaxSpeed { speed: 50 }).unwrap();
+
+        assert_eq!(
+            limits(&mut rover),
+            [("left", 45, Some(LimitReason::MaxSpeed)), ("right", 50, Some(LimitReason::MaxSpeed))],
+        );
+
+        rover.latch_emergency_stop = true;
+        rover.emergency_stop().unwrap();
+
+        assert_eq!(limits(&mut rover)[0], ("left", 0, Some(LimitReason::EmergencyStopped)));
+    }
+
+    #[test]
+    fn stops_keep_the_colors() {
+        let mut config = RoverConfig::default();
+        let led = |red, green, blue| config::RgbLedConfig { red, green, blue, board: config::DEFAULT_BOARD.to_string() };
+
+        config.rgb_leds.insert("status".to_string(), led(12, 13, 14));
+        config.rgb_leds.insert("deck".to_string(), led(6, 7, 8));
+        config.status_led = Some("status".to_string());
+
+        let mut rover = Rover::mock(&config);
+        let color = |rover: &Rover, id| rover.rgb_leds[id].color();
+        let blue = rgb::Color::new(0, 0, 255);
+
+        apply_command(&mut rover, RoverCommand::SetColor { id: "deck".to_string(), r: 0, g: 0, b: 255 }).unwrap();
+        rover.show_state().unwrap();
+
+        assert_eq!(color(&rover, "status"), config.status_colors.ready);
+
+        apply_command(&mut rover, RoverCommand::Drive { linear: 50, angular: 0 }).unwrap();
+        rover.show_state().unwrap();
+
+        assert_eq!(color(&rover, "status"), config.status_colors.armed);
+
+        apply_command(&mut rover, RoverCommand::Neutral).unwrap();
+        rover.show_state().unwrap();
+
+        assert_eq!(color(&rover, "status"), config.status_colors.ready);
+        assert_eq!(color(&rover, "deck"), blue);
+
+        rover.latch_emergency_stop = true;
+        rover.emergency_stop().unwrap();
+        rover.show_state().unwrap();
+
+        assert_eq!(color(&rover, "status"), config.status_colors.fault);
+        assert_eq!(color(&rover, "deck"), blue);
+    }
+
+    #[test]
+    fn command_errors_map_to_http_statuses() {
+        let error = |code| Some(RoverResponse::Error { code, message: String::new() });
+
+        assert_eq!(command_status(&None), StatusCode::OK);
+        assert_eq!(command_status(&Some(RoverResponse::Scheduled { id: 1 })), StatusCode::OK);
+        assert_eq!(command_status(&error("OUT_OF_RANGE")), StatusCode::BAD_REQUEST);
+        assert_eq!(command_status(&error("WEBSOCKET_ONLY")), StatusCode::BAD_REQUEST);
+        assert_eq!(command_status(&error("NOT_CONTROLLER")), StatusCode::FORBIDDEN);
+        assert_eq!(command_status(&error("NO_SUCH_MOTOR")), StatusCode::NOT_FOUND);
+        assert_eq!(command_status(&error("QUOTA_EXCEEDED")), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(command_status(&error("I2C_ERROR")), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(command_status(&error("MOTOR_UNAVAILABLE")), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(command_status(&error("PAUSED")), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn commands_require_the_token() {
+        let services = Services { token: Some("s3cret".into()), ..services(rover()) };
+        let request = |uri| Request::post(uri).body(Body::from(r#""GetStatus""#)).unwrap();
+        let addr = "127.0.0.1:4242".parse().unwrap();
+
+        for (uri, status) in [
+            ("/command", StatusCode::UNAUTHORIZED),
+            ("/command?token=guess", StatusCode::UNAUTHORIZED),
+            ("/command?token=s3cret", StatusCode::OK),
+            ("/api/command?token=guess", StatusCode::UNAUTHORIZED),
+            ("/api/command?token=s3cret", StatusCode::OK),
+        ] {
+            let response = route_request(request(uri), addr, services.clone(), Tagging::External, 0).await.unwrap();
+
+            assert_eq!(response.status(), status, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_require_a_token() {
+        let request = |uri| Request::post(uri).body(Body::empty()).unwrap();
+        let addr = "127.0.0.1:4242".parse().unwrap();
+        let open = services(rover());
+
+        let response = route_request(request("/api/restart"), addr, open.clone(), Tagging::External, 0).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!open.shutdown.is_restarting());
+
+        let services = Services { token: Some("s3cret".into()), ..services(rover()) };
+
+        for (uri, status) in [
+            ("/api/restart?token=guess", StatusCode::UNAUTHORIZED),
+            ("/api/restart?token=s3cret", StatusCode::ACCEPTED),
+        ] {
+            let response = route_request(request(uri), addr, services.clone(), Tagging::External, 0).await.unwrap();
+
+            assert_eq!(response.status(), status, "{}", uri);
+        }
+
+        assert!(services.shutdown.is_restarting());
+        assert!(tokio::time::timeout(Duration::from_secs(1), services.shutdown.restart_requested()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn polls_wait_for_telemetry() {
+        let services = services(rover());
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+        let addr = "127.0.0.1:4242".parse().unwrap();
+
+        let response = route_request(request("/api/poll?timeout_ms=0"), addr, services.clone(), Tagging::External, 0).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let poll = route_request(request("/api/poll"), addr, services.clone(), Tagging::External, 0);
+        let frame = services.motors.with_rover(|rover| Telemetry::of(rover)).await;
+
+        tokio::pin!(poll);
+
+        // Published until the poll is waiting for it.
+        let response = loop {
+            tokio::select! {
+                response = &mut poll => break response.unwrap(),
+                _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                    let _ = services.telemetry.send(frame.clone());
+                },
+            }
+        };
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        assert!(String::from_utf8(body.to_vec()).unwrap().starts_with(r#"{"Telemetry":{"left_speed":0,"#));
+    }
+
+    #[tokio::test]
+    async fn commands_are_refused_while_shutting_down() {
+        let services = services(rover());
+        let request = || Request::post("/command").body(Body::from(r#""GetStatus""#)).unwrap();
+        let addr = "127.0.0.1:4242".parse().unwrap();
+
+        let response = route_request(request(), addr, services.clone(), Tagging::External, 0).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        services.shutdown.close_connections(Duration::from_secs(0)).await;
+
+        let response = route_request(request(), addr, services, Tagging::External, 0).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
     }
 }
@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+const SERVICE_TYPE: &str = "_rover._tcp.local.";
+
+/// Advertises the rover service over mDNS as `<name>.local`, its WebSocket
+/// endpoint being at `ws_path`.
+///
+/// The advertisement lasts as long as the returned daemon is kept alive.
+pub fn advertise(name: &str, port: u16, ws_path: &str) -> Result<ServiceDaemon, mdns_sd::Error> {
+    let daemon = ServiceDaemon::new()?;
+    let host_name = format!("{}.local.", name);
+    let mut properties = HashMap::new();
+
+    properties.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+    properties.insert("websocket".to_string(), ws_path.to_string());
+    properties.insert("protocol".to_string(), "json".to_string());
+
+    let service = ServiceInfo::new(SERVICE_TYPE, name, &host_name, "", port, properties)?
+        .enable_addr_auto();
+
+    daemon.register(service)?;
+
+    info!("advertising {} as {} on port {}", SERVICE_TYPE, host_name, port);
+
+    Ok(daemon)
+}
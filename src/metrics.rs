@@ -0,0 +1,141 @@
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of the rover's activity, exported by `GET /metrics` in the
+/// Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    commands_received: AtomicU64,
+    /// Commands rejected because they couldn't be parsed.
+    unparsable_commands: AtomicU64,
+    /// Commands rejected by `RoverCommand::validate()`.
+    invalid_commands: AtomicU64,
+    watchdog_stops: AtomicU64,
+    /// WebSocket handshakes whose connection couldn't be upgraded.
+    upgrade_failures: AtomicU64,
+}
+
+/// What the rover and the server report themselves, read when rendering
+/// the metrics.
+#[derive(Clone, Copy, Debug)]
+pub struct Snapshot {
+    /// Signed speeds, negative when going backward.
+    pub left_speed: i16,
+    pub right_speed: i16,
+    pub connections: usize,
+    pub i2c_errors: u64,
+    pub reset_recoveries: u32,
+    pub stop_faults: u32,
+}
+
+impl Metrics {
+    pub fn command_received(&self) {
+        self.commands_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn command_unparsable(&self) {
+        self.unparsable_commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn command_invalid(&self) {
+        self.invalid_commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn watchdog_stopped(&self) {
+        self.watchdog_stops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upgrade_failed(&self) {
+        self.upgrade_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Formats the counters and `snapshot` in the Prometheus text format.
+    pub fn render(&self, snapshot: Snapshot) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut text = String::new();
+
+        write_metric(
+            &mut text,
+            "rover_commands_received_total",
+            "counter",
+            "Commands received over WebSocket and HTTP.",
+            &[("", load(&self.commands_received))],
+        );
+        write_metric(
+            &mut text,
+            "rover_commands_rejected_total",
+            "counter",
+            "Commands rejected because they couldn't be parsed or were out of range.",
+            &[
+                ("reason=\"parse\"", load(&self.unparsable_commands)),
+                ("reason=\"validation\"", load(&self.invalid_commands)),
+            ],
+        );
+        write_metric(
+            &mut text,
+            "rover_motor_speed",
+            "gauge",
+            "Current speed of the motors, negative when going backward.",
+            &[("side=\"left\"", snapshot.left_speed), ("side=\"right\"", snapshot.right_speed)],
+        );
+        write_metric(
+            &mut text,
+            "rover_websocket_connections",
+            "gauge",
+            "Open WebSocket connections.",
+            &[("", snapshot.connections)],
+        );
+        write_metric(
+            &mut text,
+            "rover_websocket_upgrade_failures_total",
+            "counter",
+            "WebSocket handshakes whose connection couldn't be upgraded.",
+            &[("", load(&self.upgrade_failures))],
+        );
+        write_metric(
+            &mut text,
+            "rover_watchdog_stops_total",
+            "counter",
+            "Times the watchdog stopped the rover for lack of commands.",
+            &[("", load(&self.watchdog_stops))],
+        );
+        write_metric(
+            &mut text,
+            "rover_stop_faults_total",
+            "counter",
+            "Stops the PCA9685 didn't apply, or that couldn't be verified.",
+            &[("", snapshot.stop_faults)],
+        );
+        write_metric(
+            &mut text,
+            "rover_i2c_errors_total",
+            "counter",
+            "Failed I2C transactions with the PCA9685.",
+            &[("", snapshot.i2c_errors)],
+        );
+        write_metric(
+            &mut text,
+            "rover_reset_recoveries_total",
+            "counter",
+            "Times a PCA9685 was re-initialized after losing its state to a reset.",
+            &[("", snapshot.reset_recoveries)],
+        );
+
+        text
+    }
+}
+
+/// Writes a metric with its help and type, one sample per set of labels.
+fn write_metric(text: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, impl fmt::Display)]) {
+    // Writing to a String can't fail.
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(text, "{} {}", name, value);
+        } else {
+            let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
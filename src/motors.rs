@@ -0,0 +1,524 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+
+use crate::metrics::Metrics;
+use crate::rover::{Rover, RAMP_PERIOD};
+use crate::schedule::Scheduler;
+use crate::{execute_command, stop_rover, RoverCommand, RoverResponse};
+
+/// Commands of the clients waiting for the motor task, beyond which new
+/// ones are dropped, see `Motors::execute()`.
+const QUEUE_CAPACITY: usize = 32;
+/// How often the command watchdog checks when the last command was
+/// received.
+const WATCHDOG_PERIOD: Duration = Duration::from_millis(100);
+
+/// What the motor task does next.
+enum Job {
+    Command(CommandJob),
+    /// Runs on the rover for code without a command of its own, e.g. the
+    /// telemetry or the HTTP endpoints, see `Motors::with_rover()`.
+    Access(Box<dyn FnOnce(&mut Rover) + Send>),
+}
+
+/// A command for the motor task, and where to send its response.
+#[derive(Debug)]
+struct CommandJob {
+    addr: SocketAddr,
+    command: RoverCommand,
+    /// Whether the command comes from the controller, which keeps the rover
+    /// going.
+    feed_watchdog: bool,
+    sent: Instant,
+    response: oneshot::Sender<(Option<RoverResponse>, Timing)>,
+}
+
+/// How long the motor task took with a command.
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    /// Waiting for the motor task to get to it.
+    pub queued: Duration,
+    /// Applying it, I2C transfers included.
+    pub applied: Duration,
+}
+
+/// Sends the commands of the clients to the motor task, which owns the
+/// rover and applies them one after the other on its own thread, so that
+/// slow I2C transfers don't hold the connections back. It also ramps the
+/// motors and runs the command watchdog in between.
+///
+/// The commands stopping the motors, those the server applies by itself
+/// (scheduled or replayed) and the other accesses to the rover skip the
+/// queue of the clients' commands, and are never dropped. Their lane is
+/// unbounded since each of them is waited for before sending the next one.
+#[derive(Clone, Debug)]
+pub struct Motors {
+    queue: mpsc::Sender<Job>,
+    priority: mpsc::UnboundedSender<Job>,
+}
+
+impl Motors {
+    /// Starts the motor task with `rover`. The motors are always ramped and
+    /// the watchdog always checked, since both can be enabled at runtime,
+    /// see `DriveParams` and `Tunables`.
+    pub fn spawn(rover: Rover, scheduler: Arc<Scheduler>, metrics: Arc<Metrics>) -> Self {
+        let (task, motors) = MotorTask::new(rover, scheduler, metrics);
+
+        std::thread::Builder::new()
+            .name("motors".to_string())
+            .spawn(move || {
+                // Commands start background tasks (e.g. DriveFor stopping the
+                // rover), which run along with the motor task.
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("unable to start the motor task runtime");
+
+                runtime.block_on(task.run());
+            })
+            .expect("unable to start the motor task thread");
+
+        motors
+    }
+
+    /// Has the motor task execute `command` of a client, waiting for its
+    /// response from a blocking thread, along with how long it took unless
+    /// it was dropped.
+    ///
+    /// The command is dropped if too many are waiting already, the motors
+    /// being unable to keep up, unless it stops them, and rejected if the
+    /// motor task stopped.
+    pub fn execute(
+        &self,
+        addr: SocketAddr,
+        command: RoverCommand,
+        feed_watchdog: bool,
+    ) -> (Option<RoverResponse>, Option<Timing>) {
+        match self.send(addr, command, feed_watchdog) {
+            Ok(receiver) => Self::wait(addr, receiver),
+            Err(response) => (Some(response), None),
+        }
+    }
+
+    /// Has the motor task execute `command` on behalf of `addr`, without its
+    /// client, e.g. once scheduled. Never dropped.
+    pub async fn apply(&self, addr: SocketAddr, command: RoverCommand) -> Option<RoverResponse> {
+        let (response, receiver) = oneshot::channel();
+        let job = CommandJob { addr, command, feed_watchdog: false, sent: Instant::now(), response };
+
+        if self.priority.send(Job::Command(job)).is_err() {
+            return Some(task_stopped());
+        }
+
+        match receiver.await {
+            Ok((response, _)) => response,
+            Err(_) => Some(task_stopped()),
+        }
+    }
+
+    /// Runs `f` on the rover from the motor task.
+    pub async fn with_rover<T: Send + 'static>(&self, f: impl FnOnce(&mut Rover) -> T + Send + 'static) -> T {
+        self.access(f).await.expect("the motor task stopped")
+    }
+
+    /// Same as `with_rover()`, from a blocking thread.
+    pub fn with_rover_blocking<T: Send + 'static>(&self, f: impl FnOnce(&mut Rover) -> T + Send + 'static) -> T {
+        futures::executor::block_on(self.with_rover(f))
+    }
+
+    fn access<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Rover) -> T + Send + 'static,
+    ) -> oneshot::Receiver<T> {
+        let (result, receiver) = oneshot::channel();
+
+        // Dropping `result` along with the job tells the motor task stopped.
+        let _ = self.priority.send(Job::Access(Box::new(move |rover| {
+            let _ = result.send(f(rover));
+        })));
+
+        receiver
+    }
+
+    /// Sends `command` to the motor task, returning where its response will
+    /// come from, see `execute()`.
+    fn send(
+        &self,
+        addr: SocketAddr,
+        command: RoverCommand,
+        feed_watchdog: bool,
+    ) -> Result<oneshot::Receiver<(Option<RoverResponse>, Timing)>, RoverResponse> {
+        let (response, receiver) = oneshot::channel();
+        let stops = command.stops();
+        let job = Job::Command(CommandJob { addr, command, feed_watchdog, sent: Instant::now(), response });
+        let sent = if stops {
+            self.priority.send(job).map_err(|e| TrySendError::Closed(e.0))
+        } else {
+            self.queue.try_send(job)
+        };
+
+        match sent {
+            Ok(()) => Ok(receiver),
+            Err(TrySendError::Full(Job::Command(job))) => {
+                warn!("dropped {:?} from {}: {} commands are waiting for the motors", job.command, addr, QUEUE_CAPACITY);
+
+                Err(RoverResponse::Error {
+                    code: "BUSY",
+                    message: "too many commands waiting for the motors, dropped".to_string(),
+                })
+            },
+            Err(TrySendError::Full(Job::Access(_))) => unreachable!("only commands are queued"),
+            Err(TrySendError::Closed(_)) => {
+                error!("unable to apply a command from {}: the motor task stopped", addr);
+
+                Err(task_stopped())
+            },
+        }
+    }
+
+    fn wait(
+        addr: SocketAddr,
+        receiver: oneshot::Receiver<(Option<RoverResponse>, Timing)>,
+    ) -> (Option<RoverResponse>, Option<Timing>) {
+        match futures::executor::block_on(receiver) {
+            Ok((response, timing)) => (response, Some(timing)),
+            // The motor task panicked while applying the command.
+            Err(_) => {
+                error!("the motor task stopped while applying a command from {}", addr);
+
+                (Some(task_stopped()), None)
+            },
+        }
+    }
+}
+
+fn task_stopped() -> RoverResponse {
+    RoverResponse::Error {
+        code: "MOTORS_STOPPED",
+        message: "the motor task stopped, restart the server".to_string(),
+    }
+}
+
+/// The motor task, owning the rover. `run()` drives it from the channels
+/// and the timers, the tests step it instead.
+struct MotorTask {
+    rover: Rover,
+    scheduler: Arc<Scheduler>,
+    metrics: Arc<Metrics>,
+    /// For the background tasks started by the commands.
+    motors: Motors,
+    queue: mpsc::Receiver<Job>,
+    priority: mpsc::UnboundedReceiver<Job>,
+    /// When the last command stopping the motors was sent, which supersedes
+    /// the commands driving them queued before it.
+    last_stop: Option<Instant>,
+}
+
+impl MotorTask {
+    fn new(rover: Rover, scheduler: Arc<Scheduler>, metrics: Arc<Metrics>) -> (Self, Motors) {
+        let (queue, queue_receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let (priority, priority_receiver) = mpsc::unbounded_channel();
+        let motors = Motors { queue, priority };
+        let task = MotorTask {
+            rover,
+            scheduler,
+            metrics,
+            motors: motors.clone(),
+            queue: queue_receiver,
+            priority: priority_receiver,
+            last_stop: None,
+        };
+
+        (task, motors)
+    }
+
+    async fn run(mut self) {
+        let mut ramp_interval = tokio::time::interval(RAMP_PERIOD);
+        let mut watchdog_interval = tokio::time::interval(WATCHDOG_PERIOD);
+
+        loop {
+            tokio::select! {
+                // The priority jobs first, whatever is queued.
+                biased;
+
+                Some(job) = self.priority.recv() => self.handle(job, false),
+                // The task holds a sender, so the queue never closes.
+                Some(job) = self.queue.recv() => self.handle(job, true),
+                _ = ramp_interval.tick() => self.ramp(),
+                _ = watchdog_interval.tick() => self.check_watchdog(),
+            }
+            self.show_state();
+        }
+    }
+
+    /// Handles the next job waiting, the priority ones first, returning
+    /// whether there was one.
+    #[cfg(test)]
+    fn step(&mut self) -> bool {
+        use futures::FutureExt;
+
+        if let Some(Some(job)) = self.priority.recv().now_or_never() {
+            self.handle(job, false);
+        } else if let Some(Some(job)) = self.queue.recv().now_or_never() {
+            self.handle(job, true);
+        } else {
+            return false;
+        }
+        self.show_state();
+
+        true
+    }
+
+    fn handle(&mut self, job: Job, queued: bool) {
+        let job = match job {
+            Job::Command(job) => job,
+            Job::Access(f) => return f(&mut self.rover),
+        };
+        let started = Instant::now();
+
+        if queued && job.command.drives() && self.last_stop.is_some_and(|stop| job.sent < stop) {
+            debug!("{:?} from {} superseded by a stop sent after it", job.command, job.addr);
+
+            let superseded = RoverResponse::Error {
+                code: "SUPERSEDED",
+                message: "superseded by a stop sent after it".to_string(),
+            };
+            let _ = job.response.send((Some(superseded), Timing { queued: started - job.sent, applied: Duration::ZERO }));
+
+            return;
+        }
+        if job.command.stops() {
+            self.last_stop = Some(job.sent);
+        }
+        if job.feed_watchdog {
+            self.rover.feed_watchdog();
+        }
+
+        let response = execute_command(job.addr, job.command, &mut self.rover, &self.scheduler, &self.motors);
+        let timing = Timing { queued: started - job.sent, applied: started.elapsed() };
+
+        // The client may be gone already.
+        let _ = job.response.send((response, timing));
+    }
+
+    fn ramp(&mut self) {
+        if let Err(e) = self.rover.tick() {
+            error!("unable to ramp the motors: {}, stopping the rover", e);
+            stop_rover(&mut self.rover);
+        }
+    }
+
+    /// Keeps the status LED up to date with whatever changed the state of the
+    /// rover, see `Rover::show_state()`.
+    fn show_state(&mut self) {
+        if let Err(e) = self.rover.show_state() {
+            warn!("unable to show the state of the rover: {}", e);
+        }
+    }
+
+    fn check_watchdog(&mut self) {
+        match self.rover.check_watchdog() {
+            Ok(true) => self.metrics.watchdog_stopped(),
+            Ok(false) => {},
+            Err(e) => error!("unable to stop the rover: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    use futures::FutureExt;
+    use linux_embedded_hal::i2cdev::linux::LinuxI2CError;
+    use pwm_pca9685::Channel;
+
+    use super::*;
+    use crate::backend::{MockBackend, MotorBackend};
+    use crate::config::RoverConfig;
+    use crate::error::RoverError;
+    use crate::RoverMotorId;
+
+    /// Records the duty cycles written, in order.
+    #[derive(Clone, Debug, Default)]
+    struct Recording {
+        mock: MockBackend,
+        duty_cycles: Arc<Mutex<Vec<u16>>>,
+    }
+
+    impl MotorBackend for Recording {
+        fn set_pwm_duty_cycle(&mut self, channel: Channel, pulse: u16) -> Result<(), RoverError> {
+            self.duty_cycles.lock().unwrap().push(pulse);
+            self.mock.set_pwm_duty_cycle(channel, pulse)
+        }
+
+        fn set_off_count(&mut self, channel: Channel, off: u16) -> Result<(), RoverError> {
+            self.mock.set_off_count(channel, off)
+        }
+
+        fn set_level(&mut self, channel: Channel, value: u16) -> Result<(), RoverError> {
+            self.mock.set_level(channel, value)
+        }
+
+        fn read_channel(&self, channel: Channel) -> Result<(u16, u16), LinuxI2CError> {
+            self.mock.read_channel(channel)
+        }
+    }
+
+    /// A task whose rover only has the left and right motors, recording the
+    /// duty cycles written to them.
+    fn task(configure: impl FnOnce(&mut Rover)) -> (MotorTask, Motors, Arc<Mutex<Vec<u16>>>) {
+        let config = RoverConfig::default();
+        let backend = Recording::default();
+        let backends: BTreeMap<_, _> = config.boards().into_keys().map(|id| (id.to_string(), backend.clone())).collect();
+        let mut rover = Rover::with_backends(backends, &config);
+
+        configure(&mut rover);
+        backend.duty_cycles.lock().unwrap().clear();
+
+        let (task, motors) = MotorTask::new(rover, Arc::new(Scheduler::default()), Arc::new(Metrics::default()));
+
+        (task, motors, backend.duty_cycles)
+    }
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:4242".parse().unwrap()
+    }
+
+    fn drive(linear: i16) -> RoverCommand {
+        RoverCommand::Drive { linear, angular: 0 }
+    }
+
+    fn response(receiver: oneshot::Receiver<(Option<RoverResponse>, Timing)>) -> Option<RoverResponse> {
+        receiver.now_or_never().unwrap().unwrap().0
+    }
+
+    #[tokio::test]
+    async fn commands_apply_in_order() {
+        let (mut task, motors, duty_cycles) = task(|_| {});
+        let first = motors.send(addr(), drive(30), true).unwrap();
+        let second = motors.send(addr(), drive(60), true).unwrap();
+
+        while task.step() {}
+
+        assert!(response(first).is_none());
+        assert!(response(second).is_none());
+        assert_eq!(*duty_cycles.lock().unwrap(), vec![30, 30, 60, 60]);
+    }
+
+    #[tokio::test]
+    async fn stops_jump_the_queue() {
+        let (mut task, motors, duty_cycles) = task(|_| {});
+        let driven = motors.send(addr(), drive(30), true).unwrap();
+        let stopped = motors.send(addr(), RoverCommand::Neutral, true).unwrap();
+        // Still driven, since it was sent after the stop.
+        let driven_again = motors.send(addr(), drive(60), true).unwrap();
+
+        assert!(task.step());
+        assert!(response(stopped).is_none());
+        assert_eq!(*duty_cycles.lock().unwrap(), vec![0, 0]);
+
+        while task.step() {}
+
+        assert!(matches!(response(driven), Some(RoverResponse::Error { code: "SUPERSEDED", .. })));
+        assert!(response(driven_again).is_none());
+        assert_eq!(*duty_cycles.lock().unwrap(), vec![0, 0, 60, 60]);
+    }
+
+    #[tokio::test]
+    async fn stops_are_never_dropped() {
+        let (mut task, motors, _) = task(|_| {});
+        let queued: Vec<_> = (0..QUEUE_CAPACITY).map(|_| motors.send(addr(), drive(30), true).unwrap()).collect();
+
+        assert!(matches!(motors.send(addr(), drive(30), true), Err(RoverResponse::Error { code: "BUSY", .. })));
+
+        let stop = RoverCommand::MotorStop { motor: RoverMotorId::Left, brake: false };
+        let stopped = motors.send(addr(), stop, true).unwrap();
+
+        while task.step() {}
+
+        assert!(response(stopped).is_none());
+        assert!(queued.into_iter().all(|driven| matches!(response(driven), Some(RoverResponse::Error { code: "SUPERSEDED", .. }))));
+    }
+
+    #[tokio::test]
+    async fn ticks_ramp_the_motors() {
+        let (mut task, motors, duty_cycles) = task(|rover| {
+            for motor in rover.motors_mut() {
+                motor.max_delta_per_tick = 20;
+            }
+        });
+        let driven = motors.send(addr(), drive(50), true).unwrap();
+
+        assert!(task.step());
+        assert!(response(driven).is_none());
+
+        for _ in 0..3 {
+            task.ramp();
+        }
+
+        assert_eq!(*duty_cycles.lock().unwrap(), vec![20, 20, 40, 40, 50, 50]);
+    }
+
+    #[tokio::test]
+    async fn the_watchdog_stops_the_rover() {
+        let (mut task, motors, duty_cycles) = task(|rover| rover.watchdog_timeout = Some(Duration::ZERO));
+        let driven = motors.send(addr(), drive(50), true).unwrap();
+
+        assert!(task.step());
+        assert!(response(driven).is_none());
+
+        task.check_watchdog();
+
+        assert_eq!(*duty_cycles.lock().unwrap(), vec![50, 50, 0, 0]);
+        assert!(task.rover.motors().all(|motor| motor.is_stopped()));
+    }
+
+    #[tokio::test]
+    async fn accesses_jump_the_queue() {
+        let (mut task, motors, _) = task(|_| {});
+        let driven = motors.send(addr(), drive(50), true).unwrap();
+        let speed = motors.access(|rover| rover.left_motor.speed());
+
+        assert!(task.step());
+        assert_eq!(speed.now_or_never(), Some(Ok(0)));
+
+        assert!(task.step());
+        assert!(response(driven).is_none());
+    }
+
+    #[tokio::test]
+    async fn emergency_stops_jump_the_queue() {
+        let (mut task, motors, duty_cycles) = task(|rover| rover.latch_emergency_stop = true);
+        let driven = motors.send(addr(), drive(30), true).unwrap();
+        let stopped = motors.access(Rover::emergency_stop);
+
+        while task.step() {}
+
+        assert!(matches!(stopped.now_or_never(), Some(Ok(Ok(())))));
+        assert!(matches!(response(driven), Some(RoverResponse::Error { code: "EMERGENCY_STOPPED", .. })));
+        assert!(duty_cycles.lock().unwrap().iter().all(|duty_cycle| *duty_cycle == 0));
+    }
+
+    #[test]
+    fn commands_are_rejected_once_the_motor_task_stopped() {
+        let (task, motors) = MotorTask::new(
+            Rover::mock(&RoverConfig::default()),
+            Arc::new(Scheduler::default()),
+            Arc::new(Metrics::default()),
+        );
+
+        drop(task);
+
+        let command = RoverCommand::MotorStop { motor: RoverMotorId::Left, brake: false };
+        let (response, timing) = motors.execute(addr(), command, true);
+
+        assert!(matches!(response, Some(RoverResponse::Error { code: "MOTORS_STOPPED", .. })));
+        assert!(timing.is_none());
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+
+use crate::error::RoverError;
+
+/// An output on a PCA9685 channel, such as a motor, a servo or an LED,
+/// set with a value normalized whatever the output, so that they can all be
+/// driven alike by id, see `Rover::output_mut()`.
+///
+/// Values go from -1.0 to 1.0 for the outputs going either way (full speed
+/// backward to forward, full left to right), and from 0.0 to 1.0 for the
+/// other ones (off to full brightness), out of range values being clamped.
+pub trait PwmOutput: fmt::Debug {
+    /// Sets the output to `value`, the output type mapping it to its own
+    /// unit (speed, angle, level).
+    fn set_value(&mut self, value: f32) -> Result<(), RoverError>;
+
+    /// The value last set.
+    fn value(&self) -> f32;
+
+    /// Puts the output in its neutral state, e.g. stopped or centered.
+    fn neutral(&mut self) -> Result<(), RoverError>;
+
+    /// Writes the state of the output again, e.g. once its board was reset.
+    fn rewrite(&mut self) -> Result<(), RoverError> {
+        self.set_value(self.value())
+    }
+}
+
+/// Maps a `value` clamped to -1.0..=1.0 to -`max`..=`max`, rounded.
+pub fn scale(value: f32, max: i32) -> i32 {
+    (value.clamp(-1.0, 1.0) * max as f32).round() as i32
+}
@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Step, StepDrive};
+
+/// Predefined routines to check the drivetrain and the trims with, see
+/// `RoverCommand::TestPattern`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatternKind {
+    /// Drive along the four sides of a square, turning right in place at
+    /// each corner.
+    Square,
+    /// Drive a loop to the right, then one to the left.
+    FigureEight,
+}
+
+/// Sizes and speeds of the test patterns, see `RoverConfig::pattern_speed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PatternSettings {
+    pub speed: i16,
+    pub side_ms: u32,
+    pub turn_ms: u32,
+    pub loop_ms: u32,
+}
+
+impl PatternSettings {
+    /// The steps of the `kind` pattern, run as a `Sequence`.
+    pub fn steps(&self, kind: PatternKind) -> Vec<Step> {
+        let step = |linear, angular, duration_ms| Step {
+            drive: StepDrive { linear, angular },
+            duration_ms,
+        };
+
+        match kind {
+            PatternKind::Square => (0..4)
+                .flat_map(|_| vec![step(self.speed, 0, self.side_ms), step(0, self.speed, self.turn_ms)])
+                .collect(),
+            PatternKind::FigureEight => vec![
+                step(self.speed, self.speed / 2, self.loop_ms),
+                step(self.speed, -self.speed / 2, self.loop_ms),
+            ],
+        }
+    }
+}
+
+/// How the last test pattern went, as reported in the telemetry and the
+/// status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct PatternRun {
+    pub kind: PatternKind,
+    pub state: PatternState,
+    /// Generation of the rover the pattern runs at, see
+    /// `Rover::generation()`.
+    #[serde(skip)]
+    pub generation: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatternState {
+    Running,
+    Done,
+    /// A newer command took over.
+    Cancelled,
+    /// A step was rejected or failed, the rover being stopped.
+    Failed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: PatternSettings = PatternSettings {
+        speed: 40,
+        side_ms: 1000,
+        turn_ms: 700,
+        loop_ms: 5000,
+    };
+
+    #[test]
+    fn squares_turn_at_each_corner() {
+        let steps = SETTINGS.steps(PatternKind::Square);
+        let drives: Vec<_> = steps.iter().map(|step| (step.drive.linear, step.drive.angular, step.duration_ms)).collect();
+
+        assert_eq!(drives.len(), 8);
+        for corner in drives.chunks(2) {
+            assert_eq!(corner, [(40, 0, 1000), (0, 40, 700)]);
+        }
+    }
+
+    #[test]
+    fn figure_eights_loop_both_ways() {
+        let steps = SETTINGS.steps(PatternKind::FigureEight);
+        let drives: Vec<_> = steps.iter().map(|step| (step.drive.linear, step.drive.angular, step.duration_ms)).collect();
+
+        assert_eq!(drives, [(40, 20, 5000), (40, -20, 5000)]);
+    }
+}
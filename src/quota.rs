@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Per-client command quotas, reset at the end of each window.
+///
+/// Clients are identified by their IP address so that reconnecting does
+/// not grant a fresh quota.
+#[derive(Debug)]
+pub struct Quotas {
+    /// Commands allowed per window, `None` when quotas are disabled.
+    limit: Option<u32>,
+    window: Duration,
+    clients: Mutex<HashMap<IpAddr, Window>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    used: u32,
+}
+
+impl Quotas {
+    pub fn new(limit: Option<u32>, window: Duration) -> Self {
+        Quotas {
+            limit,
+            window,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Consumes one command from the quota of `ip`.
+    ///
+    /// Returns the number of commands left in the current window, or `None`
+    /// if the quota is exhausted (or `Some(u32::MAX)` when disabled).
+    pub fn consume(&self, ip: IpAddr) -> Option<u32> {
+        self.consume_at(ip, Instant::now())
+    }
+
+    fn consume_at(&self, ip: IpAddr, now: Instant) -> Option<u32> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Some(u32::MAX),
+        };
+        let mut clients = self.clients.lock().unwrap();
+
+        // Forget about the clients whose window is over.
+        clients.retain(|_, window| now.duration_since(window.start) < self.window);
+
+        let window = clients.entry(ip).or_insert(Window { start: now, used: 0 });
+
+        if window.used >= limit {
+            return None;
+        }
+
+        window.used += 1;
+
+        Some(limit - window.used)
+    }
+
+    /// Number of commands `ip` can still send in its current window, `None`
+    /// when quotas are disabled.
+    pub fn remaining(&self, ip: IpAddr) -> Option<u32> {
+        self.remaining_at(ip, Instant::now())
+    }
+
+    fn remaining_at(&self, ip: IpAddr, now: Instant) -> Option<u32> {
+        let limit = self.limit?;
+        let used = self.clients
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .filter(|window| now.duration_since(window.start) < self.window)
+            .map_or(0, |window| window.used);
+
+        Some(limit - used)
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotas_reset_with_the_window() {
+        let quotas = Quotas::new(Some(2), Duration::from_secs(60));
+        let ip = IpAddr::from([192, 168, 1, 2]);
+        let other = IpAddr::from([192, 168, 1, 3]);
+        let start = Instant::now();
+
+        assert_eq!(quotas.remaining_at(ip, start), Some(2));
+        assert_eq!(quotas.consume_at(ip, start), Some(1));
+        assert_eq!(quotas.consume_at(ip, start + Duration::from_secs(30)), Some(0));
+        assert_eq!(quotas.consume_at(ip, start + Duration::from_secs(59)), None);
+        assert_eq!(quotas.remaining_at(ip, start + Duration::from_secs(59)), Some(0));
+        // Each client has its own quota.
+        assert_eq!(quotas.consume_at(other, start + Duration::from_secs(59)), Some(1));
+
+        assert_eq!(quotas.remaining_at(ip, start + Duration::from_secs(60)), Some(2));
+        assert_eq!(quotas.consume_at(ip, start + Duration::from_secs(60)), Some(1));
+    }
+
+    #[test]
+    fn disabled_quotas_are_unlimited() {
+        let quotas = Quotas::new(None, Duration::from_secs(60));
+        let ip = IpAddr::from([192, 168, 1, 2]);
+
+        assert_eq!(quotas.consume(ip), Some(u32::MAX));
+        assert_eq!(quotas.remaining(ip), None);
+    }
+}
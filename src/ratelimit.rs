@@ -0,0 +1,188 @@
+use std::time::{Duration, Instant};
+
+/// Limits how many commands a connection applies per window, so that a
+/// client flooding the rover doesn't starve the I2C bus.
+///
+/// Commands beyond the limit are held back until the window ends, as set
+/// by their `Policy`: coalesced, only the latest one of each key being
+/// applied, queued or, for those that change nothing, dropped. Unlike
+/// `Quotas`, this is per connection and meant to smooth bursts out rather
+/// than to cap usage.
+#[derive(Debug)]
+pub struct RateLimiter<K, T> {
+    /// Commands allowed per window, `None` when rate limiting is disabled.
+    limit: Option<u32>,
+    window: Duration,
+    start: Instant,
+    used: u32,
+    /// Held back commands, in the order they must be applied, with the key
+    /// of those that are coalesced.
+    pending: Vec<(Option<K>, T)>,
+    /// When the window the first pending command was held back in ends.
+    flush_at: Option<Instant>,
+}
+
+/// What happens to a command beyond the limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy<K> {
+    /// Applied when the window ends, unless a newer command with the same
+    /// key replaces it.
+    Coalesce(K),
+    /// Applied when the window ends, whatever comes after it. For commands
+    /// changing the state of the rover that can't be coalesced.
+    Queue,
+    /// Dropped, for commands that change nothing.
+    Drop,
+}
+
+/// Why a command was not applied right away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttled {
+    /// See `Policy::Coalesce`.
+    Coalesced,
+    /// See `Policy::Queue`.
+    Queued,
+    Dropped,
+}
+
+impl<K: PartialEq, T> RateLimiter<K, T> {
+    pub fn new(limit: Option<u32>, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            start: Instant::now(),
+            used: 0,
+            pending: Vec::new(),
+            flush_at: None,
+        }
+    }
+
+    /// Returns `command` if it can be applied right away. Otherwise it is
+    /// held back or dropped, as set by its `policy`.
+    ///
+    /// Commands that would be held back beyond the limit are held back as
+    /// long as others are pending, even within the limit, so that they are
+    /// applied in order.
+    pub fn admit(&mut self, policy: Policy<K>, command: T) -> Result<T, Throttled> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(command),
+        };
+
+        self.roll();
+
+        match policy {
+            _ if self.used < limit && self.pending.is_empty() => {
+                self.used += 1;
+
+                Ok(command)
+            },
+            Policy::Coalesce(key) => {
+                self.pending.retain(|(pending, _)| pending.as_ref() != Some(&key));
+                self.hold_back(Some(key), command);
+
+                Err(Throttled::Coalesced)
+            },
+            Policy::Queue => {
+                self.hold_back(None, command);
+
+                Err(Throttled::Queued)
+            },
+            Policy::Drop if self.used < limit => {
+                self.used += 1;
+
+                Ok(command)
+            },
+            Policy::Drop => Err(Throttled::Dropped),
+        }
+    }
+
+    fn hold_back(&mut self, key: Option<K>, command: T) {
+        self.pending.push((key, command));
+        self.flush_at.get_or_insert(self.start + self.window);
+    }
+
+    /// When the held back commands are due, if any.
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.flush_at
+    }
+
+    /// Takes the held back commands once they are due, counting them
+    /// against the current window.
+    pub fn take_due(&mut self) -> Vec<T> {
+        match self.flush_at {
+            Some(flush_at) if Instant::now() >= flush_at => {},
+            _ => return Vec::new(),
+        }
+
+        self.roll();
+        self.flush_at = None;
+
+        let due: Vec<T> = self.pending.drain(..).map(|(_, command)| command).collect();
+
+        self.used += due.len() as u32;
+
+        due
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Starts a new window if the current one is over.
+    fn roll(&mut self) {
+        let now = Instant::now();
+
+        if now.duration_since(self.start) >= self.window {
+            self.start = now;
+            self.used = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesces_beyond_the_limit() {
+        let mut limiter = RateLimiter::new(Some(2), Duration::from_millis(10));
+
+        assert_eq!(limiter.admit(Policy::Coalesce("left"), 1), Ok(1));
+        assert_eq!(limiter.admit(Policy::Coalesce("left"), 2), Ok(2));
+        assert_eq!(limiter.admit(Policy::Coalesce("left"), 3), Err(Throttled::Coalesced));
+        assert_eq!(limiter.admit(Policy::Coalesce("right"), 4), Err(Throttled::Coalesced));
+        assert_eq!(limiter.admit(Policy::Coalesce("left"), 5), Err(Throttled::Coalesced));
+        assert_eq!(limiter.admit(Policy::Drop, 6), Err(Throttled::Dropped));
+        assert!(limiter.take_due().is_empty());
+
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(limiter.take_due(), vec![4, 5]);
+        assert_eq!(limiter.next_flush(), None);
+    }
+
+    #[test]
+    fn queues_in_order_beyond_the_limit() {
+        let mut limiter = RateLimiter::new(Some(1), Duration::from_millis(10));
+
+        assert_eq!(limiter.admit(Policy::Coalesce("motors"), 1), Ok(1));
+        assert_eq!(limiter.admit(Policy::Coalesce("motors"), 2), Err(Throttled::Coalesced));
+        assert_eq!(limiter.admit(Policy::Queue, 3), Err(Throttled::Queued));
+        assert_eq!(limiter.admit(Policy::Queue, 4), Err(Throttled::Queued));
+        assert_eq!(limiter.admit(Policy::Coalesce("motors"), 5), Err(Throttled::Coalesced));
+
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(limiter.take_due(), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut limiter = RateLimiter::new(None, Duration::from_millis(10));
+
+        for i in 0..100 {
+            assert_eq!(limiter.admit(Policy::<()>::Drop, i), Ok(i));
+        }
+    }
+}
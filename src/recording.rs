@@ -0,0 +1,248 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::RoverCommand;
+
+/// A saved recording.
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordingFile {
+    /// How long the recording lasted, the rover stopping at the end of the
+    /// replay.
+    duration_ms: u64,
+    commands: Vec<RecordedCommand>,
+}
+
+/// A command of a saved recording, applied `at_ms` after it started.
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedCommand {
+    at_ms: u64,
+    command: RoverCommand,
+}
+
+/// The commands of a recording, with when to apply them.
+#[derive(Debug)]
+pub struct Replay {
+    pub commands: Vec<(Duration, RoverCommand)>,
+    pub duration: Duration,
+}
+
+/// Records the applied commands to replay them later with the same timing,
+/// e.g. for demos. Recordings are saved as JSON files in a directory.
+#[derive(Debug)]
+pub struct Recorder {
+    directory: PathBuf,
+    recording: Mutex<Option<Recording>>,
+    /// The replay in progress, if any, and the client that started it.
+    replay: Mutex<Option<(SocketAddr, JoinHandle<()>)>>,
+}
+
+#[derive(Debug)]
+struct Recording {
+    name: String,
+    start: Instant,
+    commands: Vec<(Duration, RoverCommand)>,
+}
+
+impl Recorder {
+    pub fn new(directory: PathBuf) -> Self {
+        Recorder {
+            directory,
+            recording: Mutex::new(None),
+            replay: Mutex::new(None),
+        }
+    }
+
+    /// Starts recording under `name`, discarding any recording in progress.
+    pub fn start(&self, name: String) -> Result<(), String> {
+        self.path(&name)?;
+
+        let previous = self.recording.lock().unwrap().replace(Recording {
+            name,
+            start: Instant::now(),
+            commands: Vec::new(),
+        });
+
+        if let Some(previous) = previous {
+            warn!("discarding the unfinished {:?} recording", previous.name);
+        }
+
+        Ok(())
+    }
+
+    /// Records `command` if a recording is in progress.
+    pub fn record(&self, command: &RoverCommand) {
+        if let Some(recording) = &mut *self.recording.lock().unwrap() {
+            recording.commands.push((recording.start.elapsed(), command.clone()));
+        }
+    }
+
+    /// Stops recording and saves the recording.
+    pub fn stop(&self) -> Result<(), String> {
+        let recording = self.recording
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| "not recording, send StartRecording first".to_string())?;
+        let file = RecordingFile {
+            duration_ms: recording.start.elapsed().as_millis() as u64,
+            commands: recording.commands
+                .into_iter()
+                .map(|(at, command)| RecordedCommand { at_ms: at.as_millis() as u64, command })
+                .collect(),
+        };
+        let path = self.path(&recording.name)?;
+
+        fs::create_dir_all(&self.directory)
+            .and_then(|_| fs::write(&path, serde_json::to_vec_pretty(&file).expect("recordings always serialize to JSON")))
+            .map_err(|e| format!("unable to save {:?}: {}", path, e))?;
+        info!("saved {} commands to {:?}", file.commands.len(), path);
+
+        Ok(())
+    }
+
+    /// Loads the `name` recording, rejecting it if any of its commands is
+    /// invalid or can't be replayed, since the file may have been edited.
+    pub fn load(&self, name: &str) -> Result<Replay, String> {
+        let path = self.path(name)?;
+        let data = fs::read(&path).map_err(|e| format!("unable to read the {:?} recording: {}", name, e))?;
+        let file: RecordingFile = serde_json::from_slice(&data)
+            .map_err(|e| format!("invalid {:?} recording: {}", name, e))?;
+
+        for (i, recorded) in file.commands.iter().enumerate() {
+            let valid = match &recorded.command {
+                RoverCommand::RequestControl
+                | RoverCommand::StartRecording { .. }
+                | RoverCommand::StopRecording
+                | RoverCommand::Replay { .. } => Err(format!("{:?} can't be replayed", recorded.command)),
+                command => command.validate(),
+            };
+
+            valid.map_err(|e| format!("invalid {:?} recording, command #{}: {}", name, i, e))?;
+        }
+
+        Ok(Replay {
+            commands: file.commands
+                .into_iter()
+                .map(|recorded| (Duration::from_millis(recorded.at_ms), recorded.command))
+                .collect(),
+            duration: Duration::from_millis(file.duration_ms),
+        })
+    }
+
+    /// Keeps track of the replay `task` of `owner`, aborting any other one.
+    pub fn replaying(&self, owner: SocketAddr, task: JoinHandle<()>) {
+        if let Some((_, previous)) = self.replay.lock().unwrap().replace((owner, task)) {
+            previous.abort();
+        }
+    }
+
+    /// Aborts the replay in progress, if any. The command it was applying
+    /// (if any) still completes.
+    pub fn abort_replay(&self) {
+        if let Some((owner, task)) = self.replay.lock().unwrap().take() {
+            debug!("aborting the replay of {}", owner);
+            task.abort();
+        }
+    }
+
+    /// Aborts the replay in progress if `owner` started it, e.g. when it
+    /// disconnects.
+    pub fn abort_replay_of(&self, owner: SocketAddr) {
+        let mut replay = self.replay.lock().unwrap();
+
+        match &*replay {
+            Some((replay_owner, task)) if *replay_owner == owner => {
+                debug!("aborting the replay of {}", owner);
+                task.abort();
+            },
+            _ => return,
+        }
+        *replay = None;
+    }
+
+    /// Only allows simple names, so that recordings stay in the directory.
+    fn path(&self, name: &str) -> Result<PathBuf, String> {
+        let valid = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        if !valid {
+            return Err(format!(
+                "invalid recording name {:?}, only letters, digits, '-' and '_' are allowed",
+                name,
+            ));
+        }
+
+        Ok(self.directory.join(format!("{}.json", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RoverMotorId;
+
+    fn recorder(test: &str) -> Recorder {
+        Recorder::new(std::env::temp_dir().join(format!("rover-recordings-{}-{}", test, std::process::id())))
+    }
+
+    #[test]
+    fn names_stay_in_the_directory() {
+        let recorder = recorder("names");
+
+        for name in &["", "..", "../demo", "demos/demo", "/demo", "demo.json", "démo"] {
+            assert!(recorder.start(name.to_string()).is_err(), "{:?}", name);
+        }
+        assert!(recorder.start("demo-1_a".to_string()).is_ok());
+        assert_eq!(recorder.path("demo-1_a").unwrap(), recorder.directory.join("demo-1_a.json"));
+    }
+
+    #[test]
+    fn recordings_round_trip() {
+        let recorder = recorder("round-trip");
+        let stop = RoverCommand::MotorStop { motor: RoverMotorId::Left, brake: true };
+
+        recorder.start("demo".to_string()).unwrap();
+        recorder.record(&RoverCommand::Drive { linear: 50, angular: -20 });
+        recorder.record(&stop);
+        recorder.stop().unwrap();
+
+        let replay = recorder.load("demo").unwrap();
+        let commands: Vec<_> = replay.commands.iter().map(|(_, command)| format!("{:?}", command)).collect();
+
+        assert_eq!(commands, vec![format!("{:?}", RoverCommand::Drive { linear: 50, angular: -20 }), format!("{:?}", stop)]);
+        assert!(replay.commands.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert!(replay.commands.iter().all(|(at, _)| *at <= replay.duration));
+        assert!(recorder.stop().is_err());
+
+        fs::remove_dir_all(&recorder.directory).unwrap();
+    }
+
+    #[test]
+    fn invalid_recordings_are_rejected() {
+        let recorder = recorder("invalid");
+        let save = |name: &str, command: &str| {
+            fs::create_dir_all(&recorder.directory).unwrap();
+            fs::write(
+                recorder.path(name).unwrap(),
+                format!(r#"{{"duration_ms":100,"commands":[{{"at_ms":0,"command":{}}}]}}"#, command),
+            ).unwrap();
+        };
+
+        save("valid", r#"{"Drive":{"linear":50,"angular":0}}"#);
+        save("too-fast", r#"{"Drive":{"linear":500,"angular":0}}"#);
+        save("nested", r#"{"Replay":{"name":"valid"}}"#);
+
+        assert!(recorder.load("valid").is_ok());
+        assert!(recorder.load("too-fast").unwrap_err().contains("linear must be between"));
+        assert!(recorder.load("nested").unwrap_err().contains("can't be replayed"));
+        assert!(recorder.load("missing").is_err());
+
+        fs::remove_dir_all(&recorder.directory).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Serialize;
+
+use crate::logs;
+
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ResourceStats {
+    /// Resident set size of the process, if it could be read.
+    rss_bytes: Option<u64>,
+    connections: usize,
+    log_records: usize,
+    log_records_capacity: usize,
+}
+
+/// Keeps track of an open WebSocket connection until dropped.
+pub struct Connection;
+
+impl Connection {
+    /// Opens a connection, unless `limit` connections are open already. The
+    /// server opens them with `Shutdown::connection()`, which also waits for
+    /// them to close.
+    pub fn open(limit: Option<usize>) -> Option<Self> {
+        // Checked and counted at once, for simultaneous connections not to
+        // go over the limit.
+        CONNECTIONS
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |connections| {
+                Some(connections + 1).filter(|connections| limit.is_none_or(|limit| *connections <= limit))
+            })
+            .ok()?;
+
+        Some(Connection)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Reads the resident set size from /proc/self/statm.
+fn rss_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    // The second field is the number of resident pages.
+    let pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    // SAFETY: sysconf() has no preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+/// Number of open WebSocket connections.
+pub fn connections() -> usize {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
+pub fn stats() -> ResourceStats {
+    ResourceStats {
+        rss_bytes: rss_bytes(),
+        connections: connections(),
+        log_records: logs::len(),
+        log_records_capacity: logs::CAPACITY,
+    }
+}
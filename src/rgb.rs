@@ -0,0 +1,124 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auxiliary::AuxOutput;
+use crate::backend::MotorBackend;
+use crate::config::{AuxChannel, RgbLedConfig};
+use crate::error::RoverError;
+use crate::output::PwmOutput;
+
+/// A color, each component from 0 (off) to 255 (full brightness).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+}
+
+/// Parses `r,g,b`, e.g. `255,160,0`.
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let components = s
+            .split(',')
+            .map(|component| component.trim().parse::<u8>().map_err(|e| format!("{:?}: {}", component, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match components[..] {
+            [r, g, b] => Ok(Color { r, g, b }),
+            _ => Err(format!("expected r,g,b from 0 to 255, not {:?}", s)),
+        }
+    }
+}
+
+/// An RGB LED on three PCA9685 channels, e.g. a status indicator, each
+/// component being dimmed like an auxiliary output.
+///
+/// Unlike the other outputs, it isn't put in the neutral pose, so that
+/// stopping the rover keeps its color.
+#[derive(Debug)]
+pub struct RgbLed {
+    red: AuxOutput,
+    green: AuxOutput,
+    blue: AuxOutput,
+    color: Color,
+}
+
+impl RgbLed {
+    /// Creates the LED of `config`, its channels driven by the backends
+    /// `backend` returns.
+    pub fn new(backend: impl Fn() -> Box<dyn MotorBackend>, config: &RgbLedConfig) -> Self {
+        let channel = |channel| AuxChannel {
+            channel,
+            board: config.board.clone(),
+            neutral_level: 0,
+        };
+
+        RgbLed {
+            red: AuxOutput::new(backend(), &channel(config.red)),
+            green: AuxOutput::new(backend(), &channel(config.green)),
+            blue: AuxOutput::new(backend(), &channel(config.blue)),
+            color: Color::default(),
+        }
+    }
+
+    pub fn set_color(&mut self, color: Color) -> Result<(), RoverError> {
+        debug!("RgbLed.set_color({:?}, {:?})", self, color);
+
+        // Unknown until all the writes succeed.
+        self.color = Color::default();
+        for (output, component) in [(&mut self.red, color.r), (&mut self.green, color.g), (&mut self.blue, color.b)] {
+            output.set_value(f32::from(component) / f32::from(u8::MAX))?;
+        }
+        self.color = color;
+
+        Ok(())
+    }
+
+    pub fn color(&self) -> Color {
+        self.color
+    }
+
+    /// Writes the color again, e.g. once its board was reset.
+    pub fn rewrite(&mut self) -> Result<(), RoverError> {
+        self.set_color(self.color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pwm_pca9685::Channel;
+
+    use super::*;
+    use crate::backend::{MockBackend, MotorBackend};
+    use crate::config::DEFAULT_BOARD;
+
+    #[test]
+    fn components_map_to_duty_cycles() {
+        let backend = MockBackend::default();
+        let config = RgbLedConfig { red: 3, green: 4, blue: 5, board: DEFAULT_BOARD.to_string() };
+        let mut led = RgbLed::new(|| Box::new(backend.clone()), &config);
+
+        led.set_color(Color::new(255, 128, 0)).unwrap();
+
+        assert_eq!((led.red.value(), led.green.value(), led.blue.value()), (1.0, 0.5, 0.0));
+        assert_eq!(backend.read_channel(Channel::C5).unwrap(), (0, 0));
+        assert_eq!(led.color(), Color::new(255, 128, 0));
+    }
+
+    #[test]
+    fn colors_parse() {
+        assert_eq!("255, 160,0".parse(), Ok(Color::new(255, 160, 0)));
+        assert!("255,160".parse::<Color>().is_err());
+        assert!("256,0,0".parse::<Color>().is_err());
+    }
+}
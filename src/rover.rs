@@ -1,21 +1,211 @@
-use std::cmp::{max};
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt;
+use std::sync::Arc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
-use linux_embedded_hal::I2cdev;
-use pwm_pca9685::{Address, Channel, Pca9685};
+use linux_embedded_hal::i2cdev::linux::LinuxI2CError;
+use pwm_pca9685::Channel;
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+use crate::auxiliary::AuxOutput;
+use crate::axis::{AxisShaping, Inversion};
+use crate::backend::{I2cHealth, MockBackend, Monitored, MotorBackend, Pca9685Backend};
+use crate::config::{Layout, MotorChannels, RoverConfig, StatusColors};
+use crate::error::RoverError;
+use crate::output::{self, PwmOutput};
+use crate::pattern::{PatternKind, PatternRun, PatternSettings, PatternState};
+use crate::rgb::RgbLed;
+use crate::servo::Servo;
+
+/// Full speed, speeds being percentages of the full power.
+pub const MAX_SPEED: u16 = 100;
+/// How often the motors are ramped toward their target speed, see
+/// `Rover::tick()`.
+pub const RAMP_PERIOD: Duration = Duration::from_millis(20);
+/// Ids of the motors as outputs, as in the configuration (`left` for
+/// `left_channels`...), see `Rover::output_mut()`.
+pub const MOTOR_OUTPUTS: [&str; 4] = ["left", "right", "rear_left", "rear_right"];
+/// Id of the steering servo as an output.
+pub const STEERING_OUTPUT: &str = "steering";
+
+// Bit 4 of the LEDn_ON_H/LEDn_OFF_H registers.
+const FULL_ON_OFF_BIT: u16 = 0x1000;
+pub const CHANNELS: [Channel; 16] = [
+    Channel::C0, Channel::C1, Channel::C2, Channel::C3,
+    Channel::C4, Channel::C5, Channel::C6, Channel::C7,
+    Channel::C8, Channel::C9, Channel::C10, Channel::C11,
+    Channel::C12, Channel::C13, Channel::C14, Channel::C15,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DCMotorDirection {
     Forward,
     Backward,
 }
 
+impl DCMotorDirection {
+    /// Splits a signed speed into its magnitude and direction, negative
+    /// speeds being backward.
+    pub fn split_signed(speed: i16) -> (u16, DCMotorDirection) {
+        let direction = if speed < 0 {
+            DCMotorDirection::Backward
+        } else {
+            DCMotorDirection::Forward
+        };
+
+        (speed.unsigned_abs(), direction)
+    }
+}
+
+/// Side of the rover a motor is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Direction of a spin in place, as seen from above.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TurnDirection {
+    Clockwise,
+    CounterClockwise,
+}
+
+impl TurnDirection {
+    /// Signed (left, right) speeds spinning the rover at `speed`, both
+    /// motors running at the same speed in opposite directions.
+    pub fn speeds(self, speed: u16) -> (i16, i16) {
+        let speed = signed(speed);
+
+        match self {
+            TurnDirection::Clockwise => (speed, -speed),
+            TurnDirection::CounterClockwise => (-speed, speed),
+        }
+    }
+}
+
+/// What the rover is up to, as shown by the status LED, see
+/// `Rover::state()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoverState {
+    Ready,
+    Armed,
+    Fault,
+}
+
+/// What happens to nonzero speeds below a motor's `min_speed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MinSpeedPolicy {
+    /// Run at `min_speed` instead.
+    #[serde(rename = "snap")]
+    SnapUp,
+    /// Stop the motor.
+    #[serde(rename = "stop")]
+    Stop,
+}
+
+/// What happens to a new target speed while a motor is still ramping to
+/// the previous one, kicked or stopped before reversing, see
+/// `DCMotor::set_target_speed()`. Stops always apply right away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TargetPolicy {
+    /// The new target replaces the previous one, ending its kick: the motor
+    /// ramps to it from its current speed.
+    #[serde(rename = "latest")]
+    LatestWins,
+    /// The new target applies once the motor reached the previous one, up
+    /// to 8 targets being queued, the oldest being dropped beyond.
+    #[serde(rename = "queue")]
+    Queue,
+}
+
+impl FromStr for TargetPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(TargetPolicy::LatestWins),
+            "queue" => Ok(TargetPolicy::Queue),
+            _ => Err(format!("unknown target policy {:?}, expected latest or queue", s)),
+        }
+    }
+}
+
+impl FromStr for MinSpeedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snap" => Ok(MinSpeedPolicy::SnapUp),
+            "stop" => Ok(MinSpeedPolicy::Stop),
+            _ => Err(format!("unknown minimum speed policy {:?}, expected snap or stop", s)),
+        }
+    }
+}
+
+/// Most targets a motor queues, see `TargetPolicy::Queue`.
+const MAX_QUEUED_TARGETS: usize = 8;
+
 pub struct DCMotor {
-    pwm: Pca9685<I2cdev>,
+    backend: Box<dyn MotorBackend>,
     control: Channel,
     forward: Channel,
     backward: Channel,
+    current_speed: u16,
+    current_direction: DCMotorDirection,
+    /// Whether `brake()` shorts the motor, until it is stopped or driven
+    /// again. The speed is 0 meanwhile.
+    braked: bool,
+    /// What `tick()` ramps the motor to.
+    target: MotorState,
+    pub target_policy: TargetPolicy,
+    /// The targets to ramp to next, see `TargetPolicy::Queue`.
+    queued: VecDeque<MotorState>,
+    /// Maximum speed change of each `tick()`, 0 to apply speeds right away.
+    pub max_delta_per_tick: u16,
+    /// Maximum speed decrease of each `tick()` if not `max_delta_per_tick`,
+    /// 0 to slow down right away.
+    pub max_decel_per_tick: Option<u16>,
+    /// Overrides `max_delta_per_tick` until a soft stop is over, see
+    /// `soft_stop()`.
+    stop_delta_per_tick: Option<u16>,
+    /// Minimum time between two direction reversals.
+    pub reversal_cooldown: Duration,
+    /// Speed above which `set_speed()` doesn't reverse the motor right
+    /// away: it stops it first, `tick()` reversing it after
+    /// `reversal_dwell`. A zero dwell disables it.
+    pub reversal_guard_speed: u16,
+    pub reversal_dwell: Duration,
+    /// When the motor stopped by the reversal guard can be reversed.
+    reversal_dwell_until: Option<Instant>,
+    /// Duty cycle `set_speed()` starts a stopped motor with for
+    /// `kick_duration` when asked for a lower speed, to overcome static
+    /// friction, `tick()` then settling to that speed. 0 disables it.
+    pub kick_duty: u16,
+    pub kick_duration: Duration,
+    /// When the current kick is over.
+    kick_until: Option<Instant>,
+    /// Percentage added to the commanded speed, see `RoverConfig::left_trim`.
+    /// Changing it takes effect on the next speed change, unlike
+    /// `set_trim()`.
+    pub trim: i8,
+    /// Highest duty cycle written, the trim included, see
+    /// `Rover::set_max_speed()`.
+    max_speed: u16,
+    /// Lowest speed actually turning the motor, see `RoverConfig::min_speed`.
+    pub min_speed: u16,
+    pub min_speed_policy: MinSpeedPolicy,
+    /// Swaps the forward and backward channels, see
+    /// `MotorChannels::inverted`.
+    pub inverted: bool,
+    /// See `is_available()`.
+    available: bool,
+    last_reversal: Option<Instant>,
+    /// Duty cycle and direction last written to the channels, `None` when
+    /// unknown (e.g. after a stop or a failed write) so that the next speed
+    /// change writes them all.
+    written: Option<(u16, DCMotorDirection)>,
 }
 
 impl fmt::Debug for DCMotor {
@@ -24,104 +214,1699 @@ impl fmt::Debug for DCMotor {
             .field("control", &self.control)
             .field("forward", &self.forward)
             .field("backward", &self.backward)
+            .field("speed", &self.speed())
+            .field("direction", &self.direction())
+            .field("braked", &self.braked)
+            .field("backend", &self.backend)
             .finish()
     }
 }
 
 impl DCMotor {
     fn new(
+        backend: Box<dyn MotorBackend>,
         control: Channel,
         forward: Channel,
         backward: Channel,
     ) -> Self {
-        trace!("creating i2c device");
-        let dev = I2cdev::new("/dev/i2c-1").unwrap();
-        let address = Address::default();
-        trace!("creating PCA9685 device");
-        let mut pwm = Pca9685::new(dev, address).unwrap();        
-        // This corresponds to a frequency of ~100 Hz.
-        pwm.set_prescale(240).unwrap();
-        // It is necessary to enable the device.
-        pwm.enable().unwrap();
-
         DCMotor {
-            pwm,
+            backend,
             control,
             forward,
             backward,
+            current_speed: 0,
+            current_direction: DCMotorDirection::Forward,
+            braked: false,
+            target: MotorState { speed: 0, direction: DCMotorDirection::Forward },
+            target_policy: TargetPolicy::LatestWins,
+            queued: VecDeque::new(),
+            max_delta_per_tick: 0,
+            max_decel_per_tick: None,
+            stop_delta_per_tick: None,
+            reversal_cooldown: Duration::from_secs(0),
+            reversal_guard_speed: 0,
+            reversal_dwell: Duration::from_secs(0),
+            reversal_dwell_until: None,
+            kick_duty: 0,
+            kick_duration: Duration::from_secs(0),
+            kick_until: None,
+            trim: 0,
+            max_speed: MAX_SPEED,
+            min_speed: 0,
+            min_speed_policy: MinSpeedPolicy::SnapUp,
+            inverted: false,
+            available: true,
+            last_reversal: None,
+            written: None,
         }
     }
 
-    fn set_pwm_duty_cycle(self: &mut Self, channel: Channel, pulse: u16) {
-        let off = max(
-            0,
-            // 100f32 because we assume the freq is set to 100hz
-            (f32::from(pulse) * (4096f32 / 100f32) - 1f32).round() as u16
-        );
-        
-        trace!("set_channel_on_off({:?}, 0, {})", channel, off);
-        self.pwm.set_channel_on_off(channel, 0, off).unwrap();
+    /// Sets the speed right away, without ramping. Reversing a motor
+    /// running faster than `reversal_guard_speed` stops it instead, the new
+    /// speed being applied by `tick()` once `reversal_dwell` is over.
+    /// Likewise, starting a stopped motor below `kick_duty` kicks it first.
+    ///
+    /// Returns whether the channels were written to, which they are not if
+    /// the motor already runs at this speed in this direction.
+    pub fn set_speed(&mut self, speed: u16, direction: DCMotorDirection) -> Result<bool, RoverError> {
+        debug!("DCMotor.set_speed({:?}, {}, {:?})", self, speed, direction);
+
+        let speed = self.deadbanded(speed);
+
+        self.target = MotorState { speed, direction };
+        self.stop_delta_per_tick = None;
+        self.queued.clear();
+
+        if self.guards_reversal(speed, direction) {
+            if self.reversal_dwell_until.is_none() {
+                debug!("stopping {:?} for {:?} before reversing it", self, self.reversal_dwell);
+                self.reversal_dwell_until = Some(Instant::now() + self.reversal_dwell);
+            }
+
+            return self.apply_speed(0, self.current_direction);
+        }
+        self.reversal_dwell_until = None;
+
+        if self.kicks(speed, direction) {
+            return self.kick(direction);
+        }
+        self.kick_until = None;
+
+        self.apply_speed(speed, direction)
+    }
+
+    /// Whether running at `speed` in `direction` calls for a kick, or for
+    /// going on with the current one, see `kick_duty`.
+    fn kicks(&self, speed: u16, direction: DCMotorDirection) -> bool {
+        if speed == 0 || speed >= self.kick_speed() || self.kick_duration.is_zero() {
+            return false;
+        }
+
+        self.is_stopped() || (self.kick_until.is_some() && direction == self.current_direction)
+    }
+
+    /// `kick_duty`, as long as it is within the maximum speed.
+    fn kick_speed(&self) -> u16 {
+        self.kick_duty.min(self.max_speed)
+    }
+
+    /// Starts a kick in `direction`, or goes on with the current one, see
+    /// `kicks()`.
+    fn kick(&mut self, direction: DCMotorDirection) -> Result<bool, RoverError> {
+        // Commands repeated during the kick don't start it over.
+        if self.is_stopped() {
+            trace!("kicking {:?} for {:?}", self, self.kick_duration);
+            self.kick_until = Some(Instant::now() + self.kick_duration);
+        }
+
+        self.apply_speed(self.kick_speed(), direction)
+    }
+
+    /// Whether running at `speed` in `direction` must wait for the motor to
+    /// stop, see `reversal_guard_speed`.
+    fn guards_reversal(&self, speed: u16, direction: DCMotorDirection) -> bool {
+        if self.reversal_dwell.is_zero() || speed == 0 || direction == self.current_direction {
+            return false;
+        }
+
+        // Still stopping from an earlier reversal.
+        self.current_speed > self.reversal_guard_speed || self.reversal_dwell_until.is_some()
+    }
+
+    /// Sets the speed `tick()` ramps the motor to, or right away if ramping
+    /// is disabled.
+    ///
+    /// While the motor is still on its way to the previous target, the new
+    /// one replaces it or waits for the motor to get there, according to
+    /// `target_policy`.
+    pub fn set_target_speed(&mut self, speed: u16, direction: DCMotorDirection) -> Result<(), RoverError> {
+        debug!("DCMotor.set_target_speed({:?}, {}, {:?})", self, speed, direction);
+
+        if self.target_policy == TargetPolicy::Queue && (!self.is_settled() || !self.queued.is_empty()) {
+            if self.queued.len() == MAX_QUEUED_TARGETS {
+                self.queued.pop_front();
+            }
+            self.queued.push_back(MotorState { speed, direction });
+
+            return Ok(());
         }
 
-        fn set_level(self: &mut Self, channel: Channel, value: u16) {
-        if value == 1 {
-            trace!("set_channel_on_off({:?}, 0, 4095)", channel);
-            self.pwm.set_channel_on_off(channel, 0, 4095).unwrap();
+        self.retarget(speed, direction)
+    }
+
+    /// Same as `set_target_speed()`, whatever the `target_policy`.
+    fn retarget(&mut self, speed: u16, direction: DCMotorDirection) -> Result<(), RoverError> {
+        if self.max_delta_per_tick == 0 && self.decel_per_tick() == 0 {
+            return self.set_speed(speed, direction).map(drop);
+        }
+
+        let speed = self.deadbanded(speed);
+
+        // The kick was on the way to the previous target.
+        if !self.kicks(speed, direction) {
+            self.kick_until = None;
+        }
+        self.target = MotorState { speed, direction };
+        self.stop_delta_per_tick = None;
+
+        Ok(())
+    }
+
+    /// Maximum speed decrease of each `tick()`, see `max_decel_per_tick`.
+    pub fn decel_per_tick(&self) -> u16 {
+        self.max_decel_per_tick.unwrap_or(self.max_delta_per_tick)
+    }
+
+    /// Whether the motor runs at its target, without a kick or a reversal
+    /// dwell going on.
+    fn is_settled(&self) -> bool {
+        self.kick_until.is_none()
+            && self.reversal_dwell_until.is_none()
+            && self.current_speed == self.target.speed
+            && (self.current_speed == 0 || self.current_direction == self.target.direction)
+    }
+
+    /// Moves the speed toward the target by at most `max_delta_per_tick`,
+    /// or `max_decel_per_tick` when slowing down. Reversals ramp down to
+    /// zero before ramping up the other way.
+    ///
+    /// Also completes the reversals stopped by `set_speed()` once their
+    /// dwell is over, and kicks the motors it starts like `set_speed()`
+    /// does, ending the kicks once over. Once the motor got to its target,
+    /// moves on to the next queued one, if any.
+    pub fn tick(&mut self) -> Result<(), RoverError> {
+        if self.is_settled() {
+            if let Some(next) = self.queued.pop_front() {
+                self.retarget(next.speed, next.direction)?;
+            }
+        }
+
+        let target = self.target;
+        // 0 applies the change right away.
+        let step = |delta: u16| if delta == 0 { u16::MAX } else { delta };
+        let delta = self.stop_delta_per_tick.unwrap_or(self.max_delta_per_tick);
+        let decel = step(self.stop_delta_per_tick.unwrap_or_else(|| self.decel_per_tick()));
+
+        if let Some(until) = self.reversal_dwell_until {
+            if Instant::now() < until {
+                return Ok(());
+            }
+
+            self.reversal_dwell_until = None;
+            if delta == 0 {
+                return self.apply_speed(target.speed, target.direction).map(drop);
+            }
+        }
+        if let Some(until) = self.kick_until {
+            if Instant::now() < until {
+                return Ok(());
+            }
+
+            self.kick_until = None;
+            return self.apply_speed(target.speed, target.direction).map(drop);
+        }
+
+        if self.is_stopped() && target.speed == 0 {
+            self.stop_delta_per_tick = None;
+        }
+
+        if self.current_direction != target.direction && self.current_speed > 0 {
+            let speed = self.current_speed.saturating_sub(decel);
+
+            self.apply_speed(speed, self.current_direction).map(drop)
+        } else if self.kicks(target.speed, target.direction) {
+            self.kick(target.direction).map(drop)
+        } else if self.current_speed < target.speed {
+            let speed = self.current_speed.saturating_add(step(delta)).min(target.speed);
+
+            self.apply_speed(speed, target.direction).map(drop)
+        } else if self.current_speed > target.speed {
+            let speed = self.current_speed.saturating_sub(decel).max(target.speed);
+
+            self.apply_speed(speed, target.direction).map(drop)
         } else {
-            trace!("set_channel_on_off({:?}, 0, 0)", channel);
-            self.pwm.set_channel_on_off(channel, 0, 0).unwrap();
+            Ok(())
         }
     }
 
-    pub fn set_speed(self: &mut Self, speed: u16, direction: DCMotorDirection) {
-        debug!("DCMotor.set_speed({:?}, {}, {:?})", self, speed, direction);
-        
-        self.set_pwm_duty_cycle(self.control, speed);
+    /// Only writes the channels that changed, returning whether any did.
+    fn apply_speed(&mut self, speed: u16, direction: DCMotorDirection) -> Result<bool, RoverError> {
+        trace!("DCMotor.apply_speed({:?}, {}, {:?})", self, speed, direction);
 
-        match direction {
-            DCMotorDirection::Forward => {
-                self.set_level(self.forward, 1);
-                self.set_level(self.backward, 0);
-            },
-            DCMotorDirection::Backward => {
-                self.set_level(self.forward, 0);
-                self.set_level(self.backward, 1);
-            },
-        };
+        let duty_cycle = self.trimmed(speed);
+        // Unknown until all the writes succeed.
+        let written = self.written.take();
+
+        if written.map(|(duty_cycle, _)| duty_cycle) != Some(duty_cycle) {
+            self.backend.set_pwm_duty_cycle(self.control, duty_cycle)?;
+        }
+        self.current_speed = speed;
+        if direction != self.current_direction {
+            self.last_reversal = Some(Instant::now());
+        }
+        self.current_direction = direction;
+
+        if written.map(|(_, direction)| direction) != Some(direction) {
+            let (forward, backward) = if self.inverted {
+                (self.backward, self.forward)
+            } else {
+                (self.forward, self.backward)
+            };
+
+            match direction {
+                DCMotorDirection::Forward => {
+                    self.backend.set_level(forward, 1)?;
+                    self.backend.set_level(backward, 0)?;
+                },
+                DCMotorDirection::Backward => {
+                    self.backend.set_level(forward, 0)?;
+                    self.backend.set_level(backward, 1)?;
+                },
+            }
+        }
+        self.written = Some((duty_cycle, direction));
+        // Both direction channels were written since `brake()`.
+        self.braked = false;
+
+        Ok(written != self.written)
+    }
+
+    /// Applies the `min_speed_policy` to speeds below `min_speed`. Zero
+    /// always stops the motor.
+    fn deadbanded(&self, speed: u16) -> u16 {
+        if speed == 0 || speed >= self.min_speed {
+            return speed;
+        }
+
+        match self.min_speed_policy {
+            MinSpeedPolicy::SnapUp => self.min_speed,
+            MinSpeedPolicy::Stop => 0,
+        }
+    }
+
+    /// Duty cycle actually applied for `speed`, within 0..=max_speed so
+    /// that the trim doesn't push it over the cap.
+    fn trimmed(&self, speed: u16) -> u16 {
+        let speed = i32::from(speed) * (100 + i32::from(self.trim)) / 100;
+
+        speed.clamp(0, i32::from(self.max_speed)) as u16
+    }
+
+    /// Highest duty cycle the commands can drive the motor at, the cap and
+    /// the trim included.
+    pub fn speed_ceiling(&self) -> u16 {
+        self.trimmed(self.max_speed)
+    }
+
+    /// Changes the trim, re-applying the current speed with it.
+    pub fn set_trim(&mut self, trim: i8) -> Result<(), RoverError> {
+        debug!("DCMotor.set_trim({:?}, {})", self, trim);
+
+        self.trim = trim;
+        if self.is_stopped() {
+            return Ok(());
+        }
+
+        self.apply_speed(self.current_speed, self.current_direction).map(drop)
+    }
+
+    /// Changes the highest duty cycle written, re-applying the current
+    /// speed under it.
+    fn set_max_speed(&mut self, speed: u16) -> Result<(), RoverError> {
+        self.max_speed = speed.min(MAX_SPEED);
+        if self.is_stopped() {
+            return Ok(());
+        }
+
+        self.apply_speed(self.current_speed, self.current_direction).map(drop)
     }
 
-    pub fn stop(self: &mut Self) {
+    /// Ramps the motor down to a stop in (at most) `ticks` calls to
+    /// `tick()`. Setting another speed cancels it, unless the motor queues
+    /// its targets (see `TargetPolicy::Queue`), which then wait for the stop.
+    ///
+    /// A braked motor has nothing to ramp down, it is released right away
+    /// with `stop()`.
+    pub fn soft_stop(&mut self, ticks: u16) -> Result<(), RoverError> {
+        debug!("DCMotor.soft_stop({:?}, {})", self, ticks);
+
+        self.reversal_dwell_until = None;
+        if self.braked {
+            return self.stop();
+        }
+
+        let speed = self.current_speed;
+
+        self.target.speed = 0;
+        self.stop_delta_per_tick = Some(speed.div_ceil(ticks).max(1));
+        self.kick_until = None;
+        self.queued.clear();
+
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<(), RoverError> {
         debug!("DCMotor.stop({:?})", self);
-        self.set_pwm_duty_cycle(self.control, 0);
+        // Don't ramp back up if the stop fails.
+        self.target.speed = 0;
+        self.queued.clear();
+        self.stop_delta_per_tick = None;
+        self.kick_until = None;
+        self.reversal_dwell_until = None;
+        // Backends may stop the motor in their own way.
+        self.written = None;
+        self.backend.stop(self.control)?;
+        if self.braked {
+            // Otherwise both direction channels stay high, shorting the
+            // motor whatever the duty cycle.
+            self.backend.set_level(self.forward, 0)?;
+            self.backend.set_level(self.backward, 0)?;
+        }
+        self.current_speed = 0;
+        self.braked = false;
+
+        Ok(())
+    }
+
+    /// Actively brakes the motor by driving both direction channels high at
+    /// full duty, shorting its terminals through the driver, where `stop()`
+    /// lets it coast.
+    ///
+    /// Only use this with H-bridge drivers whose both-inputs-high state is a
+    /// short brake (such as the TB6612FNG): on a driver without shoot-through
+    /// protection it shorts the power supply instead. The motor's energy is
+    /// dissipated as heat in the driver.
+    pub fn brake(&mut self) -> Result<(), RoverError> {
+        debug!("DCMotor.brake({:?})", self);
+        self.target.speed = 0;
+        self.stop_delta_per_tick = None;
+        self.kick_until = None;
+        self.written = None;
+        self.backend.set_level(self.forward, 1)?;
+        self.backend.set_level(self.backward, 1)?;
+        self.backend.set_pwm_duty_cycle(self.control, MAX_SPEED)?;
+        self.current_speed = 0;
+        self.braked = true;
+
+        Ok(())
+    }
+
+    /// The speed currently applied, which lags behind the target while
+    /// ramping.
+    pub fn speed(&self) -> u16 {
+        self.current_speed
+    }
+
+    pub fn direction(&self) -> DCMotorDirection {
+        self.current_direction
+    }
+
+    /// Whether the motor doesn't turn, be it coasting or braked.
+    pub fn is_stopped(&self) -> bool {
+        self.current_speed == 0
+    }
+
+    /// Whether the motor is held by `brake()`, its terminals shorted.
+    pub fn is_braked(&self) -> bool {
+        self.braked
+    }
+
+    /// Whether the board of the motor works, see `RoverConfig::allow_degraded`.
+    /// Unavailable motors are only simulated, the commands to them must be
+    /// rejected.
+    pub fn is_available(&self) -> bool {
+        self.available
+    }
+
+    /// How long to wait before the motor can be reversed to `direction`,
+    /// `None` if it can be right away.
+    pub fn reversal_cooldown_remaining(&self, direction: DCMotorDirection) -> Option<Duration> {
+        if direction == self.current_direction {
+            return None;
+        }
+
+        self.last_reversal
+            .map(|last_reversal| last_reversal.elapsed())
+            .filter(|elapsed| *elapsed < self.reversal_cooldown)
+            .map(|elapsed| self.reversal_cooldown - elapsed)
+    }
+
+    /// Reads back the control channel registers to check that the chip
+    /// actually accepted the last `stop()`.
+    fn verify_stopped(&self) -> Result<bool, LinuxI2CError> {
+        let (on, off) = self.backend.read_channel(self.control)?;
+
+        trace!("DCMotor.verify_stopped({:?}): on = {}, off = {}", self, on, off);
+
+        Ok(on == 0 && off == 0)
+    }
+}
+
+/// Raw register values of a PCA9685 channel, as read back from the chip.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelReading {
+    /// Id of the board, see `RoverConfig::boards`.
+    pub board: String,
+    pub channel: u8,
+    pub on: u16,
+    pub off: u16,
+    /// Duty cycle implied by `on` and `off`, in percent.
+    pub duty: f32,
+}
+
+impl ChannelReading {
+    fn new(board: &str, channel: Channel, on: u16, off: u16) -> Self {
+        let duty = if off & FULL_ON_OFF_BIT != 0 {
+            0f32
+        } else if on & FULL_ON_OFF_BIT != 0 {
+            100f32
+        } else {
+            // The output goes high at `on` and low at `off` counts, wrapping
+            // around at 4096.
+            f32::from((off.wrapping_sub(on)) % 4096) * 100f32 / 4096f32
+        };
+
+        ChannelReading {
+            board: board.to_string(),
+            channel: channel as u8,
+            on,
+            off,
+            duty,
+        }
+    }
+}
+
+/// Outcome of `Rover::benchmark_i2c()`.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct I2cBenchmark {
+    pub writes: u32,
+    pub elapsed_ms: f64,
+    pub writes_per_second: f64,
+}
+
+/// Signed speeds as values, see `PwmOutput`. Unlike the motor commands,
+/// nothing checks the reversal cooldown, the outputs being driven one by one.
+impl PwmOutput for DCMotor {
+    fn set_value(&mut self, value: f32) -> Result<(), RoverError> {
+        match DCMotorDirection::split_signed(output::scale(value, i32::from(MAX_SPEED)) as i16) {
+            (0, _) => self.stop(),
+            (speed, direction) => self.set_speed(speed, direction).map(drop),
+        }
+    }
+
+    fn value(&self) -> f32 {
+        f32::from(MotorState::of(self).to_signed()) / f32::from(MAX_SPEED)
+    }
+
+    fn neutral(&mut self) -> Result<(), RoverError> {
+        self.stop()
+    }
+
+    /// Writes the speed applied (rather than the target, while ramping) and
+    /// the brake again.
+    fn rewrite(&mut self) -> Result<(), RoverError> {
+        self.written = None;
+
+        if self.braked {
+            return self.brake();
+        }
+
+        self.apply_speed(self.current_speed, self.current_direction).map(drop)
+    }
+}
+
+/// Speed and direction of a motor, as saved by `Rover::pause()`.
+#[derive(Clone, Copy, Debug)]
+pub struct MotorState {
+    pub speed: u16,
+    pub direction: DCMotorDirection,
+}
+
+impl MotorState {
+    /// What `motor` is doing, or ramping to.
+    pub fn of(motor: &DCMotor) -> Self {
+        motor.target
+    }
+
+    /// What `motor` is doing right now, short of its target while ramping.
+    pub fn current(motor: &DCMotor) -> Self {
+        MotorState {
+            speed: motor.current_speed,
+            direction: motor.current_direction,
+        }
+    }
+
+    /// State of a motor driven at a signed speed, see
+    /// `DCMotorDirection::split_signed()`.
+    pub fn signed(speed: i16) -> Self {
+        let (speed, direction) = DCMotorDirection::split_signed(speed);
+
+        MotorState { speed, direction }
+    }
+
+    /// The signed speed of this state, see `signed()`.
+    pub fn to_signed(self) -> i16 {
+        match self.direction {
+            DCMotorDirection::Forward => signed(self.speed),
+            DCMotorDirection::Backward => -signed(self.speed),
+        }
+    }
+
+    pub fn restore(self, motor: &mut DCMotor) -> Result<(), RoverError> {
+        if self.speed == 0 {
+            motor.stop()
+        } else {
+            motor.set_target_speed(self.speed, self.direction)
+        }
     }
 }
 
+/// What the motors were doing when the rover was paused. With four motors,
+/// the front motor's state is restored to both motors of its side.
+#[derive(Clone, Copy, Debug)]
+pub struct PausedState {
+    pub right: MotorState,
+    pub left: MotorState,
+}
+
 #[derive(Debug)]
 pub struct Rover {
+    /// The right motor, or the front right one with four motors.
     pub right_motor: DCMotor,
     pub left_motor: DCMotor,
+    /// Only with four motors.
+    pub rear_right_motor: Option<DCMotor>,
+    pub rear_left_motor: Option<DCMotor>,
+    /// Only on rovers steering with a servo, see `RoverConfig::steering`.
+    pub steering: Option<Servo>,
+    /// Auxiliary outputs by name, see `RoverConfig::aux`.
+    pub aux: BTreeMap<String, AuxOutput>,
+    /// RGB LEDs by id, see `RoverConfig::rgb_leds`.
+    pub rgb_leds: BTreeMap<String, RgbLed>,
+    /// Id of the RGB LED showing the state, see `show_state()`.
+    status_led: Option<String>,
+    pub status_colors: StatusColors,
+    /// State last shown on the status LED.
+    shown_state: Option<RoverState>,
+    /// Read the motor registers back after each stop to make sure the chip
+    /// accepted it.
+    pub verify_stop: bool,
+    /// While paused, have motor commands update what `resume()` restores
+    /// instead of rejecting them.
+    pub queue_while_paused: bool,
+    /// Have `emergency_stop()` latch until `reset_emergency_stop()`.
+    pub latch_emergency_stop: bool,
+    /// How long `soft_stop()` takes to stop the rover, 0 to stop it right
+    /// away.
+    pub soft_stop_window: Duration,
+    /// See `check_watchdog()`, `None` to disable the watchdog.
+    pub watchdog_timeout: Option<Duration>,
+    /// Highest speed the motors are driven at, see `set_max_speed()`.
+    max_speed: u16,
+    /// Shapes the gamepad sticks of `Axis` commands.
+    pub axis: AxisShaping,
+    /// Flips the operator's inputs, see `RoverCommand::SetSteeringInvert`.
+    pub inversion: Inversion,
+    /// Sizes and speeds of `TestPattern` commands.
+    pub patterns: PatternSettings,
+    /// The last test pattern run, see `start_pattern()`.
+    test_pattern: Option<PatternRun>,
+    /// Each PCA9685 board by id, to read or re-initialize it as a whole.
+    boards: BTreeMap<String, Box<dyn MotorBackend>>,
+    /// Ids of the boards of the configuration left out in degraded mode, see
+    /// `new()`.
+    unavailable_boards: Vec<String>,
+    /// Shared by all the boards.
+    i2c_health: Arc<I2cHealth>,
+    emergency_stopped: bool,
+    paused: Option<PausedState>,
+    /// What the motors were doing before the wiggle test in progress, in the
+    /// order of `motors()`, see `start_wiggle()`.
+    wiggled: Option<Vec<MotorState>>,
+    generation: u64,
+    reset_recoveries: u32,
+    /// Stops `verify_stop` found the chip didn't apply.
+    stop_faults: u32,
+    last_command: Instant,
+    created: Instant,
 }
 
 impl Rover {
-    pub fn new() -> Self {
+    /// Creates a rover driving the PCA9685 boards of `config`, see
+    /// `RoverConfig::boards`, initializing them one after the other as
+    /// `RoverConfig::init_order` says.
+    ///
+    /// If `degraded`, the boards failing to initialize are left out, their
+    /// outputs being unavailable, as long as one of them works.
+    pub fn new(config: &RoverConfig, degraded: bool) -> Result<Self, RoverError> {
+        let mut backends = BTreeMap::new();
+        let mut failure = None;
+
+        let boards = config.boards();
+
+        for (id, delay) in config.board_inits() {
+            let (path, address) = boards[id];
+
+            if delay > Duration::from_millis(0) {
+                debug!("waiting {:?} before initializing the {:?} board", delay, id);
+                // Only while starting, before the rover serves anything.
+                std::thread::sleep(delay);
+            }
+
+            match Pca9685Backend::new(path, address, config.frequency_hz) {
+                Ok(backend) => {
+                    info!("initialized the {:?} board at address {:#04x} on {}", id, address, path);
+                    backends.insert(id.to_string(), backend);
+                },
+                Err(e) => {
+                    let e = RoverError::Board { id: id.to_string(), address, source: Box::new(e) };
+
+                    if !degraded {
+                        return Err(e);
+                    }
+                    warn!("{}, running without its outputs", e);
+                    failure = Some(e);
+                },
+            }
+        }
+
+        match failure {
+            Some(e) if backends.is_empty() => Err(e),
+            _ => Ok(Rover::with_backends(backends, config)),
+        }
+    }
+
+    /// Creates a rover whose motors only exist in memory.
+    pub fn mock(config: &RoverConfig) -> Self {
+        let backends = config
+            .boards()
+            .into_keys()
+            .map(|id| (id.to_string(), MockBackend::default()))
+            .collect();
+
+        Rover::with_backends(backends, config)
+    }
+
+    /// Creates a rover whose outputs are on the boards of `backends` by id,
+    /// each driven by clones of its backend, with the layout and channels of
+    /// `config`.
+    ///
+    /// The outputs on boards missing from `backends` are unavailable: the
+    /// motors are only simulated, see `DCMotor::is_available()`, and the
+    /// steering servo and auxiliary outputs left out.
+    pub fn with_backends<B>(backends: BTreeMap<String, B>, config: &RoverConfig) -> Self
+    where
+        B: MotorBackend + Clone + 'static,
+    {
+        let i2c_health = Arc::new(I2cHealth::default());
+        let backends: BTreeMap<_, _> = backends
+            .into_iter()
+            .map(|(id, backend)| (id, Monitored::new(backend, i2c_health.clone())))
+            .collect();
+        let backend = |board: &str| -> Box<dyn MotorBackend> {
+            match backends.get(board) {
+                Some(backend) => Box::new(backend.clone()),
+                None => Box::new(MockBackend::default()),
+            }
+        };
+        let motor = |channels: &MotorChannels| {
+            let mut motor = DCMotor::new(
+                backend(&channels.board),
+                CHANNELS[usize::from(channels.control)],
+                CHANNELS[usize::from(channels.forward)],
+                CHANNELS[usize::from(channels.backward)],
+            );
+
+            motor.inverted = channels.inverted;
+            motor.max_speed = config.max_speed.min(MAX_SPEED);
+            motor.available = backends.contains_key(&channels.board);
+            motor
+        };
+        let four_motors = config.layout == Layout::FourMotors;
+
         Rover {
-            right_motor: DCMotor::new(
-                Channel::C0,
-                Channel::C1,
-                Channel::C2,
-            ),
-            left_motor: DCMotor::new(
-                Channel::C5,
-                Channel::C3,
-                Channel::C4,
-            ),
+            right_motor: motor(&config.right_channels),
+            left_motor: motor(&config.left_channels),
+            rear_right_motor: four_motors.then(|| motor(&config.rear_right_channels)),
+            rear_left_motor: four_motors.then(|| motor(&config.rear_left_channels)),
+            steering: config.steering
+                .as_ref()
+                .filter(|steering| backends.contains_key(&steering.board))
+                .map(|steering| Servo::new(backend(&steering.board), steering, config.frequency_hz)),
+            aux: config.aux
+                .iter()
+                .filter(|(_, aux)| backends.contains_key(&aux.board))
+                .map(|(name, aux)| (name.clone(), AuxOutput::new(backend(&aux.board), aux)))
+                .collect(),
+            rgb_leds: config.rgb_leds
+                .iter()
+                .filter(|(_, led)| backends.contains_key(&led.board))
+                .map(|(id, led)| (id.clone(), RgbLed::new(|| backend(&led.board), led)))
+                .collect(),
+            status_led: config.status_led.clone(),
+            status_colors: config.status_colors,
+            shown_state: None,
+            boards: backends.keys().map(|id| (id.clone(), backend(id))).collect(),
+            unavailable_boards: config
+                .boards()
+                .into_keys()
+                .filter(|id| !backends.contains_key(*id))
+                .map(str::to_string)
+                .collect(),
+            verify_stop: false,
+            queue_while_paused: false,
+            latch_emergency_stop: false,
+            soft_stop_window: Duration::from_secs(0),
+            watchdog_timeout: None,
+            max_speed: config.max_speed.min(MAX_SPEED),
+            axis: AxisShaping::new(config.axis_deadzone, config.axis_expo),
+            inversion: Inversion {
+                forward: config.invert_forward,
+                steering: config.invert_steering,
+            },
+            patterns: PatternSettings {
+                speed: config.pattern_speed as i16,
+                side_ms: config.pattern_side_ms,
+                turn_ms: config.pattern_turn_ms,
+                loop_ms: config.pattern_loop_ms,
+            },
+            test_pattern: None,
+            i2c_health,
+            emergency_stopped: false,
+            paused: None,
+            wiggled: None,
+            generation: 0,
+            reset_recoveries: 0,
+            stop_faults: 0,
+            last_command: Instant::now(),
+            created: Instant::now(),
         }
     }
 
-    pub fn stop(self: &mut Self) {
-        trace!("Rover.stop({:?})", self);
+    /// Stops the rover, saving the motors' state so that `resume()` can
+    /// restore it. Does nothing if the rover is already paused.
+    pub fn pause(&mut self) -> Result<(), RoverError> {
+        trace!("Rover.pause({:?})", self);
+
+        if self.paused.is_some() {
+            return Ok(());
+        }
+        // Resuming should not go on with the wiggle speed.
+        self.end_wiggle()?;
+
+        let paused = PausedState {
+            right: MotorState::of(&self.right_motor),
+            left: MotorState::of(&self.left_motor),
+        };
+
+        self.stop()?;
+        self.paused = Some(paused);
+
+        Ok(())
+    }
+
+    /// Restores the motors' state saved by `pause()`.
+    pub fn resume(&mut self) -> Result<(), RoverError> {
+        trace!("Rover.resume({:?})", self);
+
+        if let Some(paused) = self.paused.take() {
+            // The maximum speed may have been lowered in the meantime.
+            self.drive(paused.left.to_signed(), paused.right.to_signed())?;
+        }
+
+        Ok(())
+    }
+
+    /// Saves what the motors are doing before a wiggle test changes their
+    /// speed, for `end_wiggle()` to restore.
+    pub fn start_wiggle(&mut self) {
+        self.wiggled = Some(self.motors().map(MotorState::of).collect());
+    }
+
+    /// Restores the motors as they were before the wiggle test in progress,
+    /// if any, whether it ended by itself or was cancelled by another
+    /// command.
+    pub fn end_wiggle(&mut self) -> Result<(), RoverError> {
+        if let Some(states) = self.wiggled.take() {
+            self.motors_mut()
+                .zip(states)
+                .try_for_each(|(motor, state)| state.restore(motor))?;
+        }
+
+        Ok(())
+    }
+
+    /// Error of the last transaction with the PCA9685, `None` if it
+    /// succeeded.
+    pub fn i2c_error(&self) -> Option<String> {
+        self.i2c_health.last_error()
+    }
+
+    /// Number of failed transactions with the PCA9685 so far.
+    pub fn i2c_errors(&self) -> u64 {
+        self.i2c_health.errors()
+    }
+
+    /// Ids of the boards which failed to initialize, in degraded mode.
+    pub fn unavailable_boards(&self) -> &[String] {
+        &self.unavailable_boards
+    }
+
+    /// Names of the motors on the unavailable boards, as in the
+    /// configuration (`left` for `left_channels`...).
+    pub fn unavailable_motors(&self) -> Vec<&'static str> {
+        self.named_motors().filter(|(_, motor)| !motor.is_available()).map(|(name, _)| name).collect()
+    }
+
+    /// The motors of the rover with their output id, see `MOTOR_OUTPUTS`.
+    pub fn named_motors(&self) -> impl Iterator<Item = (&'static str, &DCMotor)> {
+        let motors = [
+            Some(&self.left_motor),
+            Some(&self.right_motor),
+            self.rear_left_motor.as_ref(),
+            self.rear_right_motor.as_ref(),
+        ];
+
+        MOTOR_OUTPUTS.iter().zip(motors).filter_map(|(name, motor)| Some((*name, motor?)))
+    }
+
+    /// The output with `id`: a motor (see `MOTOR_OUTPUTS`), the steering
+    /// servo (`STEERING_OUTPUT`) or an auxiliary output by name.
+    pub fn output_mut(&mut self, id: &str) -> Option<&mut dyn PwmOutput> {
+        match id {
+            "left" => Some(&mut self.left_motor),
+            "right" => Some(&mut self.right_motor),
+            "rear_left" => self.rear_left_motor.as_mut().map(|motor| motor as &mut dyn PwmOutput),
+            "rear_right" => self.rear_right_motor.as_mut().map(|motor| motor as &mut dyn PwmOutput),
+            STEERING_OUTPUT => self.steering.as_mut().map(|servo| servo as &mut dyn PwmOutput),
+            name => self.aux.get_mut(name).map(|output| output as &mut dyn PwmOutput),
+        }
+    }
+
+    /// All the outputs, the motors first.
+    pub fn outputs_mut(&mut self) -> impl Iterator<Item = &mut dyn PwmOutput> {
+        let motors = std::iter::once(&mut self.right_motor)
+            .chain(std::iter::once(&mut self.left_motor))
+            .chain(self.rear_right_motor.as_mut())
+            .chain(self.rear_left_motor.as_mut())
+            .map(|motor| motor as &mut dyn PwmOutput);
+        let steering = self.steering.iter_mut().map(|servo| servo as &mut dyn PwmOutput);
+        let aux = self.aux.values_mut().map(|output| output as &mut dyn PwmOutput);
+
+        motors.chain(steering).chain(aux)
+    }
+
+    /// Number of times a board was re-initialized after a reset, see
+    /// `recover_from_reset()`.
+    pub fn reset_recoveries(&self) -> u32 {
+        self.reset_recoveries
+    }
+
+    /// Number of stops found not applied so far, see `verify_stop`.
+    pub fn stop_faults(&self) -> u32 {
+        self.stop_faults
+    }
+
+    pub fn max_speed(&self) -> u16 {
+        self.max_speed
+    }
+
+    /// Caps the speed of the motors to `speed`, whatever the commands ask,
+    /// slowing down the motors going faster right away.
+    pub fn set_max_speed(&mut self, speed: u16) -> Result<(), RoverError> {
+        trace!("Rover.set_max_speed({:?}, {})", self, speed);
+
+        self.max_speed = speed.min(MAX_SPEED);
+
+        let max_speed = self.max_speed;
+
+        // The trimmed duty cycles, even of the motors already slow enough.
+        self.motors_mut().try_for_each(|motor| motor.set_max_speed(max_speed))?;
+
+        let fastest = self.motors().map(|motor| MotorState::of(motor).speed).max().unwrap_or(0);
+
+        if fastest <= self.max_speed {
+            return Ok(());
+        }
+
+        // Scale all the motors alike, so that the rover keeps turning the
+        // same way.
+        let max_speed = u32::from(self.max_speed);
+
+        for motor in self.motors_mut() {
+            let state = MotorState::of(motor);
+            let speed = u32::from(state.speed) * max_speed / u32::from(fastest);
+
+            MotorState { speed: speed as u16, ..state }.restore(motor)?;
+        }
+
+        Ok(())
+    }
+
+    /// Scales signed speeds down so that none is faster than the maximum
+    /// speed, keeping their ratio so that the rover turns the same way.
+    pub fn capped(&self, left: i16, right: i16) -> (i16, i16) {
+        let fastest = left.unsigned_abs().max(right.unsigned_abs());
+
+        if fastest <= self.max_speed {
+            return (left, right);
+        }
+
+        let scale = |speed: i16| (i32::from(speed) * i32::from(self.max_speed) / i32::from(fastest)) as i16;
+
+        (scale(left), scale(right))
+    }
+
+    /// The motors on `side`, front first.
+    pub fn side_mut(&mut self, side: Side) -> Vec<&mut DCMotor> {
+        let (front, rear) = match side {
+            Side::Left => (&mut self.left_motor, &mut self.rear_left_motor),
+            Side::Right => (&mut self.right_motor, &mut self.rear_right_motor),
+        };
+
+        std::iter::once(front).chain(rear.as_mut()).collect()
+    }
+
+    pub fn motors(&self) -> impl Iterator<Item = &DCMotor> {
+        std::iter::once(&self.right_motor)
+            .chain(std::iter::once(&self.left_motor))
+            .chain(self.rear_right_motor.as_ref())
+            .chain(self.rear_left_motor.as_ref())
+    }
+
+    pub fn motors_mut(&mut self) -> impl Iterator<Item = &mut DCMotor> {
+        std::iter::once(&mut self.right_motor)
+            .chain(std::iter::once(&mut self.left_motor))
+            .chain(self.rear_right_motor.as_mut())
+            .chain(self.rear_left_motor.as_mut())
+    }
+
+    /// The state `resume()` will restore, if paused.
+    pub fn paused_mut(&mut self) -> Option<&mut PausedState> {
+        self.paused.as_mut()
+    }
+
+    /// Number of commands that moved the motors so far, used to cancel
+    /// background actions superseded by a newer command.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn next_generation(&mut self) -> u64 {
+        self.end_pattern(self.generation, PatternState::Cancelled);
+        self.generation += 1;
+
+        self.generation
+    }
+
+    /// Records that a `kind` test pattern runs from now on, at the current
+    /// generation.
+    pub fn start_pattern(&mut self, kind: PatternKind) {
+        self.test_pattern = Some(PatternRun {
+            kind,
+            state: PatternState::Running,
+            generation: self.generation,
+        });
+    }
+
+    /// Records how the test pattern running at `generation` ended, if any.
+    pub fn end_pattern(&mut self, generation: u64, state: PatternState) {
+        if let Some(run) = &mut self.test_pattern {
+            if run.generation == generation && run.state == PatternState::Running {
+                run.state = state;
+            }
+        }
+    }
+
+    pub fn test_pattern(&self) -> Option<PatternRun> {
+        self.test_pattern
+    }
+
+    /// Drives both sides at signed speeds: positive is forward, negative
+    /// backward, the magnitude being clamped to `MAX_SPEED` then capped to
+    /// the maximum speed, see `capped()`.
+    pub fn drive(&mut self, left: i16, right: i16) -> Result<(), RoverError> {
+        trace!("Rover.drive({:?}, {}, {})", self, left, right);
+
+        let clamp = |speed: i16| speed.clamp(-(MAX_SPEED as i16), MAX_SPEED as i16);
+        let (left, right) = self.capped(clamp(left), clamp(right));
+
+        for &(side, speed) in &[(Side::Left, left), (Side::Right, right)] {
+            for motor in self.side_mut(side) {
+                MotorState::signed(speed).restore(motor)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ramps the motors toward their target speed, see `DCMotor::tick()`.
+    pub fn tick(&mut self) -> Result<(), RoverError> {
+        self.motors_mut().try_for_each(DCMotor::tick)
+    }
+
+    /// Records that a command was received, see `check_watchdog()`.
+    pub fn feed_watchdog(&mut self) {
+        self.last_command = Instant::now();
+    }
+
+    /// Same as `feed_watchdog()`, for a command that keeps the rover busy
+    /// for `duration` and will stop it by itself.
+    pub fn feed_watchdog_for(&mut self, duration: Duration) {
+        self.last_command = Instant::now() + duration;
+    }
+
+    /// Stops the rover if it is moving and no command was received for
+    /// `watchdog_timeout`, e.g. because its client froze. Returns whether it
+    /// did.
+    pub fn check_watchdog(&mut self) -> Result<bool, RoverError> {
+        let timeout = match self.watchdog_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(false),
+        };
+        let elapsed = Instant::now().saturating_duration_since(self.last_command);
+
+        // Braked motors are stopped, but shorting them for good would heat
+        // the driver up.
+        if elapsed < timeout || self.motors().all(|motor| motor.is_stopped() && !motor.is_braked()) {
+            return Ok(false);
+        }
+
+        warn!("no command received for {:?}, stopping", elapsed);
+        self.stop()?;
+
+        Ok(true)
+    }
+
+    /// Measures how many channel writes per second the I2C bus sustains by
+    /// writing a zero duty cycle to the (stopped) motors' control channels.
+    ///
+    /// This blocks for the whole benchmark, so keep `writes` small.
+    pub fn benchmark_i2c(&mut self, writes: u32) -> Result<I2cBenchmark, RoverError> {
+        trace!("Rover.benchmark_i2c({:?}, {})", self, writes);
+
+        let start = Instant::now();
+
+        for i in 0..writes {
+            let motor = if i % 2 == 0 { &mut self.right_motor } else { &mut self.left_motor };
+
+            motor.backend.set_pwm_duty_cycle(motor.control, 0)?;
+        }
+
+        let elapsed = start.elapsed();
+
+        // Leave the motors exactly as stopped as they were.
+        self.stop()?;
+
+        Ok(I2cBenchmark {
+            writes,
+            elapsed_ms: elapsed.as_secs_f64() * 1000f64,
+            writes_per_second: f64::from(writes) / elapsed.as_secs_f64(),
+        })
+    }
+
+    /// Reads back the values actually programmed on every channel of every
+    /// board.
+    pub fn read_channels(&self) -> Result<Vec<ChannelReading>, LinuxI2CError> {
+        let mut readings = Vec::new();
+
+        for (id, board) in &self.boards {
+            for channel in &CHANNELS {
+                let (on, off) = board.read_channel(*channel)?;
+
+                readings.push(ChannelReading::new(id, *channel, on, off));
+            }
+        }
+
+        Ok(readings)
+    }
+
+    /// Checks whether the PCA9685 boards went through a power-on reset (e.g.
+    /// after a brownout) and re-initializes those which did.
+    ///
+    /// A reset chip comes back asleep with its prescale lost, so the motors
+    /// stay unresponsive until it is re-initialized. The outputs are all off
+    /// after a reset: the motors remain stopped until the next command,
+    /// while the steering servo and the auxiliary outputs are set back to
+    /// their last angle and level right away.
+    pub fn recover_from_reset(&mut self) -> Result<bool, RoverError> {
+        let mut recovered = false;
+
+        for (id, board) in &mut self.boards {
+            if !board.was_reset()? {
+                continue;
+            }
+
+            self.reset_recoveries += 1;
+            warn!("PCA9685 {:?} reset detected, re-initializing (recovery #{})", id, self.reset_recoveries);
+
+            board.reinitialize()?;
+            recovered = true;
+        }
+
+        if recovered {
+            // The outputs of the board are all off, write their whole state
+            // again.
+            self.outputs_mut().try_for_each(|output| output.rewrite())?;
+            self.rgb_leds.values_mut().try_for_each(RgbLed::rewrite)?;
+        }
+
+        Ok(recovered)
+    }
+
+    /// Stops the rover, latching the stop if `latch_emergency_stop` is set.
+    pub fn emergency_stop(&mut self) -> Result<(), RoverError> {
+        warn!("emergency stop");
+
+        // Latch even if the stop fails, the rover must not start again.
+        self.emergency_stopped = self.latch_emergency_stop;
+        self.neutral()
+    }
+
+    /// `Fault` while emergency stopped or when the last I2C transaction
+    /// failed, `Armed` while the motors run, `Ready` otherwise.
+    pub fn state(&self) -> RoverState {
+        if self.emergency_stopped || self.i2c_error().is_some() {
+            RoverState::Fault
+        } else if !self.motors().all(DCMotor::is_stopped) {
+            RoverState::Armed
+        } else {
+            RoverState::Ready
+        }
+    }
+
+    /// Shows the state of the rover on the status LED in its color, see
+    /// `RoverConfig::status_led`, unless it is shown already.
+    pub fn show_state(&mut self) -> Result<(), RoverError> {
+        let state = self.state();
+        let led = match &self.status_led {
+            Some(id) if self.shown_state != Some(state) => self.rgb_leds.get_mut(id),
+            _ => None,
+        };
+        let led = match led {
+            Some(led) => led,
+            None => return Ok(()),
+        };
+        let color = match state {
+            RoverState::Ready => self.status_colors.ready,
+            RoverState::Armed => self.status_colors.armed,
+            RoverState::Fault => self.status_colors.fault,
+        };
+
+        debug!("showing the {:?} state", state);
+        // Not retried until the state changes again, a failing bus being a
+        // fault already.
+        self.shown_state = Some(state);
+        led.set_color(color)
+    }
+
+    /// How long ago the rover was created, i.e. the server started.
+    pub fn uptime(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// Whether a latched emergency stop prevents the motors from running.
+    pub fn emergency_stopped(&self) -> bool {
+        self.emergency_stopped
+    }
+
+    pub fn reset_emergency_stop(&mut self) {
+        info!("emergency stop reset");
+
+        self.emergency_stopped = false;
+    }
+
+    /// Ramps the motors down to a stop within `soft_stop_window`, rather
+    /// than jolting a fast rover with an instant stop. Any other motor
+    /// command cancels the ramp, which `tick()` does.
+    ///
+    /// Like `stop()`, this forgets about any paused state and cancels
+    /// background actions.
+    pub fn soft_stop(&mut self) -> Result<(), RoverError> {
+        trace!("Rover.soft_stop({:?})", self);
+
+        let ticks = self.soft_stop_window.as_millis() / RAMP_PERIOD.as_millis();
+
+        if ticks == 0 {
+            return self.stop();
+        }
+
+        let ticks = ticks.min(u128::from(u16::MAX)) as u16;
+
+        self.paused = None;
+        self.wiggled = None;
+        self.generation += 1;
+
+        // All the motors are stopped even if releasing a brake fails.
+        self.motors_mut().map(|motor| motor.soft_stop(ticks)).fold(Ok(()), Result::and)
+    }
+
+    /// Stops the motors. This also forgets about any paused state, so
+    /// that a later `resume()` does not start them again, and cancels
+    /// background actions.
+    ///
+    /// All the motors are stopped even if stopping one of them fails, the
+    /// first error being returned.
+    pub fn stop(&mut self) -> Result<(), RoverError> {
+        trace!("Rover.stop({:?})", self);
+
+        self.paused = None;
+        self.wiggled = None;
+        self.generation += 1;
+
+        let result = self.motors_mut().map(DCMotor::stop).fold(Ok(()), Result::and);
+
+        if self.verify_stop {
+            let faults = self.motors()
+                .filter(|motor| match motor.verify_stopped() {
+                    Ok(true) => false,
+                    Ok(false) => {
+                        error!("FAULT: {:?} did not stop", motor);
+                        true
+                    },
+                    Err(e) => {
+                        error!("FAULT: unable to verify {:?} stopped: {}", motor, e);
+                        true
+                    },
+                })
+                .count();
+
+            self.stop_faults += faults as u32;
+        }
+
+        result
+    }
+
+    /// Stops the motors like `stop()` and puts the steering servo and the
+    /// auxiliary outputs in their configured neutral position, a well
+    /// defined safe state rather than whatever the boards powered up with.
+    pub fn neutral(&mut self) -> Result<(), RoverError> {
+        trace!("Rover.neutral({:?})", self);
+
+        // Even if the motors fail to stop, the other outputs must be
+        // neutral.
+        let stopped = self.stop();
+        let steering = self.steering.iter_mut().map(PwmOutput::neutral);
+        let aux = self.aux.values_mut().map(PwmOutput::neutral);
+
+        steering.chain(aux).fold(stopped, Result::and)
+    }
+}
+
+/// Shortcuts for code driving the rover, such as the `Spin` command.
+impl Rover {
+    /// Turns left in place.
+    pub fn turn_left(&mut self, speed: u16) -> Result<(), RoverError> {
+        let (left, right) = TurnDirection::CounterClockwise.speeds(speed);
+
+        self.drive(left, right)
+    }
+
+    /// Turns right in place.
+    pub fn turn_right(&mut self, speed: u16) -> Result<(), RoverError> {
+        let (left, right) = TurnDirection::Clockwise.speeds(speed);
+
+        self.drive(left, right)
+    }
+}
+
+fn signed(speed: u16) -> i16 {
+    speed.min(MAX_SPEED) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A board that always reports a reset, see `Rover::recover_from_reset()`.
+    #[derive(Clone, Debug, Default)]
+    struct ResetBackend(MockBackend);
+
+    impl MotorBackend for ResetBackend {
+        fn set_pwm_duty_cycle(&mut self, channel: Channel, pulse: u16) -> Result<(), RoverError> {
+            self.0.set_pwm_duty_cycle(channel, pulse)
+        }
+
+        fn set_off_count(&mut self, channel: Channel, off: u16) -> Result<(), RoverError> {
+            self.0.set_off_count(channel, off)
+        }
+
+        fn set_level(&mut self, channel: Channel, value: u16) -> Result<(), RoverError> {
+            self.0.set_level(channel, value)
+        }
+
+        fn read_channel(&self, channel: Channel) -> Result<(u16, u16), LinuxI2CError> {
+            self.0.read_channel(channel)
+        }
+
+        fn was_reset(&self) -> Result<bool, LinuxI2CError> {
+            Ok(true)
+        }
+    }
+
+    fn motor() -> DCMotor {
+        DCMotor::new(Box::new(MockBackend::default()), Channel::C0, Channel::C1, Channel::C2)
+    }
+
+    #[test]
+    fn repeated_speeds_are_not_written() {
+        let mut motor = motor();
+
+        assert!(motor.set_speed(50, DCMotorDirection::Forward).unwrap());
+        assert!(!motor.set_speed(50, DCMotorDirection::Forward).unwrap());
+        assert!(motor.set_speed(60, DCMotorDirection::Forward).unwrap());
+    }
+
+    #[test]
+    fn reversals_are_written() {
+        let mut motor = motor();
+
+        motor.set_speed(50, DCMotorDirection::Forward).unwrap();
+
+        assert!(motor.set_speed(50, DCMotorDirection::Backward).unwrap());
+        assert_eq!(motor.backend.read_channel(Channel::C1).unwrap(), (0, 0));
+        assert_eq!(motor.backend.read_channel(Channel::C2).unwrap(), (0, 4095));
+    }
+
+    #[test]
+    fn slow_starts_are_kicked_once() {
+        let mut motor = motor();
+
+        motor.kick_duty = 60;
+        motor.kick_duration = Duration::from_secs(60);
+        motor.set_speed(20, DCMotorDirection::Forward).unwrap();
+        motor.set_speed(25, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 60);
+
+        // The kick is over.
+        motor.kick_until = Some(Instant::now());
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 25);
+
+        // Already turning.
+        motor.set_speed(20, DCMotorDirection::Forward).unwrap();
+
+        assert_eq!(motor.speed(), 20);
+    }
+
+    #[test]
+    fn new_targets_preempt_ramps() {
+        let mut motor = motor();
+
+        motor.max_delta_per_tick = 5;
+        motor.set_target_speed(50, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 10);
+
+        // Ramps down from where it is, not from the previous target.
+        motor.set_target_speed(20, DCMotorDirection::Backward).unwrap();
+        motor.tick().unwrap();
+
+        assert_eq!(MotorState::current(&motor).to_signed(), 5);
+
+        for _ in 0..10 {
+            motor.tick().unwrap();
+        }
+
+        assert_eq!(MotorState::current(&motor).to_signed(), -20);
+    }
+
+    #[test]
+    fn new_targets_preempt_kicks() {
+        let mut motor = motor();
+
+        motor.max_delta_per_tick = 5;
+        motor.kick_duty = 60;
+        motor.kick_duration = Duration::from_secs(60);
+        motor.set_target_speed(20, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 60);
+
+        // Faster than the kick, which is over.
+        motor.set_target_speed(80, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 65);
+    }
+
+    #[test]
+    fn queued_targets_wait_for_the_ramp() {
+        let mut motor = motor();
+
+        motor.target_policy = TargetPolicy::Queue;
+        motor.max_delta_per_tick = 5;
+        motor.set_target_speed(10, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+        motor.set_target_speed(20, DCMotorDirection::Backward).unwrap();
+        motor.set_target_speed(15, DCMotorDirection::Backward).unwrap();
+
+        let mut speeds = Vec::new();
+
+        for _ in 0..8 {
+            motor.tick().unwrap();
+            speeds.push(MotorState::current(&motor).to_signed());
+        }
+
+        assert_eq!(speeds, [10, 5, 0, -5, -10, -15, -20, -15]);
+
+        // Stops don't wait.
+        motor.set_target_speed(50, DCMotorDirection::Forward).unwrap();
+        motor.set_target_speed(30, DCMotorDirection::Forward).unwrap();
+        motor.stop().unwrap();
+        motor.tick().unwrap();
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 0);
+    }
+
+    #[test]
+    fn decelerations_ramp_on_their_own() {
+        let mut motor = motor();
+
+        motor.max_delta_per_tick = 10;
+        motor.max_decel_per_tick = Some(0);
+        motor.set_target_speed(30, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+        motor.tick().unwrap();
+        motor.set_target_speed(5, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 5);
+
+        motor.max_delta_per_tick = 0;
+        motor.max_decel_per_tick = Some(2);
+        motor.set_target_speed(40, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 40);
+
+        motor.set_target_speed(30, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 38);
+    }
+
+    #[test]
+    fn ramped_starts_are_kicked() {
+        let mut motor = motor();
+
+        motor.max_delta_per_tick = 5;
+        motor.max_speed = 50;
+        motor.kick_duty = 60;
+        motor.kick_duration = Duration::from_secs(60);
+        motor.set_target_speed(20, DCMotorDirection::Forward).unwrap();
+        motor.tick().unwrap();
+
+        // Capped to the maximum speed.
+        assert_eq!(motor.speed(), 50);
+
+        motor.kick_until = Some(Instant::now());
+        motor.tick().unwrap();
+
+        assert_eq!(motor.speed(), 20);
+    }
+
+    #[test]
+    fn fast_reversals_stop_first() {
+        let mut motor = motor();
+
+        motor.reversal_guard_speed = 30;
+        motor.reversal_dwell = Duration::from_secs(60);
+        motor.set_speed(80, DCMotorDirection::Forward).unwrap();
+        motor.set_speed(80, DCMotorDirection::Backward).unwrap();
+        motor.tick().unwrap();
+
+        assert!(motor.is_stopped());
+        assert_eq!(motor.direction(), DCMotorDirection::Forward);
+
+        // The dwell is over.
+        motor.reversal_dwell_until = Some(Instant::now());
+        motor.tick().unwrap();
+
+        assert_eq!((motor.speed(), motor.direction()), (80, DCMotorDirection::Backward));
+
+        // Slow enough to reverse right away.
+        motor.set_speed(20, DCMotorDirection::Backward).unwrap();
+        motor.set_speed(20, DCMotorDirection::Forward).unwrap();
+
+        assert_eq!((motor.speed(), motor.direction()), (20, DCMotorDirection::Forward));
+    }
+
+    #[test]
+    fn inverted_motors_swap_directions() {
+        let mut motor = motor();
+
+        motor.inverted = true;
+        motor.set_speed(50, DCMotorDirection::Forward).unwrap();
+
+        assert_eq!(motor.backend.read_channel(Channel::C1).unwrap(), (0, 0));
+        assert_eq!(motor.backend.read_channel(Channel::C2).unwrap(), (0, 4095));
+    }
+
+    #[test]
+    fn speeds_are_written_after_a_stop() {
+        let mut motor = motor();
+
+        motor.set_speed(50, DCMotorDirection::Forward).unwrap();
+        motor.stop().unwrap();
+
+        assert!(motor.set_speed(50, DCMotorDirection::Forward).unwrap());
+    }
+
+    #[test]
+    fn brakes_hold_until_the_next_speed() {
+        let mut motor = motor();
+
+        motor.set_speed(50, DCMotorDirection::Forward).unwrap();
+        motor.brake().unwrap();
+
+        assert!(motor.is_stopped() && motor.is_braked());
+        assert_eq!(motor.backend.read_channel(Channel::C2).unwrap(), (0, 4095));
+
+        motor.set_speed(50, DCMotorDirection::Forward).unwrap();
+
+        assert!(!motor.is_braked());
+        assert_eq!(motor.backend.read_channel(Channel::C2).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn soft_stops_release_the_brakes() {
+        let mut rover = Rover::mock(&RoverConfig::default());
+
+        rover.soft_stop_window = Duration::from_millis(500);
+        rover.left_motor.brake().unwrap();
+        rover.soft_stop().unwrap();
+        rover.tick().unwrap();
+
+        assert!(!rover.left_motor.is_braked());
+        assert_eq!(rover.left_motor.backend.read_channel(Channel::C3).unwrap(), (0, 0));
+        assert_eq!(rover.left_motor.backend.read_channel(Channel::C4).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn resets_restore_the_servo_and_the_aux_outputs() {
+        let config: RoverConfig = toml::from_str(r#"
+            frequency_hz = 50
+
+            [steering]
+            channel = 15
+
+            [aux]
+            headlight = 12
+        "#).unwrap();
+        let mut board = ResetBackend::default();
+        let mut rover = Rover::with_backends(
+            std::iter::once(("default".to_string(), board.clone())).collect(),
+            &config,
+        );
+
+        rover.steering.as_mut().unwrap().set_angle(30).unwrap();
+        rover.aux.get_mut("headlight").unwrap().set_level(40).unwrap();
+
+        let before = (board.read_channel(Channel::C15).unwrap(), board.read_channel(Channel::C12).unwrap());
+
+        // All the outputs are off after a reset.
+        board.set_off_count(Channel::C15, 0).unwrap();
+        board.set_pwm_duty_cycle(Channel::C12, 0).unwrap();
+
+        assert!(rover.recover_from_reset().unwrap());
+        assert_eq!((board.read_channel(Channel::C15).unwrap(), board.read_channel(Channel::C12).unwrap()), before);
+    }
+
+    #[test]
+    fn signed_speeds_round_trip() {
+        assert_eq!(DCMotorDirection::split_signed(60), (60, DCMotorDirection::Forward));
+        assert_eq!(DCMotorDirection::split_signed(-60), (60, DCMotorDirection::Backward));
+        assert_eq!(DCMotorDirection::split_signed(0), (0, DCMotorDirection::Forward));
+
+        for &speed in &[60, -60, 0, MAX_SPEED as i16, -(MAX_SPEED as i16)] {
+            assert_eq!(MotorState::signed(speed).to_signed(), speed);
+        }
+    }
+
+    #[test]
+    fn speeds_are_capped_to_the_max_speed() {
+        let mut rover = Rover::mock(&RoverConfig { max_speed: 30, ..RoverConfig::default() });
+
+        rover.drive(100, -50).unwrap();
+
+        assert_eq!(MotorState::of(&rover.left_motor).to_signed(), 30);
+        assert_eq!(MotorState::of(&rover.right_motor).to_signed(), -15);
+
+        rover.set_max_speed(15).unwrap();
+
+        assert_eq!(MotorState::of(&rover.left_motor).to_signed(), 15);
+        assert_eq!(MotorState::of(&rover.right_motor).to_signed(), -7);
+    }
+
+    #[test]
+    fn trims_are_capped_to_the_max_speed() {
+        let mut rover = Rover::mock(&RoverConfig { max_speed: 30, ..RoverConfig::default() });
+
+        rover.left_motor.trim = 10;
+        rover.drive(100, 100).unwrap();
+
+        assert_eq!(rover.left_motor.written, Some((30, DCMotorDirection::Forward)));
+
+        rover.set_max_speed(60).unwrap();
+        rover.drive(100, 100).unwrap();
+        rover.set_max_speed(50).unwrap();
+
+        assert_eq!(rover.left_motor.written, Some((50, DCMotorDirection::Forward)));
+    }
+
+    #[test]
+    fn turns_spin_in_place() {
+        let mut rover = Rover::mock(&RoverConfig::default());
+
+        rover.turn_left(40).unwrap();
+
+        assert_eq!(MotorState::of(&rover.left_motor).to_signed(), -40);
+        assert_eq!(MotorState::of(&rover.right_motor).to_signed(), 40);
+
+        rover.turn_right(40).unwrap();
+
+        assert_eq!(MotorState::of(&rover.left_motor).to_signed(), 40);
+        assert_eq!(MotorState::of(&rover.right_motor).to_signed(), -40);
+    }
+
+    #[test]
+    fn pausing_a_wiggle_resumes_the_previous_speeds() {
+        let mut rover = Rover::mock(&RoverConfig::default());
+
+        rover.drive(40, 40).unwrap();
+        rover.start_wiggle();
+        rover.left_motor.set_speed(20, DCMotorDirection::Backward).unwrap();
+        rover.pause().unwrap();
+        rover.resume().unwrap();
 
-        self.right_motor.stop();
-        self.left_motor.stop();
+        assert_eq!(MotorState::of(&rover.left_motor).to_signed(), 40);
+        assert_eq!(MotorState::of(&rover.right_motor).to_signed(), 40);
     }
 }
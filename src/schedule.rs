@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+/// Maximum number of actions waiting to run.
+const MAX_PENDING: usize = 16;
+
+/// Delayed actions, each owned by the client that scheduled it.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    pending: Mutex<Pending>,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    next_id: u64,
+    actions: HashMap<u64, (SocketAddr, JoinHandle<()>)>,
+}
+
+impl Scheduler {
+    /// Runs `action` after `delay` on behalf of `owner`, from a blocking
+    /// thread since actions drive the motors.
+    ///
+    /// Returns the id of the scheduled action, or `None` if too many
+    /// actions are already pending.
+    pub fn schedule(
+        self: &Arc<Self>,
+        owner: SocketAddr,
+        delay: Duration,
+        action: impl FnOnce() + Send + 'static,
+    ) -> Option<u64> {
+        let mut pending = self.pending.lock().unwrap();
+
+        if pending.actions.len() >= MAX_PENDING {
+            return None;
+        }
+
+        pending.next_id += 1;
+
+        let id = pending.next_id;
+        let scheduler = self.clone();
+        // The task cannot remove itself before it is inserted, since we hold
+        // the lock until then.
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            scheduler.pending.lock().unwrap().actions.remove(&id);
+
+            debug!("running scheduled action #{}", id);
+            if let Err(e) = tokio::task::spawn_blocking(action).await {
+                error!("scheduled action #{} failed: {}", id, e);
+            }
+        });
+
+        pending.actions.insert(id, (owner, task));
+
+        Some(id)
+    }
+
+    /// Cancels a pending action, returning whether there was one.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.pending.lock().unwrap().actions.remove(&id) {
+            Some((_, task)) => {
+                task.abort();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Cancels all the pending actions of `owner`, e.g. when it disconnects.
+    pub fn cancel_all(&self, owner: SocketAddr) {
+        self.pending.lock().unwrap().actions.retain(|id, (action_owner, task)| {
+            if *action_owner != owner {
+                return true;
+            }
+
+            debug!("cancelling scheduled action #{} of {}", id, owner);
+            task.abort();
+
+            false
+        });
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+
+use pwm_pca9685::Channel;
+
+use crate::backend::{pulse_width_count, MotorBackend};
+use crate::config::ServoConfig;
+use crate::error::RoverError;
+use crate::output::{self, PwmOutput};
+use crate::rover::CHANNELS;
+
+/// Largest angle a servo turns to, either way.
+pub const MAX_ANGLE: i8 = 90;
+
+/// A hobby servo on a PCA9685 channel, e.g. steering an Ackermann-style
+/// rover.
+///
+/// Servos expect a pulse every 20ms, i.e. a 50Hz PWM frequency, its width
+/// giving the angle: `min_pulse_us` for -90 degrees, `max_pulse_us` for 90
+/// and the middle of both for 0, see `ServoConfig`.
+pub struct Servo {
+    backend: Box<dyn MotorBackend>,
+    channel: Channel,
+    frequency_hz: u16,
+    min_pulse_us: u16,
+    max_pulse_us: u16,
+    neutral_angle: i8,
+    angle: i8,
+}
+
+impl fmt::Debug for Servo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Servo")
+            .field("channel", &self.channel)
+            .field("angle", &self.angle)
+            .field("backend", &self.backend)
+            .finish()
+    }
+}
+
+impl Servo {
+    pub fn new(backend: Box<dyn MotorBackend>, config: &ServoConfig, frequency_hz: u16) -> Self {
+        Servo {
+            backend,
+            channel: CHANNELS[usize::from(config.channel)],
+            frequency_hz,
+            min_pulse_us: config.min_pulse_us,
+            max_pulse_us: config.max_pulse_us,
+            neutral_angle: config.neutral_angle,
+            angle: 0,
+        }
+    }
+
+    /// Turns the servo to `angle` degrees, clamped to -90..=90: negative is
+    /// left, positive right.
+    pub fn set_angle(&mut self, angle: i8) -> Result<(), RoverError> {
+        debug!("Servo.set_angle({:?}, {})", self, angle);
+
+        let angle = angle.clamp(-MAX_ANGLE, MAX_ANGLE);
+        let center = (i32::from(self.min_pulse_us) + i32::from(self.max_pulse_us)) / 2;
+        let half_range = (i32::from(self.max_pulse_us) - i32::from(self.min_pulse_us)) / 2;
+        let width_us = center + half_range * i32::from(angle) / i32::from(MAX_ANGLE);
+
+        self.backend.set_off_count(self.channel, pulse_width_count(width_us as u16, self.frequency_hz))?;
+        self.angle = angle;
+
+        Ok(())
+    }
+
+    pub fn angle(&self) -> i8 {
+        self.angle
+    }
+}
+
+/// Angles as values, -1.0 being full left, see `PwmOutput`.
+impl PwmOutput for Servo {
+    fn set_value(&mut self, value: f32) -> Result<(), RoverError> {
+        self.set_angle(output::scale(value, i32::from(MAX_ANGLE)) as i8)
+    }
+
+    fn value(&self) -> f32 {
+        f32::from(self.angle) / f32::from(MAX_ANGLE)
+    }
+
+    /// Turns the servo to its neutral angle, see `ServoConfig::neutral_angle`.
+    fn neutral(&mut self) -> Result<(), RoverError> {
+        self.set_angle(self.neutral_angle)
+    }
+}
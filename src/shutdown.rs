@@ -0,0 +1,115 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::{watch, Notify};
+
+use crate::resources::{self, Connection};
+
+/// How often `close_connections()` checks whether the connections closed.
+const DRAIN_PERIOD: Duration = Duration::from_millis(50);
+
+/// Coordinates the shutdown of the WebSocket connections, which hyper's
+/// graceful shutdown doesn't wait for since they are upgraded.
+///
+/// The connections are counted by `resources::Connection`, the same count
+/// limiting them and reported by the metrics.
+#[derive(Debug)]
+pub struct Shutdown {
+    started: watch::Sender<bool>,
+    started_rx: watch::Receiver<bool>,
+    /// Held while opening a connection, for none to open once the shutdown
+    /// started.
+    opening: Mutex<()>,
+    /// Set by `restart()`, for the server to start again once shut down.
+    restarting: AtomicBool,
+    restart: Notify,
+}
+
+/// Why `Shutdown::connection()` refused a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refused {
+    ShuttingDown,
+    /// As many connections as allowed are open already.
+    TooMany,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (started, started_rx) = watch::channel(false);
+
+        Shutdown {
+            started,
+            started_rx,
+            opening: Mutex::new(()),
+            restarting: AtomicBool::new(false),
+            restart: Notify::new(),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Opens a new connection, unless the server is shutting down or
+    /// `limit` connections are open already. The shutdown waits for it
+    /// until it is dropped.
+    pub fn connection(&self, limit: Option<usize>) -> Result<Connection, Refused> {
+        let _opening = self.opening.lock().unwrap();
+
+        if self.is_started() {
+            return Err(Refused::ShuttingDown);
+        }
+
+        Connection::open(limit).ok_or(Refused::TooMany)
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started_rx.borrow()
+    }
+
+    /// Resolves once the shutdown started.
+    pub async fn started(&self) {
+        let mut started = self.started_rx.clone();
+
+        while !*started.borrow() {
+            if started.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Asks for the server to shut down, then to start again, see
+    /// `restart_requested()`.
+    pub fn restart(&self) {
+        self.restarting.store(true, Ordering::SeqCst);
+        self.restart.notify_one();
+    }
+
+    /// Whether the server starts again once shut down.
+    pub fn is_restarting(&self) -> bool {
+        self.restarting.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once `restart()` was called, even before.
+    pub async fn restart_requested(&self) {
+        self.restart.notified().await
+    }
+
+    /// Tells the connections to close, then waits for them to, at most for
+    /// `timeout`. Returns the number of connections still open.
+    pub async fn close_connections(&self, timeout: Duration) -> usize {
+        {
+            // No new connection from now on.
+            let _opening = self.opening.lock().unwrap();
+            let _ = self.started.send(true);
+        }
+
+        let closed = async {
+            while resources::connections() > 0 {
+                tokio::time::sleep(DRAIN_PERIOD).await;
+            }
+        };
+        let _ = tokio::time::timeout(timeout, closed).await;
+
+        resources::connections()
+    }
+}
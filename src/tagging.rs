@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use serde::de::{DeserializeOwned, Error};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+const TAG: &str = "type";
+const CONTENT: &str = "content";
+/// Optional sequence number of a command, next to its tag.
+const SEQ: &str = "seq";
+
+/// How enum variants such as `RoverCommand`'s are tagged in JSON.
+///
+/// The enums themselves use serde's default external tagging, the other
+/// representations are converted from/to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tagging {
+    /// `{"MotorRun":{"motor":"Left",...}}`, or `"Pause"` without fields.
+    External,
+    /// `{"type":"MotorRun","content":{"motor":"Left",...}}`.
+    Adjacent,
+    /// `{"type":"MotorRun","motor":"Left",...}`.
+    Internal,
+}
+
+impl FromStr for Tagging {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "external" => Ok(Tagging::External),
+            "adjacent" => Ok(Tagging::Adjacent),
+            "internal" => Ok(Tagging::Internal),
+            _ => Err(format!("unknown tagging {:?}, expected external, adjacent or internal", s)),
+        }
+    }
+}
+
+impl Tagging {
+    /// Parses a command which may carry a sequence number next to its tag,
+    /// e.g. `{"Pause":null,"seq":42}` or `{"type":"Pause","seq":42}`.
+    ///
+    /// The number is returned even when the command is invalid, so that the
+    /// error can echo it.
+    pub fn parse_sequenced<T: DeserializeOwned>(self, text: &str) -> (Option<u64>, serde_json::Result<T>) {
+        let mut value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(e) => return (None, Err(e)),
+        };
+        let seq = match &mut value {
+            Value::Object(object) => object.remove(SEQ),
+            _ => None,
+        };
+        let seq = match seq.map(|seq| seq.as_u64()) {
+            Some(None) => return (None, Err(serde_json::Error::custom(format!("\"{}\" must be an unsigned integer", SEQ)))),
+            seq => seq.flatten(),
+        };
+        let value = match self {
+            Tagging::External => serde_json::from_value(value),
+            _ => self.externally_tagged(value).and_then(serde_json::from_value),
+        };
+
+        (seq, value)
+    }
+
+    pub fn format<T: Serialize>(self, value: &T) -> serde_json::Result<String> {
+        match self {
+            Tagging::External => serde_json::to_string(value),
+            _ => serde_json::to_string(&self.retagged(serde_json::to_value(value)?)),
+        }
+    }
+
+    pub fn to_value<T: Serialize>(self, value: &T) -> serde_json::Result<Value> {
+        match self {
+            Tagging::External => serde_json::to_value(value),
+            _ => Ok(self.retagged(serde_json::to_value(value)?)),
+        }
+    }
+
+    fn externally_tagged(self, value: Value) -> serde_json::Result<Value> {
+        let mut object = match value {
+            Value::Object(object) => object,
+            _ => return Err(serde_json::Error::custom("expected an object")),
+        };
+        let tag = match object.remove(TAG) {
+            Some(Value::String(tag)) => tag,
+            _ => return Err(serde_json::Error::custom(format!("missing \"{}\" string", TAG))),
+        };
+        let content = match self {
+            Tagging::Adjacent => object.remove(CONTENT),
+            _ if object.is_empty() => None,
+            _ => Some(Value::Object(object)),
+        };
+
+        Ok(match content {
+            Some(content) => Value::Object(std::iter::once((tag, content)).collect()),
+            None => Value::String(tag),
+        })
+    }
+
+    fn retagged(self, value: Value) -> Value {
+        let (tag, content) = match value {
+            Value::String(tag) => (tag, None),
+            Value::Object(object) if object.len() == 1 => {
+                let (tag, content) = object.into_iter().next().expect("object has one entry");
+
+                (tag, Some(content))
+            },
+            // Not an enum, nothing to re-tag.
+            value => return value,
+        };
+        let mut object = Map::new();
+
+        object.insert(TAG.to_string(), Value::String(tag));
+        match (self, content) {
+            (_, None) => {},
+            (Tagging::Internal, Some(Value::Object(fields))) => object.extend(fields),
+            (_, Some(content)) => {
+                object.insert(CONTENT.to_string(), content);
+            },
+        }
+
+        Value::Object(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Command {
+        Pause,
+        Run { motor: String, speed: u16 },
+    }
+
+    #[test]
+    fn commands_round_trip_with_each_tagging() {
+        let run = || Command::Run { motor: "Left".to_string(), speed: 40 };
+
+        for (tagging, pause, run_json) in [
+            (Tagging::External, r#""Pause""#, r#"{"Run":{"motor":"Left","speed":40}}"#),
+            (Tagging::Adjacent, r#"{"type":"Pause"}"#, r#"{"content":{"motor":"Left","speed":40},"type":"Run"}"#),
+            (Tagging::Internal, r#"{"type":"Pause"}"#, r#"{"motor":"Left","speed":40,"type":"Run"}"#),
+        ] {
+            for (command, json) in [(Command::Pause, pause), (run(), run_json)] {
+                let text = tagging.format(&command).unwrap();
+                let (seq, parsed) = tagging.parse_sequenced::<Command>(&text);
+
+                assert_eq!(text, json, "{:?}", tagging);
+                assert_eq!(seq, None);
+                assert_eq!(parsed.unwrap(), command);
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::axis::Inversion;
+use crate::pattern::PatternRun;
+use crate::rgb::Color;
+use crate::rover::{DCMotor, DCMotorDirection, Rover, MAX_SPEED};
+use crate::servo::Servo;
+
+/// What the rover is doing, as periodically pushed to the clients.
+#[derive(Clone, Debug, Serialize)]
+pub struct Telemetry {
+    pub left_speed: u16,
+    pub left_dir: DCMotorDirection,
+    pub right_speed: u16,
+    pub right_dir: DCMotorDirection,
+    /// Motors whose board failed to initialize, see `Rover::new()`. Absent
+    /// when there is none.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_motors: Vec<&'static str>,
+    /// The last test pattern run, see `Rover::start_pattern()`. Absent when
+    /// none ran.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test_pattern: Option<PatternRun>,
+    /// The motors which can't run at full speed, see `SpeedLimit`. Absent
+    /// when there is none.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub speed_limits: BTreeMap<&'static str, SpeedLimit>,
+    /// Time left before the controller loses control in demo mode, see
+    /// `Control::remaining()`. Absent otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_remaining_ms: Option<u64>,
+    /// Milliseconds since the UNIX epoch.
+    pub ts: u64,
+}
+
+impl Telemetry {
+    pub fn of(rover: &Rover) -> Self {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        Telemetry {
+            left_speed: rover.left_motor.speed(),
+            left_dir: rover.left_motor.direction(),
+            right_speed: rover.right_motor.speed(),
+            right_dir: rover.right_motor.direction(),
+            unavailable_motors: rover.unavailable_motors(),
+            test_pattern: rover.test_pattern(),
+            speed_limits: SpeedLimit::all(rover).into_iter().filter(|(_, limit)| limit.reason.is_some()).collect(),
+            // Only known to the server, see `publish_telemetry()`.
+            session_remaining_ms: None,
+            ts,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct MotorStatus {
+    pub speed: u16,
+    pub direction: DCMotorDirection,
+    /// Whether the motor is stopped by shorting it, see `DCMotor::brake()`.
+    pub braked: bool,
+}
+
+impl MotorStatus {
+    fn of(motor: &DCMotor) -> Self {
+        MotorStatus {
+            speed: motor.speed(),
+            direction: motor.direction(),
+            braked: motor.is_braked(),
+        }
+    }
+}
+
+/// The highest speed a motor can currently be driven at, as replied to
+/// `GetSpeedLimit`, for clients to show how much headroom is left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SpeedLimit {
+    /// Highest duty cycle, from 0 to 100, the commands can drive the motor
+    /// at, see `DCMotor::speed_ceiling()`.
+    pub max_speed: u16,
+    /// What holds the motor back, `None` when it can run at full speed.
+    pub reason: Option<LimitReason>,
+}
+
+/// The first limit holding a motor back, from the most to the least
+/// restrictive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitReason {
+    /// The motors can't run until the emergency stop is reset.
+    EmergencyStopped,
+    /// The board of the motor failed to initialize, see
+    /// `Rover::unavailable_motors()`.
+    Unavailable,
+    /// Capped by `Rover::set_max_speed()`.
+    MaxSpeed,
+    /// Slowed down by a negative trim, see `DCMotor::trim`.
+    Trim,
+}
+
+impl SpeedLimit {
+    fn of(rover: &Rover, motor: &DCMotor) -> Self {
+        let (max_speed, reason) = if rover.emergency_stopped() {
+            (0, Some(LimitReason::EmergencyStopped))
+        } else if !motor.is_available() {
+            (0, Some(LimitReason::Unavailable))
+        } else if rover.max_speed() < MAX_SPEED {
+            (motor.speed_ceiling(), Some(LimitReason::MaxSpeed))
+        } else if motor.speed_ceiling() < MAX_SPEED {
+            (motor.speed_ceiling(), Some(LimitReason::Trim))
+        } else {
+            (MAX_SPEED, None)
+        };
+
+        SpeedLimit { max_speed, reason }
+    }
+
+    /// The limit of each motor by output id, see `Rover::named_motors()`.
+    pub fn all(rover: &Rover) -> BTreeMap<&'static str, SpeedLimit> {
+        rover.named_motors().map(|(name, motor)| (name, SpeedLimit::of(rover, motor))).collect()
+    }
+}
+
+/// What the rover is doing and how it is guarded, as replied to a status
+/// query. Unlike `Telemetry`, this is only sent when asked for.
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub left: MotorStatus,
+    pub right: MotorStatus,
+    /// Angle of the steering servo, `None` without one.
+    pub steering_angle: Option<i8>,
+    /// See `Rover::set_max_speed()`.
+    pub max_speed: u16,
+    /// `None` when the watchdog is disabled.
+    pub watchdog_timeout_ms: Option<u64>,
+    /// Whether a latched emergency stop keeps the motors from running.
+    pub emergency_stopped: bool,
+    /// Boards which failed to initialize and the motors on them, in
+    /// degraded mode, see `RoverConfig::allow_degraded`.
+    pub unavailable_boards: Vec<String>,
+    pub unavailable_motors: Vec<&'static str>,
+    /// See `Rover::inversion`.
+    pub inversion: Inversion,
+    /// `None` when no test pattern ran.
+    pub test_pattern: Option<PatternRun>,
+    /// Color of each RGB LED by id.
+    pub colors: BTreeMap<String, Color>,
+    pub uptime_ms: u64,
+}
+
+impl Status {
+    /// Only reads what the rover knows, without going through the hardware.
+    pub fn of(rover: &Rover) -> Self {
+        let millis = |duration: Duration| duration.as_millis() as u64;
+
+        Status {
+            left: MotorStatus::of(&rover.left_motor),
+            right: MotorStatus::of(&rover.right_motor),
+            steering_angle: rover.steering.as_ref().map(Servo::angle),
+            max_speed: rover.max_speed(),
+            watchdog_timeout_ms: rover.watchdog_timeout.map(millis),
+            emergency_stopped: rover.emergency_stopped(),
+            unavailable_boards: rover.unavailable_boards().to_vec(),
+            unavailable_motors: rover.unavailable_motors(),
+            inversion: rover.inversion,
+            test_pattern: rover.test_pattern(),
+            colors: rover.rgb_leds.iter().map(|(id, led)| (id.clone(), led.color())).collect(),
+            uptime_ms: millis(rover.uptime()),
+        }
+    }
+}
+
+/// Readiness of the rover, as replied to `GET /health`. Like `Status`, only
+/// reports what the rover already knows.
+#[derive(Clone, Debug, Serialize)]
+pub struct Health {
+    /// "ok", "degraded" when some boards failed to initialize, or "error"
+    /// when the last I2C transaction failed. A degraded rover is still
+    /// ready, the motors on the working boards running.
+    pub status: &'static str,
+    /// "ready" when the PCA9685 answered the last transaction, "error"
+    /// otherwise.
+    pub i2c: &'static str,
+    /// Why the last I2C transaction failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub motors_stopped: bool,
+    /// See `Status::unavailable_boards`, absent when there is none.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_boards: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_motors: Vec<&'static str>,
+}
+
+impl Health {
+    pub fn of(rover: &Rover) -> Self {
+        let error = rover.i2c_error();
+        let unavailable_boards = rover.unavailable_boards().to_vec();
+        let status = match (&error, unavailable_boards.is_empty()) {
+            (Some(_), _) => "error",
+            (None, false) => "degraded",
+            (None, true) => "ok",
+        };
+
+        Health {
+            status,
+            i2c: if error.is_none() { "ready" } else { "error" },
+            error,
+            motors_stopped: rover.motors().all(DCMotor::is_stopped),
+            unavailable_boards,
+            unavailable_motors: rover.unavailable_motors(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// How to connect to the rover, as replied to `GET /info`, with the same
+/// properties as the mDNS advertisement, see `mdns::advertise()`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerInfo<'a> {
+    pub version: &'static str,
+    /// Path of the WebSocket endpoint, see `RoverConfig::ws_path`.
+    pub websocket: &'a str,
+    pub protocol: &'static str,
+}
+
+impl<'a> ServerInfo<'a> {
+    pub fn new(ws_path: &'a str) -> Self {
+        ServerInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            websocket: ws_path,
+            protocol: "json",
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::Stream;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections handshaken but not picked up by the server yet.
+const PENDING_CONNECTIONS: usize = 16;
+
+/// Reads the certificate chain and the private key (PKCS#8 or RSA) of the
+/// server from PEM files.
+pub fn server_config(cert_path: &str, key_path: &str) -> Result<ServerConfig, String> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("unable to open {:?}: {}", path, e))
+    };
+    let certs = pemfile::certs(&mut open(cert_path)?)
+        .ok()
+        .filter(|certs| !certs.is_empty())
+        .ok_or_else(|| format!("no PEM certificate in {:?}", cert_path))?;
+    let key = pemfile::pkcs8_private_keys(&mut open(key_path)?)
+        .ok()
+        .filter(|keys| !keys.is_empty())
+        .or_else(|| pemfile::rsa_private_keys(&mut open(key_path).ok()?).ok())
+        .and_then(|keys| keys.into_iter().next())
+        .ok_or_else(|| format!("no PKCS#8 or RSA PEM private key in {:?}", key_path))?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+
+    Ok(config)
+}
+
+/// Accepts TLS connections on `listener`, for `hyper::Server::builder()`.
+///
+/// The handshakes run in their own tasks so that a slow client doesn't hold
+/// the other ones back.
+pub fn incoming(
+    listener: TcpListener,
+    config: ServerConfig,
+) -> impl Stream<Item = Result<TlsStream<TcpStream>, io::Error>> {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let (sender, receiver) = mpsc::channel(PENDING_CONNECTIONS);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    // e.g. too many open files, which doesn't last.
+                    error!("unable to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                },
+            };
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        // Only fails once the server stopped.
+                        let _ = sender.send(stream).await;
+                    },
+                    Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", addr, e),
+                    Err(_) => debug!("TLS handshake with {} timed out", addr),
+                }
+            });
+        }
+    });
+
+    futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let stream = receiver.recv().await?;
+
+        Some((Ok(stream), receiver))
+    })
+}
+
+/// Address of the client at the other end of `stream`.
+pub fn remote_addr(stream: &TlsStream<TcpStream>) -> SocketAddr {
+    stream
+        .get_ref()
+        .0
+        .peer_addr()
+        // The client is already gone, the connection will fail anyway.
+        .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)))
+}
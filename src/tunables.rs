@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::axis::AxisShaping;
+use crate::config::TRIM_RANGE;
+use crate::error::RoverError;
+use crate::rover::{MinSpeedPolicy, Rover, Side, MAX_SPEED};
+
+/// Settings of `RoverConfig` that can be changed while the rover runs, as
+/// replied to `GET /config`. The other ones (the I2C bus, the channels...)
+/// require a restart.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Tunables {
+    pub left_trim: i8,
+    pub right_trim: i8,
+    pub max_speed: u16,
+    pub min_speed: u16,
+    pub min_speed_policy: MinSpeedPolicy,
+    /// 0 when the watchdog is disabled.
+    pub watchdog_ms: u64,
+}
+
+impl Tunables {
+    pub fn of(rover: &Rover) -> Self {
+        Tunables {
+            left_trim: rover.left_motor.trim,
+            right_trim: rover.right_motor.trim,
+            max_speed: rover.max_speed(),
+            min_speed: rover.left_motor.min_speed,
+            min_speed_policy: rover.left_motor.min_speed_policy,
+            watchdog_ms: rover.watchdog_timeout.map_or(0, |timeout| timeout.as_millis() as u64),
+        }
+    }
+
+    /// Same checks as `RoverConfig::check()`.
+    fn check(&self) -> Result<(), String> {
+        for &(name, trim) in &[("left_trim", self.left_trim), ("right_trim", self.right_trim)] {
+            if !TRIM_RANGE.contains(&trim) {
+                return Err(format!(
+                    "{} must be a percentage between {} and {}, not {}",
+                    name,
+                    TRIM_RANGE.start(),
+                    TRIM_RANGE.end(),
+                    trim,
+                ));
+            }
+        }
+
+        if !(1..=MAX_SPEED).contains(&self.max_speed) {
+            return Err(format!("max_speed must be between 1 and {}, not {}", MAX_SPEED, self.max_speed));
+        }
+        if self.min_speed > self.max_speed {
+            return Err(format!("min_speed ({}) can't be above max_speed ({})", self.min_speed, self.max_speed));
+        }
+
+        Ok(())
+    }
+
+    /// Applies the settings to `rover`.
+    pub fn apply(&self, rover: &mut Rover) -> Result<(), RoverError> {
+        for &(side, trim) in &[(Side::Left, self.left_trim), (Side::Right, self.right_trim)] {
+            for motor in rover.side_mut(side) {
+                motor.set_trim(trim)?;
+            }
+        }
+        for motor in rover.motors_mut() {
+            // Applies from the next speed change.
+            motor.min_speed = self.min_speed;
+            motor.min_speed_policy = self.min_speed_policy;
+        }
+        rover.watchdog_timeout = Some(Duration::from_millis(self.watchdog_ms)).filter(|timeout| !timeout.is_zero());
+
+        rover.set_max_speed(self.max_speed)
+    }
+}
+
+/// Changes to some of the `Tunables`, as accepted by `POST /config`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunablesUpdate {
+    left_trim: Option<i8>,
+    right_trim: Option<i8>,
+    max_speed: Option<u16>,
+    min_speed: Option<u16>,
+    min_speed_policy: Option<MinSpeedPolicy>,
+    watchdog_ms: Option<u64>,
+}
+
+impl TunablesUpdate {
+    /// The `current` settings with these changes, checked as a whole.
+    pub fn merged_into(self, current: &Tunables) -> Result<Tunables, String> {
+        let tunables = Tunables {
+            left_trim: self.left_trim.unwrap_or(current.left_trim),
+            right_trim: self.right_trim.unwrap_or(current.right_trim),
+            max_speed: self.max_speed.unwrap_or(current.max_speed),
+            min_speed: self.min_speed.unwrap_or(current.min_speed),
+            min_speed_policy: self.min_speed_policy.unwrap_or(current.min_speed_policy),
+            watchdog_ms: self.watchdog_ms.unwrap_or(current.watchdog_ms),
+        };
+
+        tunables.check()?;
+
+        Ok(tunables)
+    }
+}
+
+/// How the rover accelerates, as set all at once by `SetDriveParams` and
+/// replied to it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct DriveParams {
+    /// See `Rover::set_max_speed()`.
+    pub max_speed: u16,
+    /// Speed change of the motors every 20ms when speeding up and slowing
+    /// down, 0 applying it right away (see `DCMotor::max_delta_per_tick`).
+    pub accel: u16,
+    pub decel: u16,
+    /// See `RoverConfig::axis_expo`.
+    pub expo: f32,
+}
+
+impl DriveParams {
+    pub fn of(rover: &Rover) -> Self {
+        DriveParams {
+            max_speed: rover.max_speed(),
+            accel: rover.left_motor.max_delta_per_tick,
+            decel: rover.left_motor.decel_per_tick(),
+            expo: rover.axis.expo(),
+        }
+    }
+
+    /// The `current` parameters with the given changes, each of them
+    /// checked.
+    pub fn merged_into(
+        current: DriveParams,
+        max_speed: Option<u16>,
+        accel: Option<u16>,
+        decel: Option<u16>,
+        expo: Option<f32>,
+        min_speed: u16,
+    ) -> Result<Self, String> {
+        let params = DriveParams {
+            max_speed: max_speed.unwrap_or(current.max_speed),
+            accel: accel.unwrap_or(current.accel),
+            decel: decel.unwrap_or(current.decel),
+            expo: expo.unwrap_or(current.expo),
+        };
+
+        if !(1..=MAX_SPEED).contains(&params.max_speed) {
+            return Err(format!("max_speed must be between 1 and {}, not {}", MAX_SPEED, params.max_speed));
+        }
+        if min_speed > params.max_speed {
+            return Err(format!("max_speed ({}) can't be below min_speed ({})", params.max_speed, min_speed));
+        }
+        for &(name, delta) in &[("accel", params.accel), ("decel", params.decel)] {
+            if delta > MAX_SPEED {
+                return Err(format!("{} must be between 0 and {}, not {}", name, MAX_SPEED, delta));
+            }
+        }
+        if !(0.0..=1.0).contains(&params.expo) {
+            return Err(format!("expo must be between 0.0 and 1.0, not {}", params.expo));
+        }
+
+        Ok(params)
+    }
+
+    /// Applies the parameters to `rover` at once, the motors ramping to
+    /// their current target with them.
+    pub fn apply(&self, rover: &mut Rover) -> Result<(), RoverError> {
+        for motor in rover.motors_mut() {
+            motor.max_delta_per_tick = self.accel;
+            motor.max_decel_per_tick = Some(self.decel);
+        }
+        rover.axis = AxisShaping::new(rover.axis.deadzone(), self.expo);
+
+        rover.set_max_speed(self.max_speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoverConfig;
+
+    #[test]
+    fn updates_are_checked_as_a_whole() {
+        let mut rover = Rover::mock(&RoverConfig::default());
+        let update = |json| serde_json::from_str::<TunablesUpdate>(json).unwrap();
+        let current = Tunables::of(&rover);
+
+        let tunables = update(r#"{"left_trim":5,"watchdog_ms":300}"#).merged_into(&current).unwrap();
+        tunables.apply(&mut rover).unwrap();
+
+        assert_eq!(Tunables::of(&rover), Tunables { left_trim: 5, watchdog_ms: 300, ..current });
+
+        let error = update(r#"{"min_speed":60,"max_speed":50}"#).merged_into(&tunables).unwrap_err();
+
+        assert!(error.contains("min_speed"));
+        assert!(serde_json::from_str::<TunablesUpdate>(r#"{"i2c_path":"/dev/i2c-0"}"#).is_err());
+    }
+
+    #[test]
+    fn drive_params_are_checked_one_by_one() {
+        let mut rover = Rover::mock(&RoverConfig::default());
+        let current = DriveParams::of(&rover);
+
+        assert_eq!(current, DriveParams { max_speed: 100, accel: 0, decel: 0, expo: 0.0 });
+
+        let params = DriveParams::merged_into(current, Some(60), Some(5), None, Some(0.5), 0).unwrap();
+        params.apply(&mut rover).unwrap();
+
+        // The parameters left out stay as they were.
+        assert_eq!(DriveParams::of(&rover), DriveParams { max_speed: 60, accel: 5, decel: 0, expo: 0.5 });
+
+        let merged = |max_speed, accel, decel, expo| DriveParams::merged_into(params, max_speed, accel, decel, expo, 20);
+
+        assert!(merged(Some(0), None, None, None).unwrap_err().contains("max_speed"));
+        assert!(merged(Some(10), None, None, None).unwrap_err().contains("min_speed"));
+        assert!(merged(None, Some(101), None, None).unwrap_err().contains("accel"));
+        assert!(merged(None, None, Some(101), None).unwrap_err().contains("decel"));
+        assert!(merged(None, None, None, Some(1.5)).unwrap_err().contains("expo"));
+    }
+}